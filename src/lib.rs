@@ -0,0 +1,691 @@
+use std::collections::HashSet;
+use std::fmt;
+use chrono::prelude::*;
+use openssl::bn::BigNumContext;
+use openssl::ec::{EcGroup, EcKey, EcPoint};
+use openssl::ecdsa::EcdsaSig;
+use openssl::nid::Nid;
+use serde::{Serialize, Deserialize};
+use sha2::{Sha256, Digest};
+use unicode_normalization::{is_nfc, UnicodeNormalization};
+
+// "0" plays two roles. As a sender it marks a coinbase, which creates coins,
+// and as a recipient it is a burn sink: coins sent there are debited from the
+// sender, credited to no one and leave the supply for good. Since "0" can never
+// send anything but a coinbase, burned coins can't come back.
+pub const COINBASE_SENDER: &str = "0";
+pub const BURN_ADDRESS: &str = COINBASE_SENDER;
+
+pub const MAX_CATEGORY_LENGTH: usize = 32;
+pub const MAX_ADDRESS_LENGTH: usize = 128;
+pub const MAX_EXTRANONCE: u64 = u32::MAX as u64;
+pub const SHORT_ID_LENGTH: usize = 16;
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Transaction {
+    pub sender: String,
+    pub recipient: String,
+    // Amounts, like every f32 we send or store, are written by serde_json as the
+    // shortest number that reads back the same. That usually has a decimal
+    // point, so 1 goes out as 1.0, but very large or very small amounts come out
+    // in exponent form without one, as 1e20 or 1e-7. Plain integers are still
+    // accepted on the way in. The format is part of the transaction and block
+    // hashes and mustn't change.
+    pub amount: f32,
+    #[serde(default)]
+    pub fee: f32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub category: Option<String>,
+    // Lets a sender repeat an otherwise identical payment under a new id.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub nonce: Option<u64>,
+    // The outputs this transaction spends. Transactions without inputs are paid
+    // from the sender's balance instead.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub inputs: Vec<OutPoint>,
+    // The index of the last block this transaction may be mined in.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub valid_until_height: Option<usize>,
+    // Only on a coinbase: lets an external miner vary the block, and with it the
+    // merkle root and block hash, without touching the transactions it pays.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub extranonce: Option<u64>,
+    // Anti-spam proof of work: varied by the sender until the transaction's id
+    // starts with as many zero bits as the node's policy asks for.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub work: Option<u64>,
+    // Hex-encoded compressed P-256 public key whose address is the sender.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub public_key: Option<String>,
+    // Hex-encoded DER ECDSA signature by public_key over signing_hash().
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signature: Option<String>
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct OutPoint {
+    pub transaction: String,
+    pub output: usize
+}
+
+impl Transaction {
+    pub fn new(sender: &str, recipient: &str, amount: f32, fee: f32) -> Transaction {
+        Transaction {
+            sender: sender.to_string(),
+            recipient: recipient.to_string(),
+            amount,
+            fee,
+            category: None,
+            nonce: None,
+            inputs: Vec::new(),
+            valid_until_height: None,
+            extranonce: None,
+            work: None,
+            public_key: None,
+            signature: None
+        }
+    }
+
+    pub fn byte_size(&self) -> usize {
+        serde_json::to_string(self).unwrap().len()
+    }
+
+    // Addresses that only differ in how their characters are composed are the
+    // same address, so they are stored in composed (NFC) form.
+    pub fn normalized(self) -> Transaction {
+        Transaction {
+            sender: self.sender.nfc().collect(),
+            recipient: self.recipient.nfc().collect(),
+            ..self
+        }
+    }
+
+    pub fn expired_at(&self, index: usize) -> bool {
+        matches!(self.valid_until_height, Some(height) if index > height)
+    }
+
+    pub fn id(&self) -> String {
+        format!("{:x}", Sha256::new().chain(serde_json::to_string(self).unwrap()).result())
+    }
+
+    // The id is the hash the work is done on, so any change to the transaction
+    // after the work was found throws it away.
+    pub fn work_bits(&self) -> usize {
+        leading_zero_bits(&Sha256::new().chain(serde_json::to_string(self).unwrap()).result())
+    }
+
+    // Leaves out the work as well as the signature, so that the work can still be
+    // searched for after signing.
+    pub fn signing_hash(&self) -> Vec<u8> {
+        let unsigned = Transaction { work: None, signature: None, ..self.clone() };
+        Sha256::new().chain(serde_json::to_string(&unsigned).unwrap()).result().to_vec()
+    }
+
+    pub fn short_id(&self) -> String {
+        self.id()[..SHORT_ID_LENGTH].to_string()
+    }
+
+    pub fn fee_per_byte(&self) -> f32 {
+        self.fee / self.byte_size() as f32
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Block {
+    pub index: usize,
+    pub timestamp: String,
+    pub transactions: Vec<Transaction>,
+    pub proof: usize,
+    pub previous_hash: String,
+    pub difficulty: usize,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub merkle_root: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub difficulty_bits: Option<usize>,
+    // Hex-encoded compressed P-256 public key of the node that mined the block.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub miner_key: Option<String>,
+    // Hex-encoded DER ECDSA signature by miner_key over signing_hash().
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signature: Option<String>
+}
+
+impl Block {
+    pub fn new(index: usize, timestamp: DateTime<Utc>, transactions: Vec<Transaction>, proof: usize, previous_hash: &str, difficulty: usize) -> Block {
+        Block {
+            index,
+            timestamp: format!("{}", timestamp),
+            transactions,
+            proof,
+            previous_hash: previous_hash.to_string(),
+            difficulty,
+            merkle_root: None,
+            difficulty_bits: None,
+            miner_key: None,
+            signature: None
+        }
+    }
+
+    // The hash of the block without its signature, which is what gets signed.
+    pub fn signing_hash(&self) -> Vec<u8> {
+        let unsigned = Block { signature: None, ..self.clone() };
+        Sha256::new().chain(serde_json::to_string(&unsigned).unwrap()).result().to_vec()
+    }
+
+    // Blocks mined before bit difficulties only record hex digits.
+    pub fn bits(&self) -> usize {
+        self.difficulty_bits.unwrap_or(self.difficulty * 4)
+    }
+
+    // Expected number of hashes needed to find a proof at this block's difficulty.
+    pub fn work(&self) -> u128 {
+        2u128.saturating_pow(self.bits() as u32)
+    }
+
+    // The block hash is the SHA-256 of the block's compact JSON serialization,
+    // with fields in declaration order:
+    //   {"index":..,"timestamp":..,"transactions":[..],"proof":..,"previous_hash":..,"difficulty":..,"merkle_root":..,"difficulty_bits":..}
+    // so it already covers every transaction and their order. merkle_root and
+    // difficulty_bits are left out when unset, which keeps the hashes of blocks
+    // mined without them unchanged.
+    pub fn preimage(&self) -> String {
+        serde_json::to_string(self).unwrap()
+    }
+
+    pub fn hash(&self) -> String {
+        format!("{:x}", Sha256::new().chain(self.preimage()).result())
+    }
+
+    pub fn compute_merkle_root(&self) -> String {
+        merkle_root(&self.transactions)
+    }
+
+    pub fn time(&self) -> Option<DateTime<Utc>> {
+        Utc.datetime_from_str(&self.timestamp, "%Y-%m-%d %H:%M:%S%.f UTC").ok()
+    }
+
+    pub fn byte_size(&self) -> usize {
+        serde_json::to_string(self).unwrap().len()
+    }
+
+    pub fn fees(&self) -> f32 {
+        self.transactions.iter().fold(0.0, |total, transaction| total + transaction.fee)
+    }
+
+    pub fn coinbase(&self) -> Option<&Transaction> {
+        self.transactions.first().filter(|transaction| transaction.sender == COINBASE_SENDER)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum BlockchainError {
+    IndexMismatch { index: usize, expected: usize },
+    BrokenLink { index: usize },
+    InvalidProof { index: usize },
+    InsufficientDifficulty { index: usize, bits: usize, required: usize },
+    DuplicateHash { index: usize },
+    EmptyChain,
+    MissingCheckpoint { hash: String },
+    NonFiniteAmount,
+    NonPositiveAmount { amount: f32 },
+    InvalidFee { fee: f32 },
+    FeeTooLow { fee: f32, required: f32 },
+    FeeRateTooLow { size: usize, fee: f32, required: f32 },
+    TooManyOutputs { count: usize, max: usize },
+    DustOutput { amount: f32, limit: f32 },
+    InvalidCategory { category: String },
+    AddressTooLong { length: usize },
+    InvalidAddress { address: String },
+    UnexpectedReward { index: usize, amount: f32, expected: f32 },
+    DoesNotExtendTip { index: usize },
+    StaleTemplate { previous_hash: String },
+    ProofNotFound { start: usize, iterations: usize },
+    ProofSpaceExhausted { start: usize },
+    TooFarAhead { index: usize, tip: usize, max_ahead: usize },
+    MissingTransactions { count: usize },
+    InvalidTransaction { index: usize, reason: String },
+    Overdraft { index: usize, sender: String },
+    MisplacedCoinbase { index: usize },
+    MultipleCoinbases { index: usize, count: usize },
+    UnexpectedCoinbase { index: usize },
+    ReplayedTransaction { index: usize, id: String },
+    DuplicateTransaction { id: String },
+    ExpiredTransaction { index: usize, valid_until_height: usize },
+    ReservedSender,
+    MissingInputs,
+    DuplicateInput { transaction: String, output: usize },
+    UnknownOutput { transaction: String, output: usize },
+    ForeignOutput { transaction: String, output: usize, sender: String },
+    InsufficientInputs { total: f32, required: f32 },
+    MerkleRootMismatch { index: usize },
+    InvalidSignature { index: usize },
+    MinerMismatch { index: usize, address: String },
+    InvalidTransactionSignature,
+    SenderMismatch { sender: String, address: String },
+    ForgedCoinbase { index: usize },
+    InvalidExtranonce { extranonce: u64 },
+    BurnsDisabled,
+    InvalidCursor { cursor: String },
+    StaleCursor { cursor: String },
+    InsufficientWork { bits: usize, required: usize },
+    DifficultyMismatch { index: usize, difficulty: usize, bits: usize },
+    UnexpectedDifficulty { index: usize, difficulty: usize, min: usize, max: usize },
+    InvalidTimestamp { index: usize },
+    TimestampTooEarly { index: usize, timestamp: String, median: String }
+}
+
+impl fmt::Display for BlockchainError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BlockchainError::IndexMismatch { index, expected } =>
+                write!(f, "block {} should have index {}", index, expected),
+            BlockchainError::BrokenLink { index } =>
+                write!(f, "block {} does not link to the hash of its previous block", index),
+            BlockchainError::InvalidProof { index } =>
+                write!(f, "block {} has an invalid proof of work", index),
+            BlockchainError::InsufficientDifficulty { index, bits, required } =>
+                write!(f, "block {} was mined at {} bits of difficulty but at least {} are required", index, bits, required),
+            BlockchainError::DuplicateHash { index } =>
+                write!(f, "block {} has the same hash as an earlier block", index),
+            BlockchainError::EmptyChain =>
+                write!(f, "chain has no genesis block"),
+            BlockchainError::MissingCheckpoint { hash } =>
+                write!(f, "chain does not contain the assume-valid checkpoint {}", hash),
+            BlockchainError::NonFiniteAmount =>
+                write!(f, "amount is too large to represent or is not a number"),
+            BlockchainError::NonPositiveAmount { amount } =>
+                write!(f, "amount must be greater than zero, got {}", amount),
+            BlockchainError::InvalidFee { fee } =>
+                write!(f, "fee must be a finite, non-negative number, got {}", fee),
+            BlockchainError::FeeTooLow { fee, required } =>
+                write!(f, "fee of {} is below the minimum fee of {}", fee, required),
+            BlockchainError::TooManyOutputs { count, max } =>
+                write!(f, "transaction creates {} outputs but at most {} are allowed", count, max),
+            BlockchainError::DustOutput { amount, limit } =>
+                write!(f, "output of {} is below the dust limit of {}", amount, limit),
+            BlockchainError::FeeRateTooLow { size, fee, required } =>
+                write!(f, "a {} byte transaction needs a fee of at least {}, got {}", size, required, fee),
+            BlockchainError::AddressTooLong { length } =>
+                write!(f, "address is {} bytes long but may be at most {}", length, MAX_ADDRESS_LENGTH),
+            BlockchainError::InvalidAddress { address } =>
+                write!(f, "address {:?} must be non-empty, NFC-normalized and free of whitespace and control characters", address),
+            BlockchainError::InvalidCategory { category } =>
+                write!(f, "category {:?} must be 1 to {} letters, digits, '-' or '_'", category, MAX_CATEGORY_LENGTH),
+            BlockchainError::ProofNotFound { start, iterations } =>
+                write!(f, "no proof found in {} attempts from {}; mine again from {} to continue", iterations, start, start.saturating_add(*iterations)),
+            BlockchainError::ProofSpaceExhausted { start } =>
+                write!(f, "no proof found from {} up to the largest proof; mine again from a lower start", start),
+            BlockchainError::StaleTemplate { previous_hash } =>
+                write!(f, "template built on {} is stale; the tip has moved on", previous_hash),
+            BlockchainError::UnexpectedReward { index, amount, expected } =>
+                write!(f, "block {} mints {} but the reward at its height is {}", index, amount, expected),
+            BlockchainError::DoesNotExtendTip { index } =>
+                write!(f, "block {} does not extend the tip of our chain", index),
+            BlockchainError::TooFarAhead { index, tip, max_ahead } =>
+                write!(f, "block {} is more than {} blocks ahead of our tip at {}", index, max_ahead, tip),
+            BlockchainError::MissingTransactions { count } =>
+                write!(f, "{} transactions of the block could not be found", count),
+            BlockchainError::InvalidTransaction { index, reason } =>
+                write!(f, "block {} contains an invalid transaction: {}", index, reason),
+            BlockchainError::Overdraft { index, sender } =>
+                write!(f, "a transaction in block {} spends more than {} has", index, sender),
+            BlockchainError::MisplacedCoinbase { index } =>
+                write!(f, "block {} has a coinbase transaction that is not its first", index),
+            BlockchainError::MultipleCoinbases { index, count } =>
+                write!(f, "block {} has {} coinbase transactions but may have at most one", index, count),
+            BlockchainError::UnexpectedCoinbase { index } =>
+                write!(f, "block {} is the genesis block and cannot pay a mining reward", index),
+            BlockchainError::ReplayedTransaction { index, id } =>
+                write!(f, "block {} contains transaction {} which was already mined", index, id),
+            BlockchainError::DuplicateTransaction { id } =>
+                write!(f, "transaction {} was already submitted; use a different nonce to repeat it", id),
+            BlockchainError::ExpiredTransaction { index, valid_until_height } =>
+                write!(f, "transaction is only valid until block {} but the next block is {}", valid_until_height, index),
+            BlockchainError::ReservedSender =>
+                write!(f, "sender {:?} is reserved for mining rewards", COINBASE_SENDER),
+            BlockchainError::MissingInputs =>
+                write!(f, "transaction must list the outputs it spends"),
+            BlockchainError::DuplicateInput { transaction, output } =>
+                write!(f, "output {}:{} is spent more than once", transaction, output),
+            BlockchainError::UnknownOutput { transaction, output } =>
+                write!(f, "output {}:{} does not exist or was already spent", transaction, output),
+            BlockchainError::ForeignOutput { transaction, output, sender } =>
+                write!(f, "output {}:{} does not belong to {}", transaction, output, sender),
+            BlockchainError::InsufficientInputs { total, required } =>
+                write!(f, "inputs hold {} but amount plus fee is {}", total, required),
+            BlockchainError::MerkleRootMismatch { index } =>
+                write!(f, "block {} has a merkle root that does not match its transactions", index),
+            BlockchainError::InvalidSignature { index } =>
+                write!(f, "block {} does not carry a valid signature by its miner key", index),
+            BlockchainError::MinerMismatch { index, address } =>
+                write!(f, "block {} is signed by {} but its coinbase pays someone else", index, address),
+            BlockchainError::InvalidTransactionSignature =>
+                write!(f, "transaction does not carry a valid signature by its public key"),
+            BlockchainError::SenderMismatch { sender, address } =>
+                write!(f, "transaction is signed by {} but claims to be sent by {}", address, sender),
+            BlockchainError::ForgedCoinbase { index } =>
+                write!(f, "block {} has a coinbase that differs from the one in its template", index),
+            BlockchainError::InvalidExtranonce { extranonce } =>
+                write!(f, "extranonce {} is only allowed on a coinbase and at most {}", extranonce, MAX_EXTRANONCE),
+            BlockchainError::BurnsDisabled =>
+                write!(f, "this node does not accept transactions burning coins to {:?}", BURN_ADDRESS),
+            BlockchainError::InvalidCursor { cursor } =>
+                write!(f, "cursor {:?} is not of the form index:hash", cursor),
+            BlockchainError::StaleCursor { cursor } =>
+                write!(f, "cursor {} no longer points at a block of our chain; start again from the beginning", cursor),
+            BlockchainError::InsufficientWork { bits, required } =>
+                write!(f, "transaction id starts with {} zero bits but {} are required; vary work to find one", bits, required),
+            BlockchainError::DifficultyMismatch { index, difficulty, bits } =>
+                write!(f, "block {} records a difficulty of {} but was mined at {} bits", index, difficulty, bits),
+            BlockchainError::UnexpectedDifficulty { index, difficulty, min, max } =>
+                write!(f, "block {} has a difficulty of {} but retargeting allows {} to {}", index, difficulty, min, max),
+            BlockchainError::InvalidTimestamp { index } =>
+                write!(f, "block {} has a timestamp that can't be parsed", index),
+            BlockchainError::TimestampTooEarly { index, timestamp, median } =>
+                write!(f, "block {} is timestamped {}, not after the median time {} of the blocks before it", index, timestamp, median)
+        }
+    }
+}
+
+// The rules every transaction in a block must follow. Fee floors are left out as
+// they are local relay policy rather than consensus.
+pub fn check_transaction(transaction: &Transaction) -> Result<(), BlockchainError> {
+    let (amount, fee) = (transaction.amount, transaction.fee);
+    // Amounts too large for an f32 deserialize to infinity, so this has to be
+    // checked before the amount touches any balance.
+    if !amount.is_finite() {
+        return Err(BlockchainError::NonFiniteAmount)
+    }
+    if amount <= 0.0 {
+        return Err(BlockchainError::NonPositiveAmount { amount })
+    }
+    if !fee.is_finite() || fee < 0.0 {
+        return Err(BlockchainError::InvalidFee { fee })
+    }
+    check_address(&transaction.sender)?;
+    check_address(&transaction.recipient)?;
+    if let Some(extranonce) = transaction.extranonce {
+        if transaction.sender != COINBASE_SENDER || extranonce > MAX_EXTRANONCE {
+            return Err(BlockchainError::InvalidExtranonce { extranonce })
+        }
+    }
+    if let Some(category) = &transaction.category {
+        let allowed = |c: char| c.is_ascii_alphanumeric() || c == '-' || c == '_';
+        if category.is_empty() || category.len() > MAX_CATEGORY_LENGTH || !category.chars().all(allowed) {
+            return Err(BlockchainError::InvalidCategory { category: category.clone() })
+        }
+    }
+    let mut inputs = HashSet::new();
+    if let Some(input) = transaction.inputs.iter().find(|input| !inputs.insert(*input)) {
+        return Err(BlockchainError::DuplicateInput { transaction: input.transaction.clone(), output: input.output })
+    }
+    verify_transaction_signature(transaction)
+}
+
+// Unsigned transactions are accepted. A signed one must carry a valid signature
+// by its public key, and the sender must be the address derived from that key,
+// so a signature by one key can't spend from another key's address.
+fn verify_transaction_signature(transaction: &Transaction) -> Result<(), BlockchainError> {
+    let (public_key, signature) = match (&transaction.public_key, &transaction.signature) {
+        (None, None) => return Ok(()),
+        (Some(public_key), Some(signature)) => (public_key, signature),
+        _ => return Err(BlockchainError::InvalidTransactionSignature)
+    };
+    if signature_is_valid(&transaction.signing_hash(), public_key, signature) != Some(true) {
+        return Err(BlockchainError::InvalidTransactionSignature)
+    }
+    let address = key_address(public_key);
+    if transaction.sender != address {
+        return Err(BlockchainError::SenderMismatch { sender: transaction.sender.clone(), address })
+    }
+    Ok(())
+}
+
+fn check_address(address: &str) -> Result<(), BlockchainError> {
+    if address.len() > MAX_ADDRESS_LENGTH {
+        return Err(BlockchainError::AddressTooLong { length: address.len() })
+    }
+    if address.is_empty() || !is_nfc(address) || address.chars().any(|c| c.is_whitespace() || c.is_control()) {
+        return Err(BlockchainError::InvalidAddress { address: address.to_string() })
+    }
+    Ok(())
+}
+
+// Pairs of transaction ids are hashed together level by level, carrying the
+// last id up unchanged when a level has an odd count.
+pub fn merkle_root(transactions: &[Transaction]) -> String {
+    let mut level: Vec<String> = transactions.iter().map(Transaction::id).collect();
+    if level.is_empty() {
+        return format!("{:x}", Sha256::new().result())
+    }
+    while level.len() > 1 {
+        level = level.chunks(2)
+            .map(|pair| match pair {
+                [left, right] => format!("{:x}", Sha256::new().chain(left).chain(right).result()),
+                _ => pair[0].clone()
+            })
+            .collect();
+    }
+    level.remove(0)
+}
+
+// What a proof is searched against besides the previous proof. A block that
+// records a merkle root commits its proof to it as well, so a miner varying the
+// coinbase extranonce gets a fresh search space on the same parent.
+pub fn proof_target(previous_hash: &str, merkle_root: Option<&str>) -> String {
+    match merkle_root {
+        Some(merkle_root) => format!("{}{}", previous_hash, merkle_root),
+        None => previous_hash.to_string()
+    }
+}
+
+// The proof hash must start with `bits` zero bits, so a difficulty of d hex
+// digits is the same as 4d bits.
+pub fn verify_proof(last_proof: usize, proof: usize, last_hash: &str, bits: usize) -> bool {
+    let guess = format!("{}{}{}", last_proof, proof, last_hash);
+    let guess_hash = Sha256::new().chain(guess).result();
+    leading_zero_bits(&guess_hash) >= bits
+}
+
+fn leading_zero_bits(hash: &[u8]) -> usize {
+    let mut zeros = 0;
+    for byte in hash {
+        zeros += byte.leading_zeros() as usize;
+        if *byte != 0 {
+            break
+        }
+    }
+    zeros
+}
+
+// The required bits are the minimum a block may record; its proof is checked
+// against the difficulty it was actually mined at.
+pub fn verify_block(block: &Block, previous_block: &Block, required_bits: usize) -> Result<(), BlockchainError> {
+    if block.index != previous_block.index + 1 {
+        return Err(BlockchainError::IndexMismatch { index: block.index, expected: previous_block.index + 1 })
+    }
+    let previous_hash = previous_block.hash();
+    if block.previous_hash != previous_hash {
+        return Err(BlockchainError::BrokenLink { index: block.index })
+    }
+    if block.bits() < required_bits {
+        return Err(BlockchainError::InsufficientDifficulty { index: block.index, bits: block.bits(), required: required_bits })
+    }
+    let target = proof_target(&previous_hash, block.merkle_root.as_deref());
+    if !verify_proof(previous_block.proof, block.proof, &target, block.bits()) {
+        return Err(BlockchainError::InvalidProof { index: block.index })
+    }
+    if let Some(merkle_root) = &block.merkle_root {
+        if *merkle_root != block.compute_merkle_root() {
+            return Err(BlockchainError::MerkleRootMismatch { index: block.index })
+        }
+    }
+    verify_signature(block)
+}
+
+// Unsigned blocks are accepted. A signed one must carry a valid signature by its
+// miner key and pay its coinbase to the address derived from that key.
+fn verify_signature(block: &Block) -> Result<(), BlockchainError> {
+    let (miner_key, signature) = match (&block.miner_key, &block.signature) {
+        (None, None) => return Ok(()),
+        (Some(miner_key), Some(signature)) => (miner_key, signature),
+        _ => return Err(BlockchainError::InvalidSignature { index: block.index })
+    };
+    if signature_is_valid(&block.signing_hash(), miner_key, signature) != Some(true) {
+        return Err(BlockchainError::InvalidSignature { index: block.index })
+    }
+    let address = key_address(miner_key);
+    if block.coinbase().map(|coinbase| &coinbase.recipient) != Some(&address) {
+        return Err(BlockchainError::MinerMismatch { index: block.index, address })
+    }
+    Ok(())
+}
+
+fn signature_is_valid(hash: &[u8], public_key: &str, signature: &str) -> Option<bool> {
+    let group = signing_group();
+    let mut ctx = BigNumContext::new().ok()?;
+    let point = EcPoint::from_bytes(&group, &from_hex(public_key)?, &mut ctx).ok()?;
+    let key = EcKey::from_public_key(&group, &point).ok()?;
+    let signature = EcdsaSig::from_der(&from_hex(signature)?).ok()?;
+    signature.verify(hash, &key).ok()
+}
+
+pub fn signing_group() -> EcGroup {
+    EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap()
+}
+
+// The first 128 bits of the key's hash, as long as a generated node identifier.
+pub fn key_address(public_key: &str) -> String {
+    format!("{:x}", Sha256::new().chain(public_key).result())[..32].to_string()
+}
+
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    hex.as_bytes().chunks(2)
+        .map(|pair| std::str::from_utf8(pair).ok().filter(|pair| pair.len() == 2).and_then(|pair| u8::from_str_radix(pair, 16).ok()))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn find_proof(last_proof: usize, last_hash: &str, bits: usize) -> usize {
+        (0..).find(|proof| verify_proof(last_proof, *proof, last_hash, bits)).unwrap()
+    }
+
+    fn genesis() -> Block {
+        Block::new(1, Utc.ymd(2020, 1, 1).and_hms(0, 0, 0), Vec::new(), 1, "0", 1)
+    }
+
+    fn mine_on(previous: &Block) -> Block {
+        let proof = find_proof(previous.proof, &previous.hash(), 4);
+        Block::new(previous.index + 1, Utc.ymd(2020, 1, 1).and_hms(0, 1, 0), Vec::new(), proof, &previous.hash(), 1)
+    }
+
+    #[test]
+    fn verify_proof_accepts_a_proof_with_enough_zero_bits() {
+        let proof = find_proof(100, "abc", 8);
+        assert!(verify_proof(100, proof, "abc", 8));
+        assert!(verify_proof(100, proof, "abc", 0));
+    }
+
+    #[test]
+    fn verify_proof_rejects_a_proof_without_enough_zero_bits() {
+        let proof = (0..).find(|proof| !verify_proof(100, *proof, "abc", 4)).unwrap();
+        assert!(!verify_proof(100, proof, "abc", 4));
+        assert!(!verify_proof(100, find_proof(100, "abc", 8), "abd", 256));
+    }
+
+    #[test]
+    fn verify_block_accepts_a_block_linked_to_its_parent() {
+        let genesis = genesis();
+        assert_eq!(verify_block(&mine_on(&genesis), &genesis, 4), Ok(()));
+    }
+
+    #[test]
+    fn verify_block_rejects_a_broken_link() {
+        let genesis = genesis();
+        let mut block = mine_on(&genesis);
+        block.previous_hash = "not the parent".to_string();
+        assert_eq!(verify_block(&block, &genesis, 4), Err(BlockchainError::BrokenLink { index: 2 }));
+    }
+
+    #[test]
+    fn verify_block_rejects_a_wrong_index() {
+        let genesis = genesis();
+        let mut block = mine_on(&genesis);
+        block.index = 3;
+        assert_eq!(verify_block(&block, &genesis, 4), Err(BlockchainError::IndexMismatch { index: 3, expected: 2 }));
+    }
+
+    #[test]
+    fn verify_block_rejects_an_invalid_proof() {
+        let genesis = genesis();
+        let mut block = mine_on(&genesis);
+        block.proof = (0..).find(|proof| !verify_proof(genesis.proof, *proof, &genesis.hash(), 4)).unwrap();
+        assert_eq!(verify_block(&block, &genesis, 4), Err(BlockchainError::InvalidProof { index: 2 }));
+    }
+
+    #[test]
+    fn verify_block_rejects_a_difficulty_below_the_required_bits() {
+        let genesis = genesis();
+        let block = mine_on(&genesis);
+        assert_eq!(verify_block(&block, &genesis, 8),
+            Err(BlockchainError::InsufficientDifficulty { index: 2, bits: 4, required: 8 }));
+    }
+
+    // Signs a payment from `sender` to bob with a fresh key, returning the key's address too.
+    fn signed_by_new_key(sender: Option<&str>) -> (Transaction, String) {
+        use openssl::ec::PointConversionForm;
+        let key = EcKey::generate(&signing_group()).unwrap();
+        let mut ctx = BigNumContext::new().unwrap();
+        let public_key = to_hex(&key.public_key().to_bytes(&signing_group(), PointConversionForm::COMPRESSED, &mut ctx).unwrap());
+        let address = key_address(&public_key);
+        let mut transaction = Transaction::new(sender.unwrap_or(&address), "bob", 1.0, 0.0);
+        transaction.public_key = Some(public_key);
+        let signature = EcdsaSig::sign(&transaction.signing_hash(), &key).unwrap();
+        transaction.signature = Some(to_hex(&signature.to_der().unwrap()));
+        (transaction, address)
+    }
+
+    #[test]
+    fn check_transaction_accepts_a_signature_by_the_senders_key() {
+        let (transaction, _) = signed_by_new_key(None);
+        assert_eq!(check_transaction(&transaction), Ok(()));
+    }
+
+    #[test]
+    fn check_transaction_rejects_a_valid_signature_by_another_key() {
+        let (victim, _) = signed_by_new_key(None);
+        let (transaction, address) = signed_by_new_key(Some(&victim.sender));
+        assert_eq!(check_transaction(&transaction),
+            Err(BlockchainError::SenderMismatch { sender: victim.sender, address }));
+    }
+
+    #[test]
+    fn check_transaction_rejects_a_tampered_signed_transaction() {
+        let (mut transaction, _) = signed_by_new_key(None);
+        transaction.amount = 2.0;
+        assert_eq!(check_transaction(&transaction), Err(BlockchainError::InvalidTransactionSignature));
+    }
+
+    #[test]
+    fn changing_the_extranonce_changes_the_proof_target() {
+        let genesis = genesis();
+        let mut coinbase = Transaction::new(COINBASE_SENDER, "miner", 1.0, 0.0);
+        coinbase.extranonce = Some(1);
+        let mut block = Block::new(2, Utc.ymd(2020, 1, 1).and_hms(0, 1, 0), vec![coinbase], 0, &genesis.hash(), 3);
+        block.merkle_root = Some(block.compute_merkle_root());
+        block.proof = find_proof(genesis.proof, &proof_target(&genesis.hash(), block.merkle_root.as_deref()), 12);
+        assert_eq!(verify_block(&block, &genesis, 12), Ok(()));
+        block.transactions[0].extranonce = Some(2);
+        let merkle_root = block.compute_merkle_root();
+        assert_ne!(block.merkle_root.as_ref(), Some(&merkle_root));
+        block.merkle_root = Some(merkle_root);
+        assert_eq!(verify_block(&block, &genesis, 12), Err(BlockchainError::InvalidProof { index: 2 }));
+    }
+}
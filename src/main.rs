@@ -1,264 +1,6867 @@
+use std::cell::Cell;
 use std::env;
-use std::io;
-use std::sync::Mutex;
-use std::collections:: HashSet;
-use actix_web::{middleware, web, post, get, App, HttpResponse, HttpServer};
+use std::fmt;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::PathBuf;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use std::collections::{HashMap, HashSet, VecDeque};
+use actix_web::{middleware, web, post, get, App, HttpRequest, HttpResponse, HttpServer};
+use actix_web::error::{InternalError, JsonPayloadError};
+use futures::{Future, Stream};
+use futures::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use openssl::bn::BigNumContext;
+use openssl::ec::{EcKey, PointConversionForm};
+use openssl::ecdsa::EcdsaSig;
+use openssl::pkey::Private;
+use rand::Rng;
 use chrono::prelude::*;
 use reqwest;
 use serde::{Serialize, Deserialize};
 use sha2::{Sha256, Digest};
 use url::{Url};
 use uuid::Uuid;
+use unicode_normalization::UnicodeNormalization;
+use blockchain::{
+    check_transaction, key_address, merkle_root, proof_target, signing_group, to_hex, verify_block, verify_proof, Block,
+    BlockchainError, OutPoint, Transaction, BURN_ADDRESS, COINBASE_SENDER, MAX_ADDRESS_LENGTH, MAX_CATEGORY_LENGTH
+};
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
-struct Response {
-    message: String
+const DEFAULT_CHAIN_ID: &str = "main";
+const DEFAULT_DIFFICULTY: usize = 5;
+const DEFAULT_TARGET_BLOCK_TIME: u64 = 10;
+const DEFAULT_MEDIAN_TIME_SPAN: usize = 11;
+const DEFAULT_MEMPOOL_FULL: usize = 10;
+const DEFAULT_MAX_DIFFICULTY_BOOST: usize = 2;
+const DEFAULT_CONSENSUS_INTERVAL: u64 = 5;
+const DEFAULT_BOOTSTRAP_HEIGHT: usize = 1;
+const DEFAULT_ORPHAN_LIMIT: usize = 64;
+const DEFAULT_ORPHAN_MAX_AHEAD: usize = 16;
+const RECENT_PEER_WINDOW: Duration = Duration::from_secs(300);
+const RECENT_PEER_BONUS: u64 = 2;
+const DEFAULT_TOPOLOGY_DEPTH: usize = 3;
+const DEFAULT_MAX_PEER_REQUESTS: usize = 16;
+const DEFAULT_MAX_CHAIN_RESPONSE: u64 = 64 * 1024 * 1024;
+const PEER_TIMEOUT: Duration = Duration::from_secs(5);
+const DEFAULT_MINING_REWARD: f32 = 1.0;
+
+const REDACTED: &str = "<redacted>";
+const DEFAULT_PAGE_LIMIT: usize = 100;
+const ADMIN_KEY_HEADER: &str = "X-Admin-Key";
+const DEFAULT_MAX_OUTPUTS: usize = 2;
+const SCHEMA_VERSION: u32 = 1;
+const SEEN_BLOCKS_CAPACITY: usize = 1024;
+const SIDE_BLOCKS_CAPACITY: usize = 64;
+const SELF_TEST_BLOCKS: usize = 3;
+const HASHRATE_SAMPLES: usize = 16;
+const DEFAULT_WAIT_TIMEOUT: u64 = 30;
+const SYNC_RETRY_AFTER: u64 = 10;
+const MAX_WAIT_TIMEOUT: u64 = 300;
+const EVENT_HISTORY: usize = 256;
+const LAST_EVENT_ID_HEADER: &str = "Last-Event-ID";
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, PartialOrd)]
+#[serde(rename_all = "lowercase")]
+enum LogLevel {
+    Off,
+    Error,
+    Info,
+    Debug
 }
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
-struct FullChain {
-    chain: Vec<Block>,
-    length: usize
+impl LogLevel {
+    fn parse(level: &str) -> Result<LogLevel, String> {
+        match level {
+            "off" => Ok(LogLevel::Off),
+            "error" => Ok(LogLevel::Error),
+            "info" => Ok(LogLevel::Info),
+            "debug" => Ok(LogLevel::Debug),
+            _ => Err(format!("unknown log level {}", level))
+        }
+    }
 }
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
-struct NodeList {
-    nodes: Vec<String>,
+// The logging settings, copied out of the config for the threads that talk to
+// peers without holding the chain.
+#[derive(Clone, Copy, Debug)]
+struct Logger {
+    level: LogLevel,
+    redact: bool
 }
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
-struct Transaction {
-    sender: String,
-    recipient: String,
-    amount: f32
+impl Logger {
+    fn log(&self, level: LogLevel, message: &str) {
+        if level <= self.level && level != LogLevel::Off {
+            println!("[{:?}] {}", level, message);
+        }
+    }
+
+    fn log_peer(&self, level: LogLevel, node: &str, message: &str) {
+        self.log(level, &self.peer_log_line(node, message));
+    }
+
+    // HTTP errors repeat the peer's address in their URL, so it is redacted
+    // wherever it appears.
+    fn peer_log_line(&self, node: &str, message: &str) -> String {
+        if self.redact {
+            message.replace(node, REDACTED)
+        } else {
+            message.to_string()
+        }
+    }
 }
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
-struct Mine {
-    message: String,
-    index: usize,
-    transactions: Vec<Transaction>,
-    proof: usize,
-    previous_hash: String
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum StorageBackend {
+    File,
+    Sqlite
 }
 
-impl Transaction {
-    fn new(sender: &str, recipient: &str, amount: f32) -> Transaction {
-        Transaction {
-            sender: sender.to_string(),
-            recipient: recipient.to_string(),
-            amount: amount
+impl StorageBackend {
+    fn parse(backend: &str) -> Result<StorageBackend, String> {
+        match backend {
+            "file" => Ok(StorageBackend::File),
+            "sqlite" => Ok(StorageBackend::Sqlite),
+            _ => Err(format!("unknown storage backend {}", backend))
         }
     }
 }
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
-struct Block {
-    index: usize,
-    timestamp: String,
-    transactions: Vec<Transaction>,
-    proof: usize,
-    previous_hash: String
+// Syncing: started with --sync-on-start and no consensus has completed yet, so
+// the chain may be incomplete; neither transactions nor mining are allowed.
+// Synced: consensus completed. Degraded: with --sync-on-start, a later
+// consensus run could not reach any peer, or in any mode the chain is corrupt;
+// transactions are still taken but nothing is mined until the node is synced
+// again.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum SyncState {
+    Syncing,
+    #[default]
+    Synced,
+    Degraded
 }
 
-impl Block {
-    fn new(index: usize, transactions: Vec<Transaction>, proof: usize, previous_hash: &str) -> Block {
-        Block {
-            index: index,
-            timestamp: format!("{}", Utc::now()),
-            transactions: transactions,
-            proof: proof,
-            previous_hash: previous_hash.to_string()
+impl SyncState {
+    fn as_str(self) -> &'static str {
+        match self {
+            SyncState::Syncing => "syncing",
+            SyncState::Synced => "synced",
+            SyncState::Degraded => "degraded"
         }
     }
+}
 
-    fn hash(&self) -> String {
-        let block_string = serde_json::to_string(self).unwrap();
-        format!("{:x}", Sha256::new().chain(block_string).result())
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+enum ConsensusKind {
+    Longest,
+    MostWork,
+    Quorum
+}
+
+impl ConsensusKind {
+    fn parse(kind: &str) -> Result<ConsensusKind, String> {
+        match kind {
+            "longest" => Ok(ConsensusKind::Longest),
+            "most-work" => Ok(ConsensusKind::MostWork),
+            "quorum" => Ok(ConsensusKind::Quorum),
+            _ => Err(format!("unknown consensus strategy {}", kind))
+        }
     }
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
-struct Blockchain {
-    current_transactions: Vec<Transaction>,
-    chain: Vec<Block>,
-    nodes: HashSet<String>
+// Fields missing from a config file keep their defaults.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+struct Config {
+    port: String,
+    advertised_address: Option<String>,
+    base_path: String,
+    node_identifier: String,
+    chain_id: String,
+    mining_reward: f32,
+    halving_interval: Option<usize>,
+    supply_cap: Option<f32>,
+    // Changes made through /reward/schedule, restored from storage.
+    #[serde(skip)]
+    reward_epochs: Vec<RewardEpoch>,
+    difficulty: usize,
+    difficulty_bits: Option<usize>,
+    target_block_time: u64,
+    median_time_span: usize,
+    mempool_difficulty: bool,
+    mempool_full: usize,
+    max_difficulty_boost: usize,
+    benchmark: Option<usize>,
+    max_pow_iterations: Option<usize>,
+    mining_niceness: Option<i32>,
+    min_block_transactions: usize,
+    consensus_interval: u64,
+    consensus_strategy: ConsensusKind,
+    sync_on_start: bool,
+    bootstrap_height: usize,
+    min_consensus_height: usize,
+    max_chain_response: u64,
+    consensus_peers: Option<usize>,
+    orphan_limit: usize,
+    orphan_max_ahead: usize,
+    assume_valid: Option<String>,
+    max_topology_depth: usize,
+    max_peer_requests: usize,
+    schema_versions: Vec<u32>,
+    admin_key: Option<String>,
+    strict_content_type: bool,
+    storage_backend: StorageBackend,
+    storage_path: Option<String>,
+    signing_key: Option<String>,
+    save_interval: Option<u64>,
+    min_fee: f32,
+    min_fee_per_byte: f32,
+    max_outputs: usize,
+    dust_limit: f32,
+    allow_burns: bool,
+    transaction_work_bits: usize,
+    log_level: LogLevel,
+    redact_logs: bool,
+    merkle_root: bool,
+    events: bool,
+    utxo: bool
 }
 
-impl Blockchain {
-    fn new() -> Blockchain {
-        let mut blockchain = Blockchain{ current_transactions: Vec::new(), chain: Vec::new(), nodes: HashSet::new() };
-        let prev_hash = format!("{:x}", Sha256::new().chain(b"1").result());
-        blockchain.new_block(1, &prev_hash);
-        blockchain
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            port: "5000".to_string(),
+            advertised_address: None,
+            base_path: String::new(),
+            node_identifier: format!("{}", Uuid::new_v4()).replace("-", ""),
+            chain_id: DEFAULT_CHAIN_ID.to_string(),
+            mining_reward: DEFAULT_MINING_REWARD,
+            halving_interval: None,
+            supply_cap: None,
+            reward_epochs: Vec::new(),
+            difficulty: DEFAULT_DIFFICULTY,
+            difficulty_bits: None,
+            target_block_time: DEFAULT_TARGET_BLOCK_TIME,
+            median_time_span: DEFAULT_MEDIAN_TIME_SPAN,
+            mempool_difficulty: false,
+            mempool_full: DEFAULT_MEMPOOL_FULL,
+            max_difficulty_boost: DEFAULT_MAX_DIFFICULTY_BOOST,
+            benchmark: None,
+            max_pow_iterations: None,
+            mining_niceness: None,
+            min_block_transactions: 0,
+            consensus_interval: DEFAULT_CONSENSUS_INTERVAL,
+            consensus_strategy: ConsensusKind::Longest,
+            sync_on_start: false,
+            bootstrap_height: DEFAULT_BOOTSTRAP_HEIGHT,
+            min_consensus_height: 0,
+            max_chain_response: DEFAULT_MAX_CHAIN_RESPONSE,
+            consensus_peers: None,
+            orphan_limit: DEFAULT_ORPHAN_LIMIT,
+            orphan_max_ahead: DEFAULT_ORPHAN_MAX_AHEAD,
+            assume_valid: None,
+            max_topology_depth: DEFAULT_TOPOLOGY_DEPTH,
+            max_peer_requests: DEFAULT_MAX_PEER_REQUESTS,
+            schema_versions: vec![SCHEMA_VERSION],
+            admin_key: None,
+            strict_content_type: true,
+            storage_backend: StorageBackend::File,
+            storage_path: None,
+            signing_key: None,
+            save_interval: None,
+            min_fee: 0.0,
+            min_fee_per_byte: 0.0,
+            max_outputs: DEFAULT_MAX_OUTPUTS,
+            dust_limit: 0.0,
+            allow_burns: true,
+            transaction_work_bits: 0,
+            log_level: LogLevel::Info,
+            redact_logs: false,
+            merkle_root: false,
+            events: false,
+            utxo: false
+        }
     }
+}
 
-    fn new_block(&mut self, proof: usize, prev_hash: &str) -> &Block {
-        let block = Block::new(self.chain.len() + 1, self.current_transactions.clone(), proof, prev_hash);
-        self.current_transactions = Vec::new();
-        self.chain.push(block);
-        &self.chain[self.chain.len()-1]
+fn parse_option<T: std::str::FromStr>(option: &str, value: &str) -> Result<T, String>
+where T::Err: fmt::Display {
+    value.parse().map_err(|err| format!("invalid value {} for {}: {}", value, option, err))
+}
+
+impl Config {
+    // The minimum every block is validated against, in leading zero bits of the
+    // proof hash. Each hex digit of difficulty is four bits.
+    fn required_bits(&self) -> usize {
+        self.difficulty_bits.unwrap_or(self.difficulty * 4)
     }
 
-    fn new_transaction(&mut self, sender: &str, recipient: &str, amount: f32) -> usize {
-        let transaction = Transaction::new(sender, recipient, amount);
-        self.current_transactions.push(transaction);
-        match self.chain.last_mut() {
-            Some(block) => block.index + 1,
-            None => 0
+    // The latest runtime change in force at block `index`, or the configured
+    // schedule before the first one.
+    fn schedule_at(&self, index: usize) -> RewardEpoch {
+        self.reward_epochs.iter().rev().find(|epoch| epoch.from <= index).cloned().unwrap_or(RewardEpoch {
+            from: 1,
+            base_reward: self.mining_reward,
+            halving_interval: self.halving_interval,
+            supply_cap: self.supply_cap
+        })
+    }
+
+    // The base reward halves every halving_interval blocks after genesis and is
+    // cut short so that the total minted never exceeds the supply cap.
+    fn reward_at(&self, index: usize, minted: f32) -> f32 {
+        let schedule = self.schedule_at(index);
+        let halvings = schedule.halving_interval.map_or(0, |interval| (index - 1) / interval);
+        let reward = schedule.base_reward / 2f32.powi(halvings.min(i32::MAX as usize) as i32);
+        match schedule.supply_cap {
+            Some(cap) => reward.min(cap - minted).max(0.0),
+            None => reward
         }
     }
 
-    fn register_node(&mut self, node: &str) -> bool {
-        let parsed_url = Url::parse(node).unwrap();
-        if let Some(host) = parsed_url.host_str() {
-            if let Some(port) = parsed_url.port() {
-                return self.nodes.insert(format!("{}:{}", host, port))
-            } else {
-                return self.nodes.insert(format!("{}", host))
+    // Starts from the --config file, if any, and lets the port and every other
+    // option given on the command line override it. The port may be left out
+    // when the file sets it.
+    fn from_args(args: &[String]) -> Result<Config, String> {
+        let mut config = match args.iter().position(|arg| arg == "--config") {
+            Some(position) => {
+                let path = args.get(position + 1).ok_or("missing value for --config")?;
+                Config::from_file(path).map_err(|err| format!("invalid config file {}: {}", path, err))?
+            }
+            None => Config::default()
+        };
+        let skip = match args.get(1) {
+            Some(port) if !port.starts_with("--") => {
+                config.port = port.to_string();
+                2
+            }
+            _ => 1
+        };
+        let mut options = args.iter().skip(skip);
+        while let Some(option) = options.next() {
+            let value = options.next().ok_or_else(|| format!("missing value for {}", option))?;
+            match option.as_str() {
+                "--config" => {}
+                "--node-id" => config.node_identifier = value.to_string(),
+                "--chain-id" => config.chain_id = value.to_string(),
+                "--advertised-address" => config.advertised_address = Some(value.to_string()),
+                "--base-path" => config.base_path = value.to_string(),
+                "--mining-reward" => config.mining_reward = parse_option(option, value)?,
+                "--halving-interval" => config.halving_interval = Some(parse_option(option, value)?),
+                "--supply-cap" => config.supply_cap = Some(parse_option(option, value)?),
+                "--difficulty" => config.difficulty = parse_option(option, value)?,
+                "--difficulty-bits" => config.difficulty_bits = Some(parse_option(option, value)?),
+                "--target-block-time" => config.target_block_time = parse_option(option, value)?,
+                "--median-time-span" => config.median_time_span = parse_option(option, value)?,
+                "--mempool-difficulty" => config.mempool_difficulty = parse_option(option, value)?,
+                "--mempool-full" => config.mempool_full = parse_option(option, value)?,
+                "--max-difficulty-boost" => config.max_difficulty_boost = parse_option(option, value)?,
+                "--max-pow-iterations" => config.max_pow_iterations = Some(parse_option(option, value)?),
+                "--mining-niceness" => config.mining_niceness = Some(parse_option(option, value)?),
+                "--min-block-transactions" => config.min_block_transactions = parse_option(option, value)?,
+                "--benchmark" => config.benchmark = Some(parse_option(option, value)?),
+                "--consensus-interval" => config.consensus_interval = parse_option(option, value)?,
+                "--consensus-strategy" => config.consensus_strategy = ConsensusKind::parse(value)?,
+                "--sync-on-start" => config.sync_on_start = parse_option(option, value)?,
+                "--bootstrap-height" => config.bootstrap_height = parse_option(option, value)?,
+                "--min-consensus-height" => config.min_consensus_height = parse_option(option, value)?,
+                "--max-chain-response" => config.max_chain_response = parse_option(option, value)?,
+                "--consensus-peers" => config.consensus_peers = Some(parse_option(option, value)?),
+                "--orphan-limit" => config.orphan_limit = parse_option(option, value)?,
+                "--orphan-max-ahead" => config.orphan_max_ahead = parse_option(option, value)?,
+                "--assume-valid" => config.assume_valid = Some(value.to_string()),
+                "--max-topology-depth" => config.max_topology_depth = parse_option(option, value)?,
+                "--max-peer-requests" => config.max_peer_requests = parse_option(option, value)?,
+                "--schema-versions" => config.schema_versions = value.split(',').map(|version| parse_option(option, version)).collect::<Result<_, _>>()?,
+                "--admin-key" => config.admin_key = Some(value.to_string()),
+                "--strict-content-type" => config.strict_content_type = parse_option(option, value)?,
+                "--storage-backend" => config.storage_backend = StorageBackend::parse(value)?,
+                "--storage-path" => config.storage_path = Some(value.to_string()),
+                "--signing-key" => config.signing_key = Some(value.to_string()),
+                "--save-interval" => config.save_interval = Some(parse_option(option, value)?),
+                "--min-fee" => config.min_fee = parse_option(option, value)?,
+                "--min-fee-per-byte" => config.min_fee_per_byte = parse_option(option, value)?,
+                "--max-outputs" => config.max_outputs = parse_option(option, value)?,
+                "--dust-limit" => config.dust_limit = parse_option(option, value)?,
+                "--allow-burns" => config.allow_burns = parse_option(option, value)?,
+                "--transaction-work-bits" => config.transaction_work_bits = parse_option(option, value)?,
+                "--log-level" => config.log_level = LogLevel::parse(value)?,
+                "--redact-logs" => config.redact_logs = parse_option(option, value)?,
+                "--merkle-root" => config.merkle_root = parse_option(option, value)?,
+                "--events" => config.events = parse_option(option, value)?,
+                "--utxo" => config.utxo = parse_option(option, value)?,
+                _ => return Err(format!("unknown option {}", option))
             }
         }
-        false
+        config.base_path = format!("/{}", config.base_path.trim_matches('/')).trim_end_matches('/').to_string();
+        Ok(config)
     }
 
-    fn proof_of_work(&self, last_block: &Block) -> usize {
-        let last_proof = last_block.proof;
-        let last_hash = last_block.hash();
-        let mut proof = 0;
-        while !Blockchain::valid_proof(last_proof, proof, last_hash.as_str()) {
-            proof += 1;
-        }
-        proof
-    }
-
-    fn valid_proof(last_proof: usize, proof: usize, last_hash: &str) -> bool {
-        let guess = format!("{}{}{}", last_proof, proof, last_hash);
-        let guess_hash = format!("{:x}", Sha256::new().chain(guess).result());
-        &guess_hash[..5] == "00000"
-    }
-
-    fn valid_chain(chain: &Vec<Block>) -> bool {
-        match chain.first() {
-            Some(mut prev_block) => {
-                for block in chain.iter().skip(1) {
-                    println!("previous block: {:?}", prev_block);
-                    println!("current block: {:?}", block);
-                    println!("----------------");
-                    let prev_block_hash = prev_block.hash();
-                    if block.previous_hash != prev_block_hash {
-                        return false
-                    }
-                    if !Blockchain::valid_proof(prev_block.proof, block.proof, &prev_block_hash) {
-                        dbg!(Blockchain::valid_proof(prev_block.proof, block.proof, &prev_block_hash));
-                        return false
-                    }
-                    prev_block = block;
-                }
-                return true
-            },
-            None => return false
-        }
+    fn from_file(path: &str) -> Result<Config, String> {
+        let contents = fs::read_to_string(path).map_err(|err| err.to_string())?;
+        serde_json::from_str(&contents).map_err(|err| err.to_string())
     }
 
-    fn resolve_conflicts(&mut self) {
-        for node in &self.nodes {
-            let res: FullChain = reqwest::get(&format!("http://{}/chain", node)).unwrap().json().unwrap();
-            if res.length > self.chain.len() && Blockchain::valid_chain(&res.chain) {
-                self.chain = res.chain;
-            }
+    // Catches settings that would only fail once the node is running.
+    fn validate(&self) -> Result<(), String> {
+        if self.port.parse::<u16>().is_err() {
+            return Err(format!("port {:?} is not a valid port number", self.port))
+        }
+        if self.required_bits() > 256 || self.transaction_work_bits > 256 {
+            let bits = self.required_bits().max(self.transaction_work_bits);
+            return Err(format!("a difficulty of {} bits can never be met by a 256-bit hash", bits))
+        }
+        let amounts = [("mining_reward", self.mining_reward), ("min_fee", self.min_fee),
+            ("min_fee_per_byte", self.min_fee_per_byte), ("dust_limit", self.dust_limit)];
+        if let Some((name, value)) = amounts.iter().find(|(_, value)| !value.is_finite() || *value < 0.0) {
+            return Err(format!("{} must be a non-negative number, got {}", name, value))
+        }
+        if matches!(self.mining_niceness, Some(niceness) if !(0..=19).contains(&niceness)) {
+            return Err("mining_niceness must be between 0 and 19".to_string())
         }
+        if self.max_outputs == 0 || self.halving_interval == Some(0) || self.max_peer_requests == 0 {
+            return Err("max_outputs, halving_interval and max_peer_requests must be at least 1".to_string())
+        }
+        Ok(())
     }
 
-    fn full_chain(&self) -> FullChain {
-        FullChain {
-            chain: self.chain.clone(),
-            length: self.chain.len()
-        }
+    // The host:port peers should use to reach this node.
+    // An advertised address is used as is, so behind a proxy it has to include
+    // whatever path the proxy mounts the node under.
+    fn origin(&self) -> String {
+        self.advertised_address.clone().unwrap_or_else(|| format!("127.0.0.1:{}{}", self.port, self.base_path))
     }
 
-    fn node_list(&self) -> NodeList {
-        let mut node_list = Vec::new();
-        for node in self.nodes.iter() {
-            node_list.push(node.to_string());
-        }
-        NodeList {
-            nodes: node_list
+    // Admin endpoints are disabled unless an admin key was configured, and then
+    // require the same key in the X-Admin-Key header.
+    fn authorize_admin(&self, req: &HttpRequest) -> Result<(), HttpResponse> {
+        let provided = req.headers().get(ADMIN_KEY_HEADER).and_then(|value| value.to_str().ok());
+        match (&self.admin_key, provided) {
+            (Some(key), Some(provided)) if key == provided => Ok(()),
+            (None, _) => Err(HttpResponse::Forbidden().json(Response {
+                message: "Admin endpoints are disabled on this node".to_string()
+            })),
+            _ => Err(HttpResponse::Unauthorized().json(Response {
+                message: format!("Missing or incorrect {} header", ADMIN_KEY_HEADER)
+            }))
         }
     }
 }
 
-#[get("/mine")]
-fn mine(blockchain: web::Data<Mutex<Blockchain>>) -> HttpResponse {
-    let mut local_blockchain = blockchain.lock().unwrap();
-    if let Some(last_block) = local_blockchain.chain.last() {
-        let proof = local_blockchain.proof_of_work(last_block);
-        let previous_hash = last_block.hash();
-        let block = local_blockchain.new_block(proof, &previous_hash);
-        return HttpResponse::Ok().json(Mine {
-            message: "New block forged".to_string(),
-            index: block.index,
-            transactions: block.transactions.clone(),
-            proof: proof,
-            previous_hash: previous_hash
-        })
-    }
-    HttpResponse::InternalServerError().json(Response {
-        message: "There was an error mining".to_string()
-    })
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct Response {
+    message: String
 }
 
-#[post("/transactions/new")]
-fn new_transaction(blockchain: web::Data<Mutex<Blockchain>>, req: web::Json<Transaction>) -> HttpResponse {
-    let index = blockchain.lock().unwrap().new_transaction(&req.sender, &req.recipient, req.amount);
-    HttpResponse::Ok().json(Response {
-        message: format!("Your transaction will be in block: {}", index)
-    })
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct FullChain {
+    chain: Vec<Block>,
+    length: usize
 }
 
-#[get("/chain")]
-fn full_chain(blockchain: web::Data<Mutex<Blockchain>>) -> HttpResponse {
-    HttpResponse::Ok().json(blockchain.lock().unwrap().full_chain())
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct SchemaVersions {
+    versions: Vec<u32>,
+    #[serde(default)]
+    base_path: String
 }
 
-#[post("/nodes/register")]
-fn register_nodes(blockchain: web::Data<Mutex<Blockchain>>, req: web::Json<NodeList>) -> HttpResponse {
-    for node in &req.nodes {
-        let _ = blockchain.lock().unwrap().register_node(node);
-    }
-    HttpResponse::Ok().json(Response {
-        message: format!("Nodes successfully registered")
-    })
+#[derive(Deserialize, Clone, Debug)]
+struct MineQuery {
+    start: Option<usize>
 }
 
-#[get("/nodes/resolve")]
-fn consensus(blockchain: web::Data<Mutex<Blockchain>>) -> HttpResponse {
-    blockchain.lock().unwrap().resolve_conflicts();
-    HttpResponse::Ok().json(Response {
-        message: format!("Conflicts resolved")
-    })
+#[derive(Deserialize, Clone, Debug)]
+struct PreviewQuery {
+    miner: Option<String>
 }
 
-#[get("/nodes")]
-fn nodes(blockchain: web::Data<Mutex<Blockchain>>) -> HttpResponse {
-    HttpResponse::Ok().json(blockchain.lock().unwrap().node_list())
+// Only public data: the private signing key never leaves the Signer.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct Identity {
+    node_id: String,
+    public_key: Option<String>,
+    signature_algorithm: Option<String>,
+    chain_id: String,
+    version: String,
+    schema_versions: Vec<u32>
 }
 
-fn main() -> io::Result<()> {
-    let args: Vec<String> = env::args().collect();
-    let port = &args[1];
-    let _ = format!("{}", Uuid::new_v4()).replace("-", "");
-    let blockchain = web::Data::new(Mutex::new(Blockchain::new()));
-    HttpServer::new(move || {
-        App::new()
-            .register_data(blockchain.clone())
-            .wrap(middleware::Logger::default())
-            .service(new_transaction)
-            .service(full_chain)
-            .service(mine)
-            .service(nodes)
-            .service(register_nodes)
-            .service(consensus)
-    })
-    .bind(format!("127.0.0.1:{}", port))?
-    .run()
+#[derive(Deserialize, Clone, Debug)]
+struct ChainQuery {
+    version: Option<u32>
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct NodeList {
+    nodes: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct ConsensusResult {
+    message: String,
+    replaced: bool,
+    extended: bool,
+    length: usize,
+    cached: bool,
+    // Set when no peer could be reached at all, so consensus never ran.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    network_error: Option<String>
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct BlockPreimage {
+    index: usize,
+    preimage: String,
+    preimage_hex: String,
+    hash: String
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct Rollback {
+    blocks: usize
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct Maintenance {
+    enabled: bool
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct Compaction {
+    size_before: u64,
+    size_after: u64
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct RollbackResult {
+    removed: usize,
+    restored_transactions: usize,
+    pending_transactions: usize,
+    tip_index: usize,
+    tip_hash: String
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct ChainWork {
+    work: u128,
+    length: usize,
+    tip_index: usize,
+    tip_hash: String
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct ConsensusDryRun {
+    would_replace: bool,
+    node: Option<String>,
+    local_length: usize,
+    peer_length: usize,
+    rolled_back: usize,
+    added: usize,
+    reason: String
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct Audit {
+    total_supply: f32,
+    total_minted: f32,
+    total_burned: f32,
+    minted_by_node: HashMap<String, f32>
+}
+
+// A peer's supply as reported by its /audit; `agrees` is whether it matches ours.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct PeerSupply {
+    node: String,
+    total_supply: Option<f32>,
+    agrees: bool,
+    error: Option<String>
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct NetworkSupply {
+    local_supply: f32,
+    agreed_supply: f32,
+    reporting: usize,
+    agreeing: usize,
+    peers: Vec<PeerSupply>
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct Conservation {
+    conserved: bool,
+    blocks_checked: usize,
+    total_minted: f32,
+    total_burned: f32,
+    total_balances: f32,
+    violation: Option<Violation>
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct Violation {
+    index: usize,
+    reason: String
+}
+
+// A block announcement carrying only the header and short transaction ids. The
+// coinbase can't be in any peer's mempool, so it is always sent in full.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct CompactBlock {
+    origin: String,
+    index: usize,
+    timestamp: String,
+    proof: usize,
+    previous_hash: String,
+    difficulty: usize,
+    #[serde(default)]
+    difficulty_bits: Option<usize>,
+    #[serde(default)]
+    merkle_root: Option<String>,
+    #[serde(default)]
+    miner_key: Option<String>,
+    #[serde(default)]
+    signature: Option<String>,
+    prefilled: Vec<Transaction>,
+    short_ids: Vec<String>
+}
+
+#[derive(Deserialize, Clone, Debug)]
+struct DifficultyQuery {
+    target_block_time: Option<f64>
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct SuggestedDifficulty {
+    target_block_time: f64,
+    hashrate: f64,
+    current_difficulty: usize,
+    suggested_difficulty: usize
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct NewTransaction {
+    message: String,
+    id: String
+}
+
+// A block is tried first, as any block body would also carry the fields of a
+// transaction it doesn't have.
+#[derive(Deserialize, Clone, Debug)]
+#[serde(untagged)]
+enum SizeRequest {
+    Block(Block),
+    Transaction(Transaction)
+}
+
+// The fee a transaction needs is for its size as posted; a larger fee written
+// with more digits adds a few bytes.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct SizeEstimate {
+    kind: String,
+    size: usize,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    required_fee: Option<f32>
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct TransactionId {
+    id: String
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct SkippedTransaction {
+    position: usize,
+    reason: String
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct MempoolImport {
+    imported: usize,
+    skipped: Vec<SkippedTransaction>,
+    pending_transactions: usize
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct Simulation {
+    sender: String,
+    sender_balance: f32,
+    recipient: String,
+    recipient_balance: f32
+}
+
+#[derive(Deserialize, Clone, Debug)]
+struct WaitQuery {
+    timeout: Option<u64>
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct Confirmation {
+    id: String,
+    block_index: usize
+}
+
+#[derive(Deserialize, Clone, Debug)]
+struct TopologyQuery {
+    depth: Option<usize>
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+struct Edge {
+    from: String,
+    to: String
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct PeerLatency {
+    node: String,
+    latency_ms: Option<f64>,
+    error: Option<String>
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct Topology {
+    nodes: Vec<String>,
+    edges: Vec<Edge>
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct TransactionIds {
+    ids: Vec<String>
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct FeeEstimate {
+    min_fee: f32,
+    min_fee_per_byte: f32,
+    suggested_fee_per_byte: f32
+}
+
+// What a block has to satisfy to be accepted. Limits this node doesn't
+// enforce are reported as null.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct ConsensusRules {
+    chain_id: String,
+    hash_algorithm: String,
+    proof_of_work: String,
+    min_difficulty_bits: usize,
+    max_block_transactions: Option<usize>,
+    max_block_bytes: Option<usize>,
+    reward_schedule: RewardSchedule,
+    max_coinbases_per_block: usize,
+    coinbase_maturity: usize,
+    timestamp_tolerance: Option<u64>,
+    median_time_span: usize,
+    max_address_length: usize,
+    max_category_length: usize
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct RewardSchedule {
+    base_reward: f32,
+    halving_interval: Option<usize>,
+    supply_cap: Option<f32>,
+    minted: f32,
+    next_reward: f32
+}
+
+// A reward schedule set at runtime, in force from block `from` on. Blocks before
+// it are still validated against the schedule they were mined under.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+struct RewardEpoch {
+    from: usize,
+    base_reward: f32,
+    halving_interval: Option<usize>,
+    supply_cap: Option<f32>
+}
+
+#[derive(Deserialize, Clone, Debug)]
+struct RewardScheduleUpdate {
+    base_reward: Option<f32>,
+    halving_interval: Option<usize>,
+    supply_cap: Option<f32>
+}
+
+// How transactions are admitted and picked for blocks. As with ConsensusRules,
+// limits this node doesn't enforce are reported as null. Pending transactions
+// never time out, though each may set its own valid_until_height.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct MempoolPolicy {
+    ordering: String,
+    max_transactions_per_block: Option<usize>,
+    max_block_bytes: Option<usize>,
+    transaction_ttl: Option<u64>,
+    min_fee: f32,
+    min_fee_per_byte: f32,
+    dust_limit: f32,
+    max_outputs: usize,
+    allow_burns: bool,
+    transaction_work_bits: usize
+}
+
+#[derive(Deserialize, Clone, Debug)]
+struct PolicyUpdate {
+    min_fee: Option<f32>,
+    min_fee_per_byte: Option<f32>,
+    dust_limit: Option<f32>,
+    max_outputs: Option<usize>,
+    allow_burns: Option<bool>,
+    transaction_work_bits: Option<usize>
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct Status {
+    node_identifier: String,
+    length: usize,
+    pending_transactions: usize,
+    orphan_blocks: usize,
+    difficulty: usize,
+    min_fee: f32,
+    min_fee_per_byte: f32,
+    max_outputs: usize,
+    dust_limit: f32,
+    safe_mode: bool,
+    safe_mode_reason: Option<String>,
+    mining_paused: bool,
+    maintenance: bool,
+    sync_state: SyncState
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct Genesis {
+    chain_id: String,
+    hash: String,
+    allocations: Vec<Transaction>,
+    block: Block
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct ChainInfo {
+    chain_id: String,
+    height: usize,
+    tip_hash: String,
+    tip_timestamp: String,
+    difficulty: usize,
+    accumulated_work: u128,
+    genesis_hash: String
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct MinedBlock {
+    index: usize,
+    hash: String
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct Reindex {
+    hash_index_entries: usize,
+    stale_entries: usize,
+    safe_mode: bool
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct SelfTest {
+    passed: bool,
+    total_ms: f64,
+    checks: Vec<SelfTestCheck>
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct SelfTestCheck {
+    name: String,
+    passed: bool,
+    elapsed_ms: f64,
+    error: Option<String>
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct OffChainBlock {
+    hash: String,
+    reason: String,
+    block: Block
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct MinerBlocks {
+    address: String,
+    blocks: Vec<MinedBlock>
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct PeerState {
+    node: String,
+    reputation: i64,
+    seconds_since_seen: Option<u64>
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct DebugState {
+    length: usize,
+    tip_index: usize,
+    tip_hash: String,
+    pending_transactions: Vec<Transaction>,
+    peers: Vec<PeerState>,
+    config: Config,
+    safe_mode_reason: Option<String>,
+    seconds_since_consensus: Option<u64>,
+    orphan_blocks: usize,
+    seen_blocks: usize,
+    hashrate: Option<f64>
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct BalanceQuery {
+    addresses: Vec<String>
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct UnspentOutputs {
+    address: String,
+    outputs: Vec<UnspentOutput>,
+    total: f32
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct UnspentOutput {
+    transaction: String,
+    output: usize,
+    amount: f32
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct MinerEarnings {
+    address: String,
+    blocks: usize,
+    rewards: f32,
+    fees: f32,
+    total: f32
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct BlockSize {
+    index: usize,
+    timestamp: String,
+    transaction_count: usize,
+    byte_size: usize
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct DifficultyPoint {
+    index: usize,
+    timestamp: String,
+    difficulty: usize,
+    bits: usize
+}
+
+#[derive(Deserialize, Clone, Debug)]
+struct WindowQuery {
+    from: String,
+    to: String
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct WindowStats {
+    blocks: usize,
+    transactions: usize,
+    total_transferred: f32,
+    average_block_interval: f64
+}
+
+#[derive(Deserialize, Clone, Debug)]
+struct PageQuery {
+    offset: Option<usize>,
+    limit: Option<usize>
+}
+
+// A cursor names the last block a client has seen as "index:hash", so the next
+// page starts right after it no matter how much the chain grew in between.
+#[derive(Deserialize, Clone, Debug)]
+struct CursorQuery {
+    cursor: Option<String>,
+    limit: Option<usize>
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct BlockCursorPage {
+    blocks: Vec<Block>,
+    next_cursor: String
+}
+
+// Hashes of our chain from the tip back to genesis, dense near the tip and ever
+// sparser behind it, so a peer can find where our chains part without either
+// side sending its whole chain.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct BlockLocator {
+    hashes: Vec<String>
+}
+
+// The highest block both chains share; `cursor` resumes /chain/blocks after it.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct ForkPoint {
+    index: usize,
+    hash: String,
+    cursor: String
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct Page<T> {
+    items: Vec<T>,
+    offset: usize,
+    limit: usize,
+    total: usize
+}
+
+impl<T: Clone> Page<T> {
+    fn new(items: &[T], query: &PageQuery) -> Page<T> {
+        let limit = query.limit.unwrap_or(DEFAULT_PAGE_LIMIT);
+        let offset = query.offset.unwrap_or(0).min(items.len());
+        let end = offset.saturating_add(limit).min(items.len());
+        Page {
+            items: items[offset..end].to_vec(),
+            offset,
+            limit,
+            total: items.len()
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+struct TxOutput {
+    recipient: String,
+    amount: f32
+}
+
+// Everything an external miner needs to search for a proof: the proof must make
+// the hash of last_proof, proof and previous_hash start with `bits` zero bits.
+// On nodes that record merkle roots, the merkle root of the transactions follows
+// previous_hash in that hash, and changes with the coinbase extranonce.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct BlockTemplate {
+    index: usize,
+    previous_hash: String,
+    last_proof: usize,
+    bits: usize,
+    transactions: Vec<Transaction>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    merkle_root: Option<String>
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct BlockSubmission {
+    previous_hash: String,
+    proof: usize,
+    bits: usize,
+    transactions: Vec<Transaction>
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct Mine {
+    message: String,
+    index: usize,
+    transactions: Vec<Transaction>,
+    proof: usize,
+    previous_hash: String
+}
+
+// Our rules on top of the ones every node shares: the difficulty floor and
+// retargeting come from the config.
+fn check_block(block: &Block, previous_block: &Block, config: &Config) -> Result<(), BlockchainError> {
+    verify_block(block, previous_block, config.required_bits())?;
+    check_difficulty(block, previous_block, config)
+}
+
+// A coinbase must pay exactly the reward due at its height given what the blocks
+// before it minted, so halvings and the supply cap hold for every miner. Fees
+// are credited on top of it rather than being part of the amount.
+fn check_reward(block: &Block, minted: f32, config: &Config) -> Result<(), BlockchainError> {
+    if let Some(coinbase) = block.coinbase() {
+        let expected = config.reward_at(block.index, minted);
+        if coinbase.amount != expected {
+            return Err(BlockchainError::UnexpectedReward { index: block.index, amount: coinbase.amount, expected })
+        }
+    }
+    Ok(())
+}
+
+// Median time past: a block must be later than the median timestamp of the
+// `span` blocks before it, or of as many as there are near genesis. One miner
+// can't backdate a block past the median on its own, and unlike requiring each
+// block to follow its parent, a single bad timestamp can't hold the chain back.
+// A span of 0 turns the check off.
+fn check_median_time_past(block: &Block, previous_blocks: &[Block], span: usize) -> Result<(), BlockchainError> {
+    let mut times: Vec<DateTime<Utc>> = previous_blocks.iter().rev().take(span).filter_map(Block::time).collect();
+    if times.is_empty() {
+        return Ok(())
+    }
+    times.sort();
+    let median = times[times.len() / 2];
+    match block.time() {
+        Some(time) if time > median => Ok(()),
+        Some(_) => Err(BlockchainError::TimestampTooEarly {
+            index: block.index,
+            timestamp: block.timestamp.clone(),
+            median: median.to_string()
+        }),
+        None => Err(BlockchainError::InvalidTimestamp { index: block.index })
+    }
+}
+
+// A block mined at a bit difficulty records its hex difficulty as those bits
+// rounded down to whole digits, and a hex difficulty must follow the mempool
+// retargeting steps when they are enabled. How full the miner's mempool was
+// can't be checked, so any step it could have taken is allowed: at most one
+// digit from the previous block, never below the floor or above the boost.
+fn check_difficulty(block: &Block, previous_block: &Block, config: &Config) -> Result<(), BlockchainError> {
+    if let Some(bits) = block.difficulty_bits {
+        if block.difficulty != bits / 4 {
+            return Err(BlockchainError::DifficultyMismatch { index: block.index, difficulty: block.difficulty, bits })
+        }
+        return Ok(())
+    }
+    if config.mempool_difficulty && config.difficulty_bits.is_none() {
+        let floor = config.difficulty;
+        let previous = previous_block.difficulty.max(floor);
+        let min = previous.saturating_sub(1).max(floor);
+        let max = (previous + 1).min(floor + config.max_difficulty_boost).max(previous);
+        if block.difficulty < min || block.difficulty > max {
+            return Err(BlockchainError::UnexpectedDifficulty { index: block.index, difficulty: block.difficulty, min, max })
+        }
+    }
+    Ok(())
+}
+
+// The key a node signs the blocks it mines with. Its coinbases pay the address
+// derived from the public key, which ties each signature to the block's miner.
+#[derive(Clone, Debug)]
+struct Signer {
+    key: EcKey<Private>,
+    public_key: String
+}
+
+impl Signer {
+    // Reads the PEM-encoded key at `path`, or generates one and saves it there.
+    fn load_or_create(path: &str) -> io::Result<Signer> {
+        let to_io = io::Error::other;
+        let key = match fs::read(path) {
+            Ok(pem) => EcKey::private_key_from_pem(&pem).map_err(to_io)?,
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => {
+                let key = EcKey::generate(&signing_group()).map_err(to_io)?;
+                fs::write(path, key.private_key_to_pem().map_err(to_io)?)?;
+                key
+            }
+            Err(err) => return Err(err)
+        };
+        let mut ctx = BigNumContext::new().map_err(to_io)?;
+        let public_key = key.public_key().to_bytes(&signing_group(), PointConversionForm::COMPRESSED, &mut ctx).map_err(to_io)?;
+        Ok(Signer { key, public_key: to_hex(&public_key) })
+    }
+
+    fn address(&self) -> String {
+        key_address(&self.public_key)
+    }
+
+    fn sign(&self, block: &mut Block) {
+        block.miner_key = Some(self.public_key.clone());
+        let signature = EcdsaSig::sign(&block.signing_hash(), &self.key).and_then(|signature| signature.to_der()).unwrap();
+        block.signature = Some(to_hex(&signature));
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct Snapshot {
+    chain: Vec<Block>,
+    current_transactions: Vec<Transaction>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    reward_epochs: Vec<RewardEpoch>
+}
+
+// How a peer has behaved during consensus: good answers raise its reputation,
+// failed requests and invalid chains lower it.
+#[derive(Default, Clone, Debug)]
+struct PeerStats {
+    reputation: i64,
+    last_seen: Option<Instant>
+}
+
+// Wakes long-polling requests whenever a block is added to the chain. Waiters
+// remember the generation they saw so a block added in between isn't missed.
+#[derive(Default, Debug)]
+struct BlockNotifier {
+    generation: Mutex<u64>,
+    added: Condvar
+}
+
+impl BlockNotifier {
+    fn generation(&self) -> u64 {
+        *self.generation.lock().unwrap()
+    }
+
+    fn notify(&self) {
+        *self.generation.lock().unwrap() += 1;
+        self.added.notify_all();
+    }
+
+    // Returns false if the timeout elapsed without a new block.
+    fn wait(&self, seen: u64, timeout: Duration) -> bool {
+        let generation = self.generation.lock().unwrap();
+        let (generation, _) = self.added.wait_timeout_while(generation, timeout, |generation| *generation == seen).unwrap();
+        *generation != seen
+    }
+}
+
+#[derive(Default, Debug)]
+struct EventState {
+    next_id: u64,
+    history: VecDeque<(u64, web::Bytes)>,
+    subscribers: Vec<UnboundedSender<web::Bytes>>
+}
+
+// Fans events out to Server-Sent Events subscribers. Recent events are kept so a
+// reconnecting client can resume after the last id it saw.
+#[derive(Default, Debug)]
+struct EventBus {
+    state: Mutex<EventState>
+}
+
+impl EventBus {
+    // Subscribers whose client went away are dropped as soon as sending to them fails.
+    fn publish<T: Serialize>(&self, event: &str, data: &T) {
+        let mut state = self.state.lock().unwrap();
+        state.next_id += 1;
+        let id = state.next_id;
+        let message = web::Bytes::from(format!("id: {}\nevent: {}\ndata: {}\n\n", id, event, serde_json::to_string(data).unwrap()));
+        if state.history.len() >= EVENT_HISTORY {
+            state.history.pop_front();
+        }
+        state.history.push_back((id, message.clone()));
+        state.subscribers.retain(|subscriber| subscriber.unbounded_send(message.clone()).is_ok());
+    }
+
+    fn subscribe(&self, last_event_id: Option<u64>) -> UnboundedReceiver<web::Bytes> {
+        let (sender, receiver) = mpsc::unbounded();
+        let mut state = self.state.lock().unwrap();
+        if let Some(last_event_id) = last_event_id {
+            for (_, message) in state.history.iter().filter(|(id, _)| *id > last_event_id) {
+                let _ = sender.unbounded_send(message.clone());
+            }
+        }
+        state.subscribers.push(sender);
+        receiver
+    }
+}
+
+fn timed_check<F: FnOnce() -> Result<(), String>>(name: &str, check: F) -> SelfTestCheck {
+    let started = Instant::now();
+    let result = check();
+    SelfTestCheck {
+        name: name.to_string(),
+        passed: result.is_ok(),
+        elapsed_ms: started.elapsed().as_secs_f64() * 1000.0,
+        error: result.err()
+    }
+}
+
+thread_local! {
+    static MINING_NICENESS_SET: Cell<bool> = const { Cell::new(false) };
+}
+
+// On Linux every thread has its own niceness, which setpriority sets when given
+// the thread id.
+#[cfg(target_os = "linux")]
+fn set_thread_niceness(niceness: i32) -> Result<(), String> {
+    let result = unsafe {
+        let thread_id = libc::syscall(libc::SYS_gettid) as libc::id_t;
+        libc::setpriority(libc::PRIO_PROCESS as _, thread_id, niceness)
+    };
+    if result != 0 {
+        return Err(io::Error::last_os_error().to_string())
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_thread_niceness(_niceness: i32) -> Result<(), String> {
+    Err("setting the niceness of a thread is not supported on this platform".to_string())
+}
+
+// Where block timestamps come from, so they can be controlled instead of
+// following the wall clock.
+trait Clock: fmt::Debug + Send + Sync {
+    fn now(&self) -> DateTime<Utc>;
+}
+
+#[derive(Debug)]
+struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> DateTime<Utc> {
+        Utc::now()
+    }
+}
+
+fn system_clock() -> Arc<dyn Clock> {
+    Arc::new(SystemClock)
+}
+
+// Bounded record of recently seen block hashes, evicting the least recently seen
+// one once full.
+#[derive(Default, Clone, Debug)]
+struct SeenBlocks {
+    order: VecDeque<String>,
+    hashes: HashSet<String>
+}
+
+impl SeenBlocks {
+    fn contains(&mut self, hash: &str) -> bool {
+        if !self.hashes.contains(hash) {
+            return false
+        }
+        self.order.retain(|seen| seen != hash);
+        self.order.push_back(hash.to_string());
+        true
+    }
+
+    fn insert(&mut self, hash: String) {
+        if self.contains(&hash) {
+            return
+        }
+        if self.order.len() >= SEEN_BLOCKS_CAPACITY {
+            if let Some(oldest) = self.order.pop_front() {
+                self.hashes.remove(&oldest);
+            }
+        }
+        self.hashes.insert(hash.clone());
+        self.order.push_back(hash);
+    }
+}
+
+trait Persistence: fmt::Debug + Send + Sync {
+    fn save(&self, snapshot: &Snapshot) -> io::Result<()>;
+    fn load(&self) -> io::Result<Option<Snapshot>>;
+    // Reads the copy kept by the previous save, for stores that keep one.
+    fn recover(&self) -> io::Result<Option<Snapshot>> {
+        Ok(None)
+    }
+    // Rewrites everything from scratch instead of incrementally.
+    fn compact(&self, snapshot: &Snapshot) -> io::Result<()>;
+    fn size(&self) -> io::Result<u64>;
+}
+
+// Keeps the whole chain and mempool as a single JSON document. Saves are written
+// to a temporary file and renamed into place, keeping the previous save as a
+// backup, so a crash mid-save never leaves only a truncated file behind.
+#[derive(Debug)]
+struct FileStore {
+    path: PathBuf
+}
+
+impl FileStore {
+    fn new(path: &str) -> FileStore {
+        FileStore { path: PathBuf::from(path) }
+    }
+
+    fn with_extension(&self, extension: &str) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(extension);
+        PathBuf::from(path)
+    }
+
+    fn read(path: &PathBuf) -> io::Result<Option<Snapshot>> {
+        match fs::read_to_string(path) {
+            Ok(contents) => Ok(Some(serde_json::from_str(&contents)?)),
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err)
+        }
+    }
+}
+
+impl Persistence for FileStore {
+    fn save(&self, snapshot: &Snapshot) -> io::Result<()> {
+        let temporary = self.with_extension(".tmp");
+        let mut file = fs::File::create(&temporary)?;
+        file.write_all(serde_json::to_string(snapshot)?.as_bytes())?;
+        file.sync_all()?;
+        if self.path.exists() {
+            fs::rename(&self.path, self.with_extension(".bak"))?;
+        }
+        fs::rename(&temporary, &self.path)
+    }
+
+    fn load(&self) -> io::Result<Option<Snapshot>> {
+        FileStore::read(&self.path)
+    }
+
+    fn recover(&self) -> io::Result<Option<Snapshot>> {
+        FileStore::read(&self.with_extension(".bak"))
+    }
+
+    // Every save already rewrites the whole file.
+    fn compact(&self, snapshot: &Snapshot) -> io::Result<()> {
+        self.save(snapshot)
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        Ok(fs::metadata(&self.path)?.len())
+    }
+}
+
+// Stores one row per block keyed by index, so saves only append the blocks that
+// changed instead of rewriting the chain.
+#[cfg(feature = "sqlite")]
+#[derive(Debug)]
+struct SqliteStore {
+    connection: Mutex<rusqlite::Connection>
+}
+
+#[cfg(feature = "sqlite")]
+fn sqlite_error(err: rusqlite::Error) -> io::Error {
+    io::Error::other(err)
+}
+
+#[cfg(feature = "sqlite")]
+impl SqliteStore {
+    fn open(path: &str) -> io::Result<SqliteStore> {
+        let connection = rusqlite::Connection::open(path).map_err(sqlite_error)?;
+        connection.execute_batch(
+            "CREATE TABLE IF NOT EXISTS blocks (idx INTEGER PRIMARY KEY, hash TEXT NOT NULL, data TEXT NOT NULL);
+             CREATE TABLE IF NOT EXISTS mempool (position INTEGER PRIMARY KEY, data TEXT NOT NULL);
+             CREATE TABLE IF NOT EXISTS reward_epochs (position INTEGER PRIMARY KEY, data TEXT NOT NULL);"
+        ).map_err(sqlite_error)?;
+        Ok(SqliteStore { connection: Mutex::new(connection) })
+    }
+
+    // Writes the snapshot as part of `transaction`. Stored blocks are kept up to
+    // the first one that differs from the chain, or all replaced with `rewrite`.
+    fn write(transaction: &rusqlite::Transaction, snapshot: &Snapshot, rewrite: bool) -> io::Result<()> {
+        let chain = &snapshot.chain;
+        let common = if rewrite {
+            0
+        } else {
+            let stored_hashes: Vec<String> = {
+                let mut statement = transaction.prepare("SELECT hash FROM blocks ORDER BY idx").map_err(sqlite_error)?;
+                let rows = statement.query_map(rusqlite::NO_PARAMS, |row| row.get(0)).map_err(sqlite_error)?;
+                rows.collect::<Result<_, _>>().map_err(sqlite_error)?
+            };
+            stored_hashes.iter().zip(chain.iter())
+                .take_while(|(stored, block)| **stored == block.hash())
+                .count()
+        };
+        transaction.execute("DELETE FROM blocks WHERE idx > ?1", rusqlite::params![common as i64]).map_err(sqlite_error)?;
+        for block in &chain[common..] {
+            transaction.execute(
+                "INSERT INTO blocks (idx, hash, data) VALUES (?1, ?2, ?3)",
+                rusqlite::params![block.index as i64, block.hash(), serde_json::to_string(block)?]
+            ).map_err(sqlite_error)?;
+        }
+        transaction.execute("DELETE FROM mempool", rusqlite::NO_PARAMS).map_err(sqlite_error)?;
+        for (position, pending) in snapshot.current_transactions.iter().enumerate() {
+            transaction.execute(
+                "INSERT INTO mempool (position, data) VALUES (?1, ?2)",
+                rusqlite::params![position as i64, serde_json::to_string(pending)?]
+            ).map_err(sqlite_error)?;
+        }
+        transaction.execute("DELETE FROM reward_epochs", rusqlite::NO_PARAMS).map_err(sqlite_error)?;
+        for (position, epoch) in snapshot.reward_epochs.iter().enumerate() {
+            transaction.execute(
+                "INSERT INTO reward_epochs (position, data) VALUES (?1, ?2)",
+                rusqlite::params![position as i64, serde_json::to_string(epoch)?]
+            ).map_err(sqlite_error)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(feature = "sqlite")]
+impl Persistence for SqliteStore {
+    fn save(&self, snapshot: &Snapshot) -> io::Result<()> {
+        let mut connection = self.connection.lock().unwrap();
+        let transaction = connection.transaction().map_err(sqlite_error)?;
+        SqliteStore::write(&transaction, snapshot, false)?;
+        transaction.commit().map_err(sqlite_error)
+    }
+
+    fn load(&self) -> io::Result<Option<Snapshot>> {
+        let connection = self.connection.lock().unwrap();
+        let read_column = |sql: &str| -> io::Result<Vec<String>> {
+            let mut statement = connection.prepare(sql).map_err(sqlite_error)?;
+            let rows = statement.query_map(rusqlite::NO_PARAMS, |row| row.get(0)).map_err(sqlite_error)?;
+            rows.collect::<Result<_, _>>().map_err(sqlite_error)
+        };
+        let chain = read_column("SELECT data FROM blocks ORDER BY idx")?.iter()
+            .map(|data| serde_json::from_str(data))
+            .collect::<Result<Vec<Block>, _>>()?;
+        if chain.is_empty() {
+            return Ok(None)
+        }
+        let current_transactions = read_column("SELECT data FROM mempool ORDER BY position")?.iter()
+            .map(|data| serde_json::from_str(data))
+            .collect::<Result<Vec<Transaction>, _>>()?;
+        let reward_epochs = read_column("SELECT data FROM reward_epochs ORDER BY position")?.iter()
+            .map(|data| serde_json::from_str(data))
+            .collect::<Result<Vec<RewardEpoch>, _>>()?;
+        Ok(Some(Snapshot { chain, current_transactions, reward_epochs }))
+    }
+
+    // The rows are replaced in a single transaction, so a failure part way leaves
+    // the previous save in place. VACUUM can't run inside one and follows it.
+    fn compact(&self, snapshot: &Snapshot) -> io::Result<()> {
+        let mut connection = self.connection.lock().unwrap();
+        let transaction = connection.transaction().map_err(sqlite_error)?;
+        SqliteStore::write(&transaction, snapshot, true)?;
+        transaction.commit().map_err(sqlite_error)?;
+        connection.execute_batch("VACUUM").map_err(sqlite_error)
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        let connection = self.connection.lock().unwrap();
+        let pages: i64 = connection.query_row("PRAGMA page_count", rusqlite::NO_PARAMS, |row| row.get(0)).map_err(sqlite_error)?;
+        let page_size: i64 = connection.query_row("PRAGMA page_size", rusqlite::NO_PARAMS, |row| row.get(0)).map_err(sqlite_error)?;
+        Ok((pages * page_size) as u64)
+    }
+}
+
+fn open_storage(backend: StorageBackend, path: &str) -> io::Result<Arc<dyn Persistence>> {
+    match backend {
+        StorageBackend::File => Ok(Arc::new(FileStore::new(path))),
+        #[cfg(feature = "sqlite")]
+        StorageBackend::Sqlite => Ok(Arc::new(SqliteStore::open(path)?)),
+        #[cfg(not(feature = "sqlite"))]
+        StorageBackend::Sqlite => Err(io::Error::other("built without the sqlite feature"))
+    }
+}
+
+struct PeerChain {
+    node: String,
+    chain: Vec<Block>
+}
+
+// Decides which peer chains are worth adopting over ours. Chains are only
+// validated once a strategy picks them, so it ranks them best first and the
+// first valid one is adopted. While bootstrapping, a chain as good as ours is
+// worth adopting too.
+trait ConsensusStrategy {
+    fn rank(&self, local: &[Block], peers: &[PeerChain], bootstrapping: bool) -> Vec<usize>;
+}
+
+// The longest chain wins, the first peer to send it among equals.
+struct LongestChain;
+
+impl ConsensusStrategy for LongestChain {
+    fn rank(&self, local: &[Block], peers: &[PeerChain], bootstrapping: bool) -> Vec<usize> {
+        let mut ranked: Vec<usize> = (0..peers.len())
+            .filter(|position| {
+                let length = peers[*position].chain.len();
+                length > local.len() || (bootstrapping && length == local.len())
+            })
+            .collect();
+        ranked.sort_by(|a, b| peers[*b].chain.len().cmp(&peers[*a].chain.len()));
+        ranked
+    }
+}
+
+// The chain with the most accumulated work wins, so a shorter chain mined at a
+// higher difficulty beats a longer, easier one.
+struct MostWork;
+
+impl ConsensusStrategy for MostWork {
+    fn rank(&self, local: &[Block], peers: &[PeerChain], bootstrapping: bool) -> Vec<usize> {
+        let local_work = Blockchain::accumulated_work(local);
+        let work: Vec<u128> = peers.iter().map(|peer| Blockchain::accumulated_work(&peer.chain)).collect();
+        let mut ranked: Vec<usize> = (0..peers.len())
+            .filter(|position| work[*position] > local_work || (bootstrapping && work[*position] == local_work))
+            .collect();
+        ranked.sort_by(|a, b| work[*b].cmp(&work[*a]));
+        ranked
+    }
+}
+
+// Like longest chain, but only chains whose tip more than half of the peers
+// that answered agree on, so a single peer can't lead us onto its fork.
+struct Quorum;
+
+impl ConsensusStrategy for Quorum {
+    fn rank(&self, local: &[Block], peers: &[PeerChain], bootstrapping: bool) -> Vec<usize> {
+        let tips: Vec<Option<String>> = peers.iter().map(|peer| peer.chain.last().map(Block::hash)).collect();
+        let quorum = peers.len() / 2 + 1;
+        LongestChain.rank(local, peers, bootstrapping).into_iter()
+            .filter(|position| tips.iter().filter(|tip| **tip == tips[*position]).count() >= quorum)
+            .collect()
+    }
+}
+
+fn consensus_strategy(kind: ConsensusKind) -> Box<dyn ConsensusStrategy> {
+    match kind {
+        ConsensusKind::Longest => Box::new(LongestChain),
+        ConsensusKind::MostWork => Box::new(MostWork),
+        ConsensusKind::Quorum => Box::new(Quorum)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Received {
+    Accepted,
+    AlreadySeen,
+    Buffered
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct Blockchain {
+    current_transactions: Vec<Transaction>,
+    chain: Vec<Block>,
+    nodes: HashSet<String>,
+    config: Config,
+    #[serde(skip)]
+    last_consensus: Option<(Instant, ConsensusResult)>,
+    #[serde(skip)]
+    bootstrapped: bool,
+    #[serde(skip)]
+    sync_state: SyncState,
+    #[serde(skip)]
+    storage: Option<Arc<dyn Persistence>>,
+    #[serde(skip)]
+    corruption: Option<String>,
+    #[serde(skip)]
+    seen_blocks: SeenBlocks,
+    // Received blocks that never made it onto our chain, oldest first, for
+    // looking into forks. Buffered orphans are kept separately.
+    #[serde(skip)]
+    side_blocks: VecDeque<OffChainBlock>,
+    #[serde(skip)]
+    hash_index: HashMap<String, usize>,
+    #[serde(skip)]
+    hashrate_samples: VecDeque<(usize, Duration)>,
+    #[serde(skip)]
+    block_notifier: Arc<BlockNotifier>,
+    #[serde(skip)]
+    peer_stats: HashMap<String, PeerStats>,
+    #[serde(skip)]
+    events: Arc<EventBus>,
+    #[serde(skip)]
+    orphans: Vec<Block>,
+    #[serde(skip)]
+    mining_paused: bool,
+    #[serde(skip)]
+    maintenance: bool,
+    #[serde(skip)]
+    unsaved: bool,
+    #[serde(skip)]
+    signer: Option<Arc<Signer>>,
+    #[serde(skip, default = "system_clock")]
+    clock: Arc<dyn Clock>
+}
+
+impl Blockchain {
+    fn new(config: Config) -> Blockchain {
+        Blockchain::with_clock(config, system_clock())
+    }
+
+    fn with_clock(config: Config, clock: Arc<dyn Clock>) -> Blockchain {
+        let sync_state = if config.sync_on_start { SyncState::Syncing } else { SyncState::Synced };
+        let mut blockchain = Blockchain {
+            current_transactions: Vec::new(),
+            chain: Vec::new(),
+            nodes: HashSet::new(),
+            config,
+            last_consensus: None,
+            bootstrapped: false,
+            sync_state,
+            storage: None,
+            corruption: None,
+            seen_blocks: SeenBlocks::default(),
+            side_blocks: VecDeque::new(),
+            hash_index: HashMap::new(),
+            hashrate_samples: VecDeque::new(),
+            block_notifier: Arc::new(BlockNotifier::default()),
+            peer_stats: HashMap::new(),
+            events: Arc::new(EventBus::default()),
+            orphans: Vec::new(),
+            mining_paused: false,
+            maintenance: false,
+            unsaved: false,
+            signer: None,
+            clock
+        };
+        let prev_hash = format!("{:x}", Sha256::new().chain(b"1").result());
+        blockchain.new_block(1, &prev_hash);
+        blockchain
+    }
+
+    fn new_block(&mut self, proof: usize, prev_hash: &str) -> &Block {
+        let mut block = self.assemble_block(proof, prev_hash, self.current_transactions.clone(), self.next_bits());
+        if let Some(signer) = &self.signer {
+            signer.sign(&mut block);
+        }
+        self.log(LogLevel::Info, &format!("block {} forged with {} transactions", block.index, block.transactions.len()));
+        self.log(LogLevel::Debug, &format!("block {} hash: {}", block.index, block.hash()));
+        for transaction in &block.transactions {
+            self.log_transaction("block transaction", transaction);
+        }
+        self.current_transactions = Vec::new();
+        self.seen_blocks.insert(block.hash());
+        self.emit("block", &block);
+        self.chain.push(block);
+        self.index_from(self.chain.len() - 1);
+        self.persist();
+        self.block_notifier.notify();
+        &self.chain[self.chain.len()-1]
+    }
+
+    fn assemble_block(&self, proof: usize, prev_hash: &str, transactions: Vec<Transaction>, bits: usize) -> Block {
+        let mut block = Block::new(self.chain.len() + 1, self.clock.now(), transactions, proof, prev_hash, bits / 4);
+        if self.config.difficulty_bits.is_some() {
+            block.difficulty_bits = Some(bits);
+        }
+        if self.config.merkle_root {
+            block.merkle_root = Some(block.compute_merkle_root());
+        }
+        block
+    }
+
+    fn block_template(&self, miner: Option<&str>) -> BlockTemplate {
+        let tip = &self.chain[self.chain.len() - 1];
+        let transactions = self.next_transactions(miner.unwrap_or(&self.config.node_identifier));
+        BlockTemplate {
+            index: tip.index + 1,
+            previous_hash: tip.hash(),
+            last_proof: tip.proof,
+            bits: self.next_bits(),
+            merkle_root: Some(merkle_root(&transactions)).filter(|_| self.config.merkle_root),
+            transactions
+        }
+    }
+
+    // Appends a block mined elsewhere from one of our templates. The block goes
+    // through the same checks as one received from a peer, so the proof and the
+    // chosen transactions are validated against the chain as it is now. Of the
+    // coinbase, only the recipient and the extranonce are up to the miner.
+    fn submit_block(&mut self, submission: BlockSubmission) -> Result<Block, BlockchainError> {
+        if submission.previous_hash != self.chain[self.chain.len() - 1].hash() {
+            return Err(BlockchainError::StaleTemplate { previous_hash: submission.previous_hash })
+        }
+        if let Some(submitted) = submission.transactions.first().filter(|first| first.sender == COINBASE_SENDER) {
+            let issued = self.coinbase_for(&submitted.recipient).map(|mut coinbase| {
+                coinbase.extranonce = submitted.extranonce;
+                coinbase
+            });
+            if issued.as_ref() != Some(submitted) {
+                return Err(BlockchainError::ForgedCoinbase { index: self.chain.len() + 1 })
+            }
+        }
+        let block = self.assemble_block(submission.proof, &submission.previous_hash, submission.transactions, submission.bits);
+        self.accept_block(block.clone())?;
+        Ok(block)
+    }
+
+    // Loads the saved chain from the configured storage, or saves the fresh one if
+    // nothing has been stored yet. Every later change is saved as it happens.
+    fn attach_storage(&mut self) -> io::Result<()> {
+        let path = match &self.config.storage_path {
+            Some(path) => path.clone(),
+            None => return Ok(())
+        };
+        let storage = open_storage(self.config.storage_backend, &path)?;
+        // Falls back to the backup when the primary copy is missing or unreadable.
+        let loaded = match storage.load() {
+            Ok(Some(snapshot)) => Some(snapshot),
+            primary => match storage.recover() {
+                Ok(Some(snapshot)) => {
+                    self.log(LogLevel::Info, &format!("recovered the chain from the backup of {}", path));
+                    Some(snapshot)
+                },
+                _ => primary?
+            }
+        };
+        if let Some(snapshot) = loaded {
+            self.chain = snapshot.chain;
+            self.hash_index = Blockchain::hash_index(&self.chain);
+            self.current_transactions = snapshot.current_transactions;
+            self.config.reward_epochs = snapshot.reward_epochs;
+        }
+        self.storage = Some(storage);
+        self.self_check();
+        self.persist();
+        Ok(())
+    }
+
+    // With a save interval configured, changes are only marked here and written
+    // out together on the next tick of the save thread.
+    fn persist(&mut self) {
+        if self.config.save_interval.is_some() {
+            self.unsaved = true;
+        } else {
+            self.save();
+        }
+    }
+
+    fn save_if_unsaved(&mut self) {
+        if self.unsaved {
+            self.unsaved = false;
+            self.save();
+        }
+    }
+
+    fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            chain: self.chain.clone(),
+            current_transactions: self.current_transactions.clone(),
+            reward_epochs: self.config.reward_epochs.clone()
+        }
+    }
+
+    fn save(&self) {
+        if let Some(storage) = &self.storage {
+            if let Err(err) = storage.save(&self.snapshot()) {
+                self.log(LogLevel::Error, &format!("failed to save the chain: {}", err));
+            }
+        }
+    }
+
+    // Rewrites the storage from the in-memory chain and reads it back, failing if
+    // what was stored doesn't match what we hold.
+    fn compact_storage(&self) -> io::Result<Option<Compaction>> {
+        let storage = match &self.storage {
+            Some(storage) => storage,
+            None => return Ok(None)
+        };
+        let size_before = storage.size()?;
+        storage.compact(&self.snapshot())?;
+        let reloaded = storage.load()?.map(|snapshot| snapshot.chain.iter().map(Block::hash).collect::<Vec<_>>());
+        if reloaded != Some(self.chain.iter().map(Block::hash).collect()) {
+            return Err(io::Error::other("the compacted chain does not match the chain in memory"))
+        }
+        Ok(Some(Compaction { size_before, size_after: storage.size()? }))
+    }
+
+    fn new_transaction(&mut self, transaction: Transaction) -> Result<usize, BlockchainError> {
+        self.validate_transaction(&transaction)?;
+        self.log(LogLevel::Info, "new transaction received");
+        self.log_transaction("new transaction", &transaction);
+        self.emit("transaction", &transaction);
+        self.current_transactions.push(transaction);
+        self.persist();
+        match self.chain.last_mut() {
+            Some(block) => Ok(block.index + 1),
+            None => Ok(0)
+        }
+    }
+
+    // Mined transactions are replayed against these same rules, so a pending
+    // transaction that passes them can't make our own block invalid.
+    // Each transaction goes through the same checks as a new submission, so ones
+    // we already hold or can't accept are skipped rather than failing the import.
+    fn import_transactions(&mut self, transactions: Vec<Transaction>) -> MempoolImport {
+        let mut imported = 0;
+        let mut skipped = Vec::new();
+        for (position, transaction) in transactions.into_iter().enumerate() {
+            match self.new_transaction(transaction.normalized()) {
+                Ok(_) => imported += 1,
+                Err(err) => skipped.push(SkippedTransaction { position, reason: err.to_string() })
+            }
+        }
+        MempoolImport { imported, skipped, pending_transactions: self.current_transactions.len() }
+    }
+
+    // Projects balances as if the transaction were mined after everything already
+    // pending, without touching the mempool.
+    fn simulate_transaction(&self, transaction: &Transaction) -> Result<Simulation, BlockchainError> {
+        self.validate_transaction(transaction)?;
+        let mut balances = Blockchain::balances(&self.chain);
+        for pending in self.current_transactions.iter().chain(std::iter::once(transaction)) {
+            Blockchain::apply_transaction(&mut balances, pending);
+        }
+        let balance = |address: &str| balances.get(address).cloned().unwrap_or(0.0);
+        Ok(Simulation {
+            sender: transaction.sender.clone(),
+            sender_balance: balance(&transaction.sender),
+            recipient: transaction.recipient.clone(),
+            recipient_balance: balance(&transaction.recipient)
+        })
+    }
+
+    fn validate_transaction(&self, transaction: &Transaction) -> Result<(), BlockchainError> {
+        check_transaction(transaction)?;
+        if transaction.sender == COINBASE_SENDER {
+            return Err(BlockchainError::ReservedSender)
+        }
+        // Burns stay valid in blocks; refusing them is only relay policy.
+        if transaction.recipient == BURN_ADDRESS && !self.config.allow_burns {
+            return Err(BlockchainError::BurnsDisabled)
+        }
+        let id = transaction.id();
+        if self.current_transactions.contains(transaction) || Blockchain::transaction_ids(&self.chain).contains(&id) {
+            return Err(BlockchainError::DuplicateTransaction { id })
+        }
+        let next_index = self.chain.len() + 1;
+        if let Some(valid_until_height) = transaction.valid_until_height.filter(|_| transaction.expired_at(next_index)) {
+            return Err(BlockchainError::ExpiredTransaction { index: next_index, valid_until_height })
+        }
+        let mut balances = Blockchain::balances(&self.chain);
+        for pending in &self.current_transactions {
+            Blockchain::apply_transaction(&mut balances, pending);
+        }
+        if Blockchain::overdraws(&balances, transaction) {
+            return Err(BlockchainError::Overdraft { index: self.chain.len() + 1, sender: transaction.sender.clone() })
+        }
+        if self.config.utxo && transaction.inputs.is_empty() {
+            return Err(BlockchainError::MissingInputs)
+        }
+        // Inputs may spend the outputs of pending transactions, but only of ones
+        // already in the mempool. As an id covers the inputs, no earlier transaction
+        // can name a later one, so dependencies never form a cycle and mining the
+        // mempool in order always spends an output after it was created.
+        let mut unspent = Blockchain::unspent_outputs(&self.chain);
+        for pending in &self.current_transactions {
+            Blockchain::spend_outputs(&mut unspent, pending);
+        }
+        let change = Blockchain::check_inputs(&unspent, transaction)? - transaction.amount - transaction.fee;
+        let outputs: Vec<f32> = std::iter::once(transaction.amount).chain(Some(change).filter(|change| *change > 0.0)).collect();
+        if outputs.len() > self.config.max_outputs {
+            return Err(BlockchainError::TooManyOutputs { count: outputs.len(), max: self.config.max_outputs })
+        }
+        if let Some(amount) = outputs.into_iter().find(|amount| *amount < self.config.dust_limit) {
+            return Err(BlockchainError::DustOutput { amount, limit: self.config.dust_limit })
+        }
+        let fee = transaction.fee;
+        if fee < self.config.min_fee {
+            return Err(BlockchainError::FeeTooLow { fee, required: self.config.min_fee })
+        }
+        if transaction.fee_per_byte() < self.config.min_fee_per_byte {
+            let size = transaction.byte_size();
+            return Err(BlockchainError::FeeRateTooLow {
+                size,
+                fee,
+                required: self.config.min_fee_per_byte * size as f32
+            })
+        }
+        let bits = transaction.work_bits();
+        if bits < self.config.transaction_work_bits {
+            return Err(BlockchainError::InsufficientWork { bits, required: self.config.transaction_work_bits })
+        }
+        Ok(())
+    }
+
+    fn emit<T: Serialize>(&self, event: &str, data: &T) {
+        if self.config.events {
+            self.events.publish(event, data);
+        }
+    }
+
+    fn logger(&self) -> Logger {
+        Logger { level: self.config.log_level, redact: self.config.redact_logs }
+    }
+
+    fn log(&self, level: LogLevel, message: &str) {
+        self.logger().log(level, message);
+    }
+
+    // Errors that name an address have it redacted the same way.
+    fn log_error(&self, err: &BlockchainError) -> String {
+        if !self.config.redact_logs {
+            return err.to_string()
+        }
+        let mut err = err.clone();
+        match &mut err {
+            BlockchainError::Overdraft { sender: address, .. }
+            | BlockchainError::ForeignOutput { sender: address, .. }
+            | BlockchainError::InvalidAddress { address }
+            | BlockchainError::MinerMismatch { address, .. } => *address = REDACTED.to_string(),
+            BlockchainError::SenderMismatch { sender, address } => {
+                *sender = REDACTED.to_string();
+                *address = REDACTED.to_string();
+            }
+            _ => {}
+        }
+        err.to_string()
+    }
+
+    // Addresses are the sensitive part of a transaction, so they are what gets redacted.
+    fn log_transaction(&self, event: &str, transaction: &Transaction) {
+        self.log(LogLevel::Debug, &self.transaction_log_line(event, transaction));
+    }
+
+    fn transaction_log_line(&self, event: &str, transaction: &Transaction) -> String {
+        let redact = |address: &str| if self.config.redact_logs { REDACTED.to_string() } else { address.to_string() };
+        format!("{}: {} -> {} ({})", event, redact(&transaction.sender), redact(&transaction.recipient), transaction.amount)
+    }
+
+    // Accepts a block from a peer if it directly extends our tip, then any buffered
+    // orphans that now extend it in turn. A block we've already seen is ignored, so
+    // the same block gossiped by several peers is only processed and relayed once.
+    fn receive_block(&mut self, block: Block) -> Result<Received, BlockchainError> {
+        if self.seen_blocks.contains(&block.hash()) {
+            return Ok(Received::AlreadySeen)
+        }
+        let tip = &self.chain[self.chain.len() - 1];
+        if block.previous_hash != tip.hash() {
+            if block.index > tip.index + 1 {
+                return self.buffer_orphan(block)
+            }
+            let err = BlockchainError::DoesNotExtendTip { index: block.index };
+            self.record_side_block(block, err.to_string());
+            return Err(err)
+        }
+        self.accept_block(block)?;
+        self.connect_orphans();
+        Ok(Received::Accepted)
+    }
+
+    // Holds a block whose parent we haven't seen yet. Only blocks within the
+    // configured distance of our tip are kept, and once the pool is full the
+    // farthest-ahead block is evicted, the oldest first among equals.
+    fn buffer_orphan(&mut self, block: Block) -> Result<Received, BlockchainError> {
+        let tip = self.chain[self.chain.len() - 1].index;
+        if block.index > tip + self.config.orphan_max_ahead {
+            let err = BlockchainError::TooFarAhead { index: block.index, tip, max_ahead: self.config.orphan_max_ahead };
+            self.record_side_block(block, err.to_string());
+            return Err(err)
+        }
+        let hash = block.hash();
+        if self.orphans.iter().any(|orphan| orphan.hash() == hash) {
+            return Ok(Received::Buffered)
+        }
+        self.log(LogLevel::Debug, &format!("buffering orphan block {}", block.index));
+        self.orphans.push(block);
+        if self.orphans.len() > self.config.orphan_limit {
+            let farthest = self.orphans.iter().map(|orphan| orphan.index).max().unwrap_or(0);
+            if let Some(position) = self.orphans.iter().position(|orphan| orphan.index == farthest) {
+                let evicted = self.orphans.remove(position);
+                self.log(LogLevel::Debug, &format!("evicted orphan block {}", evicted.index));
+                self.record_side_block(evicted, "evicted from the full orphan pool".to_string());
+            }
+        }
+        Ok(Received::Buffered)
+    }
+
+    // Keeps the most recent off-chain blocks, recording each one once.
+    fn record_side_block(&mut self, block: Block, reason: String) {
+        let hash = block.hash();
+        if self.side_blocks.iter().any(|side| side.hash == hash) {
+            return
+        }
+        if self.side_blocks.len() >= SIDE_BLOCKS_CAPACITY {
+            self.side_blocks.pop_front();
+        }
+        self.side_blocks.push_back(OffChainBlock { hash, reason, block });
+    }
+
+    // Orphans still waiting for their parent come first, then the blocks that
+    // were turned away or dropped.
+    fn off_chain_blocks(&self) -> Vec<OffChainBlock> {
+        let waiting = self.orphans.iter().map(|orphan| OffChainBlock {
+            hash: orphan.hash(),
+            reason: format!("waiting for its parent {}", orphan.previous_hash),
+            block: orphan.clone()
+        });
+        waiting.chain(self.side_blocks.iter().cloned()).collect()
+    }
+
+    fn connect_orphans(&mut self) {
+        loop {
+            let tip = &self.chain[self.chain.len() - 1];
+            let (tip_index, tip_hash) = (tip.index, tip.hash());
+            self.orphans.retain(|orphan| orphan.index > tip_index);
+            let position = match self.orphans.iter().position(|orphan| orphan.previous_hash == tip_hash) {
+                Some(position) => position,
+                None => return
+            };
+            let orphan = self.orphans.remove(position);
+            let index = orphan.index;
+            if let Err(err) = self.accept_block(orphan.clone()) {
+                self.log(LogLevel::Info, &format!("dropped orphan block {}: {}", index, self.log_error(&err)));
+                self.record_side_block(orphan, err.to_string());
+            }
+        }
+    }
+
+    fn accept_block(&mut self, block: Block) -> Result<(), BlockchainError> {
+        let hash = block.hash();
+        let tip = &self.chain[self.chain.len() - 1];
+        check_block(&block, tip, &self.config)?;
+        check_median_time_past(&block, &self.chain, self.config.median_time_span)?;
+        check_reward(&block, self.minted(), &self.config)?;
+        Blockchain::check_transactions(
+            &block,
+            &mut Blockchain::balances(&self.chain),
+            &mut Blockchain::transaction_ids(&self.chain),
+            &mut Blockchain::unspent_outputs(&self.chain)
+        )?;
+        self.log(LogLevel::Info, &format!("block {} received with {} transactions", block.index, block.transactions.len()));
+        self.emit("block", &block);
+        let previous_chain = self.chain.clone();
+        self.chain.push(block);
+        self.index_from(self.chain.len() - 1);
+        self.seen_blocks.insert(hash);
+        self.revalidate_mempool(&previous_chain);
+        self.persist();
+        self.block_notifier.notify();
+        Ok(())
+    }
+
+    fn compact_block(&self, block: &Block) -> CompactBlock {
+        let prefilled = block.coinbase().into_iter().cloned().collect::<Vec<_>>();
+        CompactBlock {
+            origin: self.config.origin(),
+            index: block.index,
+            timestamp: block.timestamp.clone(),
+            proof: block.proof,
+            previous_hash: block.previous_hash.clone(),
+            difficulty: block.difficulty,
+            difficulty_bits: block.difficulty_bits,
+            merkle_root: block.merkle_root.clone(),
+            miner_key: block.miner_key.clone(),
+            signature: block.signature.clone(),
+            short_ids: block.transactions.iter().skip(prefilled.len()).map(Transaction::short_id).collect(),
+            prefilled
+        }
+    }
+
+    // Looks transactions up by short id in the mempool and then the chain, newest blocks first.
+    fn find_transactions(&self, short_ids: &[String]) -> Vec<Transaction> {
+        let known: Vec<&Transaction> = self.current_transactions.iter()
+            .chain(self.chain.iter().rev().flat_map(|block| block.transactions.iter()))
+            .collect();
+        short_ids.iter()
+            .filter_map(|short_id| known.iter().find(|transaction| transaction.short_id() == *short_id))
+            .map(|transaction| (*transaction).clone())
+            .collect()
+    }
+
+    fn reward_miner(&mut self) {
+        if let Some(coinbase) = self.coinbase_for(&self.config.node_identifier) {
+            self.current_transactions.insert(0, coinbase);
+        }
+    }
+
+    // The transactions of the next block: a coinbase paying `miner`, if there is
+    // still a reward, followed by the mempool.
+    fn next_transactions(&self, miner: &str) -> Vec<Transaction> {
+        self.coinbase_for(miner).into_iter().chain(self.current_transactions.iter().cloned()).collect()
+    }
+
+    // None once the supply cap is reached, as a coinbase can't pay nothing.
+    fn coinbase_for(&self, recipient: &str) -> Option<Transaction> {
+        let index = self.chain.len() + 1;
+        let reward = self.config.reward_at(index, self.minted());
+        if reward <= 0.0 {
+            return None
+        }
+        let mut coinbase = Transaction::new(COINBASE_SENDER, recipient, reward, 0.0);
+        // Gives each reward its own id so that its output can be told apart from earlier ones.
+        coinbase.nonce = Some(index as u64);
+        Some(coinbase)
+    }
+
+    fn minted(&self) -> f32 {
+        self.chain.iter().filter_map(Block::coinbase).fold(0.0, |minted, coinbase| minted + coinbase.amount)
+    }
+
+    fn reward_schedule(&self) -> RewardSchedule {
+        let minted = self.minted();
+        let schedule = self.config.schedule_at(self.chain.len() + 1);
+        RewardSchedule {
+            base_reward: schedule.base_reward,
+            halving_interval: schedule.halving_interval,
+            supply_cap: schedule.supply_cap,
+            minted,
+            next_reward: self.config.reward_at(self.chain.len() + 1, minted)
+        }
+    }
+
+    // A new schedule only applies from the next block on. The ones before it are
+    // kept, and saved with the chain, so earlier coinbases stay valid. A cap
+    // below what was already minted is refused.
+    fn update_reward_schedule(&mut self, update: &RewardScheduleUpdate) -> Result<RewardSchedule, String> {
+        if matches!(update.base_reward, Some(reward) if !reward.is_finite() || reward < 0.0) {
+            return Err("base_reward must be a non-negative number".to_string())
+        }
+        if update.halving_interval == Some(0) {
+            return Err("halving_interval must be at least 1".to_string())
+        }
+        let minted = self.minted();
+        if matches!(update.supply_cap, Some(cap) if !cap.is_finite() || cap < minted) {
+            return Err(format!("supply_cap must be at least the {} already minted", minted))
+        }
+        let from = self.chain.len() + 1;
+        let current = self.config.schedule_at(from);
+        let epoch = RewardEpoch {
+            from,
+            base_reward: update.base_reward.unwrap_or(current.base_reward),
+            halving_interval: update.halving_interval.or(current.halving_interval),
+            supply_cap: update.supply_cap.or(current.supply_cap)
+        };
+        self.config.reward_epochs.retain(|earlier| earlier.from < from);
+        self.config.reward_epochs.push(epoch);
+        self.persist();
+        self.log(LogLevel::Info, &format!("reward schedule updated from block {}", from));
+        Ok(self.reward_schedule())
+    }
+
+    // The path of the URL is kept, so a peer mounted under a base path is
+    // contacted there. Peers are always contacted over http, so the address is
+    // canonicalized for that: the host is lowercased, a port of 80 is dropped and
+    // the default port of any other scheme is spelled out. Equivalent spellings
+    // of one peer end up as a single entry. Addresses that aren't URLs with a
+    // host are not registered.
+    fn register_node(&mut self, node: &str) -> bool {
+        match canonical_node(node) {
+            Some(node) => self.nodes.insert(node),
+            None => false
+        }
+    }
+
+    // Searches upwards from `start`, giving up after the configured number of
+    // attempts so that a later search can pick up where this one stopped.
+    // The block mined with the proof pays our own coinbase, so that is what the
+    // proof is committed to along with the pending transactions.
+    fn proof_of_work(&self, last_block: &Block, start: usize) -> Result<usize, BlockchainError> {
+        self.lower_mining_priority();
+        let last_proof = last_block.proof;
+        let transactions = self.next_transactions(&self.config.node_identifier);
+        let merkle_root = Some(merkle_root(&transactions)).filter(|_| self.config.merkle_root);
+        let target = proof_target(&last_block.hash(), merkle_root.as_deref());
+        let mut proof = start;
+        let bits = self.next_bits();
+        while !verify_proof(last_proof, proof, &target, bits) {
+            proof = proof.checked_add(1).ok_or(BlockchainError::ProofSpaceExhausted { start })?;
+            if let Some(iterations) = self.config.max_pow_iterations.filter(|iterations| proof - start >= *iterations) {
+                return Err(BlockchainError::ProofNotFound { start, iterations })
+            }
+        }
+        Ok(proof)
+    }
+
+    // Mining runs on whichever worker thread serves the request, so that thread
+    // is the one given the configured niceness, the first time it mines. A
+    // thread can't raise its priority back, so this is never undone.
+    fn lower_mining_priority(&self) {
+        let niceness = match self.config.mining_niceness {
+            Some(niceness) if !MINING_NICENESS_SET.with(Cell::get) => niceness,
+            _ => return
+        };
+        MINING_NICENESS_SET.with(|set| set.set(true));
+        match set_thread_niceness(niceness) {
+            Ok(()) => self.log(LogLevel::Info, &format!("mining thread niceness set to {}", niceness)),
+            Err(err) => self.log(LogLevel::Info, &format!("mining niceness ignored: {}", err))
+        }
+    }
+
+    // A configured bit difficulty is used as is; mempool pressure only adjusts
+    // hex difficulties.
+    fn next_bits(&self) -> usize {
+        match self.config.difficulty_bits {
+            Some(bits) => bits,
+            None => self.next_difficulty() * 4
+        }
+    }
+
+    // The configured difficulty is the floor every block is validated against. In
+    // mempool mode the next block eases one step towards it while the mempool is
+    // full and tightens one step, up to the boost limit, while it is empty.
+    fn next_difficulty(&self) -> usize {
+        let floor = self.config.difficulty;
+        let previous = match self.chain.last() {
+            Some(block) if self.config.mempool_difficulty => block.difficulty.max(floor),
+            _ => return floor
+        };
+        let pending = self.pending_transfers();
+        if pending >= self.config.mempool_full {
+            previous.saturating_sub(1).max(floor)
+        } else if pending == 0 {
+            (previous + 1).min(floor + self.config.max_difficulty_boost)
+        } else {
+            previous
+        }
+    }
+
+    // Pending transactions other than a coinbase.
+    fn pending_transfers(&self) -> usize {
+        self.current_transactions.iter().filter(|transaction| transaction.sender != COINBASE_SENDER).count()
+    }
+
+    fn record_hashrate(&mut self, hashes: usize, elapsed: Duration) {
+        if self.hashrate_samples.len() >= HASHRATE_SAMPLES {
+            self.hashrate_samples.pop_front();
+        }
+        self.hashrate_samples.push_back((hashes, elapsed));
+    }
+
+    fn hashrate(&self) -> Option<f64> {
+        let hashes: usize = self.hashrate_samples.iter().map(|(hashes, _)| hashes).sum();
+        let seconds: f64 = self.hashrate_samples.iter().fold(0.0, |total, (_, elapsed)| total + elapsed.as_secs_f64());
+        if hashes == 0 || seconds <= 0.0 {
+            return None
+        }
+        Some(hashes as f64 / seconds)
+    }
+
+    // A proof at difficulty d needs 16^d hashes on average, so the difficulty for a
+    // target time is log16 of the hashes we can do in that time.
+    fn suggest_difficulty(&self, target_block_time: f64) -> Option<SuggestedDifficulty> {
+        let hashrate = self.hashrate()?;
+        let difficulty = (hashrate * target_block_time).log(16.0).round().max(1.0) as usize;
+        Some(SuggestedDifficulty {
+            target_block_time,
+            hashrate,
+            current_difficulty: self.next_difficulty(),
+            suggested_difficulty: difficulty
+        })
+    }
+
+    // Exercises mining, the mempool and chain validation on a throwaway chain at
+    // the lowest difficulty, leaving the node's own chain alone.
+    fn self_test() -> SelfTest {
+        let started = Instant::now();
+        let config = Config { difficulty: 1, node_identifier: "selftest".to_string(), log_level: LogLevel::Off, ..Config::default() };
+        let mut blockchain = Blockchain::new(config);
+        let mut checks = Vec::new();
+        checks.push(timed_check("mine blocks", || {
+            for _ in 0..SELF_TEST_BLOCKS {
+                blockchain.mine_throwaway()?;
+            }
+            Ok(())
+        }));
+        let transaction = Transaction::new("selftest", "selftest-recipient", 0.5, 0.0);
+        let id = transaction.id();
+        checks.push(timed_check("submit transaction", || {
+            blockchain.new_transaction(transaction).map(|_| ()).map_err(|err| err.to_string())
+        }));
+        checks.push(timed_check("confirm transaction", || {
+            blockchain.mine_throwaway()?;
+            let confirmed = blockchain.chain[blockchain.chain.len() - 1].transactions.iter().any(|transaction| transaction.id() == id);
+            if confirmed { Ok(()) } else { Err(format!("transaction {} is not in the mined block", id)) }
+        }));
+        checks.push(timed_check("validate chain", || {
+            if blockchain.valid_chain(&blockchain.chain) { Ok(()) } else { Err("the chain failed validation".to_string()) }
+        }));
+        SelfTest {
+            passed: checks.iter().all(|check| check.passed),
+            total_ms: started.elapsed().as_secs_f64() * 1000.0,
+            checks
+        }
+    }
+
+    fn mine_throwaway(&mut self) -> Result<(), String> {
+        let last_block = self.chain[self.chain.len() - 1].clone();
+        let proof = self.proof_of_work(&last_block, 0).map_err(|err| err.to_string())?;
+        self.reward_miner();
+        self.new_block(proof, &last_block.hash());
+        Ok(())
+    }
+
+    // Mines a throwaway block on a fresh genesis-only chain at each difficulty
+    // up to max_difficulty, returning how long each one took.
+    fn benchmark(max_difficulty: usize) -> Vec<(usize, Duration)> {
+        let mut timings = Vec::new();
+        for difficulty in 1..=max_difficulty {
+            let blockchain = Blockchain::new(Config { difficulty, log_level: LogLevel::Off, ..Config::default() });
+            let start = Instant::now();
+            let _ = blockchain.proof_of_work(&blockchain.chain[0], 0);
+            timings.push((difficulty, start.elapsed()));
+        }
+        timings
+    }
+
+    // Picks the first measured difficulty that reaches the target block time. If even the
+    // hardest level was faster, extrapolate at roughly 16x the work per extra hex digit.
+    fn recommend_difficulty(timings: &[(usize, Duration)], target_block_time: u64) -> usize {
+        let target = target_block_time as f64;
+        if let Some(&(difficulty, _)) = timings.iter().find(|(_, elapsed)| elapsed.as_secs_f64() >= target) {
+            return difficulty
+        }
+        match timings.last() {
+            Some(&(mut difficulty, elapsed)) => {
+                let mut estimate = elapsed.as_secs_f64().max(1e-6);
+                while estimate * 16.0 <= target {
+                    estimate *= 16.0;
+                    difficulty += 1;
+                }
+                difficulty
+            },
+            None => DEFAULT_DIFFICULTY
+        }
+    }
+
+    fn valid_chain(&self, chain: &[Block]) -> bool {
+        match self.check_peer_chain(chain) {
+            Ok(()) => true,
+            Err(err) => {
+                self.log(LogLevel::Info, &format!("invalid chain: {}", self.log_error(&err)));
+                false
+            }
+        }
+    }
+
+    // Fast sync: a peer chain must contain the assume-valid checkpoint, and the
+    // blocks up to it are only checked for linkage since the checkpoint hash
+    // commits to all of them. Everything after it is fully validated.
+    fn check_peer_chain(&self, chain: &[Block]) -> Result<(), BlockchainError> {
+        let checkpoint = match &self.config.assume_valid {
+            Some(checkpoint) => checkpoint,
+            None => return Blockchain::check_chain(chain, &self.config)
+        };
+        let trusted = chain.iter().position(|block| block.hash() == *checkpoint)
+            .ok_or_else(|| BlockchainError::MissingCheckpoint { hash: checkpoint.clone() })?;
+        self.log(LogLevel::Info, &format!("fast sync: assuming the {} blocks up to checkpoint {} are valid", trusted + 1, checkpoint));
+        Blockchain::check_chain_assuming(chain, &self.config, trusted + 1)
+    }
+
+    fn check_chain(chain: &[Block], config: &Config) -> Result<(), BlockchainError> {
+        Blockchain::check_chain_assuming(chain, config, 0)
+    }
+
+    // The first `trusted` blocks are only checked for linkage; their transactions
+    // are applied to the balances without being validated.
+    fn check_chain_assuming(chain: &[Block], config: &Config, trusted: usize) -> Result<(), BlockchainError> {
+        let mut prev_block = chain.first().ok_or(BlockchainError::EmptyChain)?;
+        let mut seen_hashes = HashSet::new();
+        seen_hashes.insert(prev_block.hash());
+        let mut balances = HashMap::new();
+        let mut seen_ids = HashSet::new();
+        let mut unspent = HashMap::new();
+        let mut minted = 0.0;
+        if trusted > 0 {
+            Blockchain::apply_block(prev_block, &mut balances, &mut seen_ids, &mut unspent);
+        } else {
+            Blockchain::check_transactions(prev_block, &mut balances, &mut seen_ids, &mut unspent)?;
+        }
+        for (position, block) in chain.iter().enumerate().skip(1) {
+            if position < trusted {
+                if block.index != prev_block.index + 1 || block.previous_hash != prev_block.hash() {
+                    return Err(BlockchainError::BrokenLink { index: block.index })
+                }
+                Blockchain::apply_block(block, &mut balances, &mut seen_ids, &mut unspent);
+                minted += block.coinbase().map_or(0.0, |coinbase| coinbase.amount);
+                prev_block = block;
+                continue
+            }
+            check_block(block, prev_block, config)?;
+            check_median_time_past(block, &chain[..position], config.median_time_span)?;
+            check_reward(block, minted, config)?;
+            minted += block.coinbase().map_or(0.0, |coinbase| coinbase.amount);
+            if !seen_hashes.insert(block.hash()) {
+                return Err(BlockchainError::DuplicateHash { index: block.index })
+            }
+            Blockchain::check_transactions(block, &mut balances, &mut seen_ids, &mut unspent)?;
+            prev_block = block;
+        }
+        Ok(())
+    }
+
+    // Coinbases are left out: a node's rewards are identical from block to block
+    // and are told apart by the block they open.
+    fn transaction_ids(chain: &[Block]) -> HashSet<String> {
+        chain.iter()
+            .flat_map(|block| block.transactions.iter())
+            .filter(|transaction| transaction.sender != COINBASE_SENDER)
+            .map(Transaction::id)
+            .collect()
+    }
+
+    fn apply_block(block: &Block, balances: &mut HashMap<String, f32>, seen_ids: &mut HashSet<String>,
+        unspent: &mut HashMap<OutPoint, TxOutput>) {
+        for transaction in &block.transactions {
+            Blockchain::apply_transaction(balances, transaction);
+            Blockchain::spend_outputs(unspent, transaction);
+        }
+        if let Some(coinbase) = block.coinbase() {
+            *balances.entry(coinbase.recipient.clone()).or_insert(0.0) += block.fees();
+        }
+        seen_ids.extend(Blockchain::transaction_ids(std::slice::from_ref(block)));
+    }
+
+    // A block may open with a single coinbase paying its miner, and the genesis
+    // block, which nobody mined, has none.
+    fn check_coinbase(block: &Block) -> Result<(), BlockchainError> {
+        let count = block.transactions.iter().filter(|transaction| transaction.sender == COINBASE_SENDER).count();
+        if count > 1 {
+            return Err(BlockchainError::MultipleCoinbases { index: block.index, count })
+        }
+        if count == 1 && block.coinbase().is_none() {
+            return Err(BlockchainError::MisplacedCoinbase { index: block.index })
+        }
+        if count == 1 && block.index == 1 {
+            return Err(BlockchainError::UnexpectedCoinbase { index: block.index })
+        }
+        Ok(())
+    }
+
+    // Replays a block's transactions on top of the balances before it, the same
+    // way balances() does, rejecting the block at the first invalid one or at a
+    // transaction that an earlier block already included.
+    fn check_transactions(block: &Block, balances: &mut HashMap<String, f32>, seen_ids: &mut HashSet<String>,
+        unspent: &mut HashMap<OutPoint, TxOutput>) -> Result<(), BlockchainError> {
+        Blockchain::check_coinbase(block)?;
+        for transaction in &block.transactions {
+            check_transaction(transaction)
+                .map_err(|err| BlockchainError::InvalidTransaction { index: block.index, reason: err.to_string() })?;
+            if transaction.sender != COINBASE_SENDER && !seen_ids.insert(transaction.id()) {
+                return Err(BlockchainError::ReplayedTransaction { index: block.index, id: transaction.short_id() })
+            }
+            if let Some(valid_until_height) = transaction.valid_until_height.filter(|_| transaction.expired_at(block.index)) {
+                let err = BlockchainError::ExpiredTransaction { index: block.index, valid_until_height };
+                return Err(BlockchainError::InvalidTransaction { index: block.index, reason: err.to_string() })
+            }
+            if Blockchain::overdraws(balances, transaction) {
+                return Err(BlockchainError::Overdraft { index: block.index, sender: transaction.sender.clone() })
+            }
+            Blockchain::check_inputs(unspent, transaction)
+                .map_err(|err| BlockchainError::InvalidTransaction { index: block.index, reason: err.to_string() })?;
+            Blockchain::apply_transaction(balances, transaction);
+            Blockchain::spend_outputs(unspent, transaction);
+        }
+        if let Some(coinbase) = block.coinbase() {
+            *balances.entry(coinbase.recipient.clone()).or_insert(0.0) += block.fees();
+        }
+        Ok(())
+    }
+
+    // Validates our own chain and enters safe mode if it is broken, so that no more
+    // blocks are mined on top of it until it is rolled back or resynced.
+    fn self_check(&mut self) {
+        self.corruption = Blockchain::check_chain(&self.chain, &self.config).err().map(|err| err.to_string());
+        if let Some(reason) = &self.corruption {
+            self.log(LogLevel::Info, &format!("chain failed its self-check, entering safe mode: {}", reason));
+        }
+    }
+
+    fn status(&self) -> Status {
+        Status {
+            node_identifier: self.config.node_identifier.clone(),
+            length: self.chain.len(),
+            pending_transactions: self.current_transactions.len(),
+            orphan_blocks: self.orphans.len(),
+            difficulty: self.next_difficulty(),
+            min_fee: self.config.min_fee,
+            min_fee_per_byte: self.config.min_fee_per_byte,
+            max_outputs: self.config.max_outputs,
+            dust_limit: self.config.dust_limit,
+            safe_mode: self.corruption.is_some(),
+            safe_mode_reason: self.corruption.clone(),
+            mining_paused: self.mining_paused,
+            maintenance: self.maintenance,
+            sync_state: self.sync_state()
+        }
+    }
+
+    // Safe mode counts as degraded whatever the last consensus run found.
+    fn sync_state(&self) -> SyncState {
+        match self.sync_state {
+            SyncState::Synced if self.corruption.is_some() => SyncState::Degraded,
+            state => state
+        }
+    }
+
+    // Maintenance turns away everything that would change the chain, the mempool
+    // or the peer list, but leaves reads and the admin endpoints alone.
+    fn accepts_writes(&self) -> Result<(), HttpResponse> {
+        if self.maintenance {
+            return Err(retry_later("The node is down for maintenance and only serves reads"))
+        }
+        Ok(())
+    }
+
+    fn accepts_transactions(&self) -> Result<(), HttpResponse> {
+        self.accepts_writes()?;
+        match self.sync_state() {
+            SyncState::Syncing => Err(retry_later("The node is still syncing and can't validate transactions yet")),
+            _ => Ok(())
+        }
+    }
+
+    fn accepts_mining(&self) -> Result<(), HttpResponse> {
+        self.accepts_writes()?;
+        match self.sync_state() {
+            SyncState::Synced => Ok(()),
+            state => Err(retry_later(&format!("Mining is unavailable while the node is {}", state.as_str())))
+        }
+    }
+
+    // Queries the peers chosen for this round and returns the node and chain that
+    // the configured consensus strategy would adopt, if any. Fails when not a
+    // single peer could be reached, which points at our own networking rather
+    // than at the peers. How each peer fared is added to `ratings` rather than
+    // applied, so that a dry run can leave reputations alone.
+    fn candidate_chain(&self, ratings: &mut Vec<(String, i64)>) -> Result<Option<(String, Vec<Block>)>, String> {
+        let client = reqwest::Client::builder().timeout(PEER_TIMEOUT).build()
+            .map_err(|err| format!("the HTTP client could not be created: {}", err))?;
+        // A fresh node near genesis has nothing worth keeping, so it adopts the
+        // first valid peer chain that is at least as good instead of a strictly better one.
+        let bootstrapping = !self.bootstrapped && self.chain.len() <= self.config.bootstrap_height;
+        let mut peer_chains = Vec::new();
+        let peers = self.consensus_peers();
+        let (supported, max_chain_response) = (self.config.schema_versions.clone(), self.config.max_chain_response);
+        let fetched = query_peers(peers.clone(), self.config.max_peer_requests, move |node| {
+            let (version, root) = negotiate_version(&client, &node, &supported)?;
+            Some(fetch_chain(&client, &format!("http://{}/chain?version={}", root, version), max_chain_response))
+        })?;
+        let mut unreachable = Vec::new();
+        for (node, res) in peers.iter().cloned().zip(fetched) {
+            let res = match res {
+                None => {
+                    self.log(LogLevel::Info, &format!("skipping {}: no common schema version", node));
+                    continue
+                },
+                Some(Ok(res)) => res,
+                Some(Err(err)) => {
+                    self.log(LogLevel::Info, &format!("failed to fetch the chain of {}: {}", node, err));
+                    ratings.push((node, -1));
+                    if let FetchError::Unreachable(err) = err {
+                        unreachable.push(err);
+                    }
+                    continue
+                }
+            };
+            // Only the blocks actually sent count, and a peer that claims a different
+            // length than it sends is not to be trusted.
+            let received = res.chain.len();
+            if res.length != received {
+                self.log(LogLevel::Info, &format!("rejecting the chain of {}: claims {} blocks but sent {}", node, res.length, received));
+                ratings.push((node, -1));
+                continue
+            }
+            if received < self.config.min_consensus_height {
+                self.log(LogLevel::Info, &format!("ignoring the {}-block chain of {}: below the minimum height of {}",
+                    received, node, self.config.min_consensus_height));
+                continue
+            }
+            peer_chains.push(PeerChain { node, chain: res.chain });
+        }
+        if !peers.is_empty() && unreachable.len() == peers.len() {
+            return Err(format!("none of the {} peers could be reached, last error: {}", peers.len(), unreachable.pop().unwrap()))
+        }
+        let ranked = consensus_strategy(self.config.consensus_strategy).rank(&self.chain, &peer_chains, bootstrapping);
+        let mut chosen = None;
+        let mut invalid = HashSet::new();
+        for position in ranked {
+            if self.valid_chain(&peer_chains[position].chain) {
+                chosen = Some(position);
+                break
+            }
+            invalid.insert(position);
+        }
+        for (position, peer) in peer_chains.iter().enumerate() {
+            ratings.push((peer.node.clone(), if invalid.contains(&position) { -1 } else { 1 }));
+        }
+        Ok(chosen.map(|position| {
+            let peer = peer_chains.swap_remove(position);
+            (peer.node, peer.chain)
+        }))
+    }
+
+    fn rate_peer(&mut self, node: &str, change: i64) {
+        let stats = self.peer_stats.entry(node.to_string()).or_default();
+        stats.reputation += change;
+        if change > 0 {
+            stats.last_seen = Some(Instant::now());
+        }
+    }
+
+    // Peers we know nothing about get a weight of one, so they still get picked
+    // now and then and can build up a reputation.
+    fn peer_weight(&self, node: &str) -> u64 {
+        let stats = self.peer_stats.get(node).cloned().unwrap_or_default();
+        let recent = matches!(stats.last_seen, Some(seen) if seen.elapsed() < RECENT_PEER_WINDOW);
+        1 + stats.reputation.max(0) as u64 + if recent { RECENT_PEER_BONUS } else { 0 }
+    }
+
+    // Every peer, or a random subset of the configured size drawn without
+    // replacement with reputable and recently seen peers more likely to be picked.
+    fn consensus_peers(&self) -> Vec<String> {
+        let mut remaining: Vec<(String, u64)> = self.nodes.iter().map(|node| (node.clone(), self.peer_weight(node))).collect();
+        let count = match self.config.consensus_peers {
+            Some(count) if count < remaining.len() => count,
+            _ => return remaining.into_iter().map(|(node, _)| node).collect()
+        };
+        let mut rng = rand::thread_rng();
+        let mut chosen = Vec::new();
+        while chosen.len() < count {
+            let total: u64 = remaining.iter().map(|(_, weight)| weight).sum();
+            let mut pick = rng.gen_range(0, total);
+            let position = remaining.iter()
+                .position(|(_, weight)| if pick < *weight { true } else { pick -= weight; false })
+                .unwrap();
+            chosen.push(remaining.swap_remove(position).0);
+        }
+        self.log(LogLevel::Debug, &format!("consensus round contacting {:?}", chosen));
+        chosen
+    }
+
+    fn resolve_dry_run(&self) -> ConsensusDryRun {
+        match self.candidate_chain(&mut Vec::new()) {
+            Ok(Some((node, chain))) => {
+                let common = self.chain.iter().zip(chain.iter())
+                    .take_while(|(local, peer)| local.hash() == peer.hash())
+                    .count();
+                ConsensusDryRun {
+                    would_replace: true,
+                    node: Some(node),
+                    local_length: self.chain.len(),
+                    peer_length: chain.len(),
+                    rolled_back: self.chain.len() - common,
+                    added: chain.len() - common,
+                    reason: "A peer has a better valid chain".to_string()
+                }
+            },
+            Ok(None) => ConsensusDryRun {
+                would_replace: false,
+                node: None,
+                local_length: self.chain.len(),
+                peer_length: self.chain.len(),
+                rolled_back: 0,
+                added: 0,
+                reason: "No peer has a better valid chain".to_string()
+            },
+            Err(err) => ConsensusDryRun {
+                would_replace: false,
+                node: None,
+                local_length: self.chain.len(),
+                peer_length: self.chain.len(),
+                rolled_back: 0,
+                added: 0,
+                reason: format!("Consensus could not run: {}", err)
+            }
+        }
+    }
+
+    // Runs within the configured interval of the previous run reuse its result
+    // instead of querying every peer again. A run that could not reach any peer
+    // is not reused, so the next request tries again.
+    fn resolve_conflicts(&mut self) -> ConsensusResult {
+        let interval = Duration::from_secs(self.config.consensus_interval);
+        if let Some((ran_at, result)) = &self.last_consensus {
+            if ran_at.elapsed() < interval {
+                return ConsensusResult { cached: true, ..result.clone() }
+            }
+        }
+        let previous_chain = self.chain.clone();
+        let mut ratings = Vec::new();
+        let candidate = self.candidate_chain(&mut ratings);
+        for (node, change) in ratings {
+            self.rate_peer(&node, change);
+        }
+        let candidate = match candidate {
+            Ok(candidate) => candidate,
+            Err(err) => {
+                self.log(LogLevel::Info, &format!("consensus could not run: {}", err));
+                if self.config.sync_on_start && self.sync_state == SyncState::Synced {
+                    self.sync_state = SyncState::Degraded;
+                }
+                return ConsensusResult {
+                    message: "Consensus could not run".to_string(),
+                    replaced: false,
+                    extended: false,
+                    length: self.chain.len(),
+                    cached: false,
+                    network_error: Some(err)
+                }
+            }
+        };
+        let changed = candidate.is_some();
+        let mut extended = false;
+        if let Some((_, mut chain)) = candidate {
+            // A chain that only adds blocks on top of ours is applied as those
+            // blocks, leaving the shared prefix we already hold untouched.
+            extended = Blockchain::extends(&chain, &self.chain);
+            if extended {
+                let added = chain.split_off(self.chain.len());
+                self.log(LogLevel::Info, &format!("consensus: extending our chain by {} blocks", added.len()));
+                let from = self.chain.len();
+                self.chain.extend(added);
+                self.index_from(from);
+            } else {
+                self.chain = chain;
+                self.hash_index = Blockchain::hash_index(&self.chain);
+                self.self_check();
+            }
+            self.bootstrapped = true;
+        }
+        if self.chain.len() > self.config.bootstrap_height {
+            self.bootstrapped = true;
+        }
+        if self.sync_state != SyncState::Synced {
+            self.log(LogLevel::Info, "consensus completed; the node is synced");
+            self.sync_state = SyncState::Synced;
+        }
+        self.revalidate_mempool(&previous_chain);
+        self.persist();
+        self.connect_orphans();
+        if changed {
+            let previous_hashes: HashSet<String> = previous_chain.iter().map(Block::hash).collect();
+            for block in self.chain.iter().filter(|block| !previous_hashes.contains(&block.hash())) {
+                self.emit("block", block);
+            }
+            self.block_notifier.notify();
+        }
+        let message = if extended {
+            "Our chain was extended"
+        } else if changed {
+            "Our chain was replaced"
+        } else {
+            "Our chain is authoritative"
+        };
+        let result = ConsensusResult {
+            message: message.to_string(),
+            replaced: changed && !extended,
+            extended,
+            length: self.chain.len(),
+            cached: false,
+            network_error: None
+        };
+        self.last_consensus = Some((Instant::now(), result.clone()));
+        result
+    }
+
+    fn extends(chain: &[Block], prefix: &[Block]) -> bool {
+        chain.len() > prefix.len() && chain.iter().zip(prefix).all(|(block, ours)| block.hash() == ours.hash())
+    }
+
+    // Removes the top blocks, returning their non-coinbase transactions to the front
+    // of the mempool. The genesis block can never be rolled back.
+    fn rollback(&mut self, blocks: usize) -> Option<RollbackResult> {
+        if blocks >= self.chain.len() {
+            return None
+        }
+        let removed = self.chain.split_off(self.chain.len() - blocks);
+        for block in &removed {
+            self.hash_index.remove(&block.hash());
+        }
+        let mut restored: Vec<Transaction> = removed.iter()
+            .flat_map(|block| block.transactions.iter())
+            .filter(|transaction| transaction.sender != COINBASE_SENDER)
+            .cloned()
+            .collect();
+        let restored_count = restored.len();
+        restored.append(&mut self.current_transactions);
+        self.current_transactions = restored;
+        let chain = self.chain.clone();
+        self.revalidate_mempool(&chain);
+        self.self_check();
+        self.persist();
+        let tip = &self.chain[self.chain.len() - 1];
+        Some(RollbackResult {
+            removed: removed.len(),
+            restored_transactions: restored_count,
+            pending_transactions: self.current_transactions.len(),
+            tip_index: tip.index,
+            tip_hash: tip.hash()
+        })
+    }
+
+    fn balances(chain: &[Block]) -> HashMap<String, f32> {
+        let mut balances = HashMap::new();
+        for block in chain {
+            for transaction in &block.transactions {
+                Blockchain::apply_transaction(&mut balances, transaction);
+            }
+            // Fees are not part of the coinbase amount; they go to whoever mined the block.
+            if let Some(coinbase) = block.coinbase() {
+                *balances.entry(coinbase.recipient.clone()).or_insert(0.0) += block.fees();
+            }
+        }
+        balances
+    }
+
+    fn apply_transaction(balances: &mut HashMap<String, f32>, transaction: &Transaction) {
+        if transaction.sender != COINBASE_SENDER {
+            *balances.entry(transaction.sender.clone()).or_insert(0.0) -= transaction.amount + transaction.fee;
+        }
+        if transaction.recipient != BURN_ADDRESS {
+            *balances.entry(transaction.recipient.clone()).or_insert(0.0) += transaction.amount;
+        }
+    }
+
+    fn overdraws(balances: &HashMap<String, f32>, transaction: &Transaction) -> bool {
+        transaction.sender != COINBASE_SENDER
+            && balances.get(&transaction.sender).cloned().unwrap_or(0.0) < transaction.amount + transaction.fee
+    }
+
+    fn unspent_outputs(chain: &[Block]) -> HashMap<OutPoint, TxOutput> {
+        let mut unspent = HashMap::new();
+        for transaction in chain.iter().flat_map(|block| block.transactions.iter()) {
+            Blockchain::spend_outputs(&mut unspent, transaction);
+        }
+        unspent
+    }
+
+    // Every transaction pays its amount to output 0, except a burn, which leaves
+    // output 0 out as nobody could spend it. One that spends inputs returns what
+    // they hold beyond the amount and fee to its sender as output 1. Block fees
+    // are credited to the miner's balance but never become an output.
+    fn spend_outputs(unspent: &mut HashMap<OutPoint, TxOutput>, transaction: &Transaction) {
+        let total = transaction.inputs.iter()
+            .filter_map(|input| unspent.remove(input))
+            .fold(0.0, |total, output| total + output.amount);
+        let id = transaction.id();
+        if transaction.recipient != BURN_ADDRESS {
+            let paid = TxOutput { recipient: transaction.recipient.clone(), amount: transaction.amount };
+            unspent.insert(OutPoint { transaction: id.clone(), output: 0 }, paid);
+        }
+        let change = total - transaction.amount - transaction.fee;
+        if !transaction.inputs.is_empty() && change > 0.0 {
+            let returned = TxOutput { recipient: transaction.sender.clone(), amount: change };
+            unspent.insert(OutPoint { transaction: id, output: 1 }, returned);
+        }
+    }
+
+    // Returns the value of the inputs, which is what the transaction's amount, fee
+    // and change add up to. One without inputs spends exactly its amount and fee.
+    fn check_inputs(unspent: &HashMap<OutPoint, TxOutput>, transaction: &Transaction) -> Result<f32, BlockchainError> {
+        if transaction.inputs.is_empty() {
+            return Ok(transaction.amount + transaction.fee)
+        }
+        let mut total = 0.0;
+        for input in &transaction.inputs {
+            let output = unspent.get(input)
+                .ok_or_else(|| BlockchainError::UnknownOutput { transaction: input.transaction.clone(), output: input.output })?;
+            if output.recipient != transaction.sender {
+                return Err(BlockchainError::ForeignOutput {
+                    transaction: input.transaction.clone(),
+                    output: input.output,
+                    sender: transaction.sender.clone()
+                })
+            }
+            total += output.amount;
+        }
+        let required = transaction.amount + transaction.fee;
+        if total < required {
+            return Err(BlockchainError::InsufficientInputs { total, required })
+        }
+        Ok(total)
+    }
+
+    // Addresses that never appear on the chain have a balance of zero.
+    fn balances_of(&self, addresses: &[String]) -> HashMap<String, f32> {
+        let balances = Blockchain::balances(&self.chain);
+        addresses.iter()
+            .map(|address| {
+                let normalized: String = address.nfc().collect();
+                (address.clone(), balances.get(&normalized).cloned().unwrap_or(0.0))
+            })
+            .collect()
+    }
+
+    fn unspent_outputs_of(&self, address: &str) -> UnspentOutputs {
+        let mut outputs: Vec<UnspentOutput> = Blockchain::unspent_outputs(&self.chain).into_iter()
+            .filter(|(_, output)| output.recipient == address)
+            .map(|(point, output)| UnspentOutput { transaction: point.transaction, output: point.output, amount: output.amount })
+            .collect();
+        outputs.sort_by(|a, b| a.transaction.cmp(&b.transaction).then(a.output.cmp(&b.output)));
+        UnspentOutputs {
+            address: address.to_string(),
+            total: outputs.iter().fold(0.0, |total, output| total + output.amount),
+            outputs
+        }
+    }
+
+    // Mirrors verify_block, check_reward and check_transactions. Every coinbase
+    // must pay exactly the reward the schedule gives for its height.
+    fn consensus_rules(&self) -> ConsensusRules {
+        ConsensusRules {
+            chain_id: self.config.chain_id.clone(),
+            hash_algorithm: "sha256".to_string(),
+            proof_of_work: "sha256(previous proof + proof + previous hash, followed by the merkle root if the block records one) starts with as many zero bits as the block's difficulty".to_string(),
+            min_difficulty_bits: self.config.required_bits(),
+            max_block_transactions: None,
+            max_block_bytes: None,
+            reward_schedule: self.reward_schedule(),
+            max_coinbases_per_block: 1,
+            coinbase_maturity: 0,
+            timestamp_tolerance: None,
+            median_time_span: self.config.median_time_span,
+            max_address_length: MAX_ADDRESS_LENGTH,
+            max_category_length: MAX_CATEGORY_LENGTH
+        }
+    }
+
+    // Blocks are assembled from the whole mempool in arrival order, behind the
+    // mining reward, so there is no per-block transaction limit to report.
+    fn mempool_policy(&self) -> MempoolPolicy {
+        MempoolPolicy {
+            ordering: "arrival".to_string(),
+            max_transactions_per_block: None,
+            max_block_bytes: None,
+            transaction_ttl: None,
+            min_fee: self.config.min_fee,
+            min_fee_per_byte: self.config.min_fee_per_byte,
+            dust_limit: self.config.dust_limit,
+            max_outputs: self.config.max_outputs,
+            allow_burns: self.config.allow_burns,
+            transaction_work_bits: self.config.transaction_work_bits
+        }
+    }
+
+    // Only applies to transactions submitted from now on; the ones already
+    // pending were accepted under the old policy and are kept.
+    fn update_policy(&mut self, update: &PolicyUpdate) -> Result<MempoolPolicy, String> {
+        let amounts = [("min_fee", update.min_fee), ("min_fee_per_byte", update.min_fee_per_byte), ("dust_limit", update.dust_limit)];
+        if let Some((name, value)) = amounts.iter().find(|(_, value)| matches!(value, Some(value) if !value.is_finite() || *value < 0.0)) {
+            return Err(format!("{} must be a non-negative number, got {}", name, value.unwrap()))
+        }
+        if update.max_outputs == Some(0) {
+            return Err("max_outputs must be at least 1".to_string())
+        }
+        if matches!(update.transaction_work_bits, Some(bits) if bits > 256) {
+            return Err("transaction_work_bits can be at most 256".to_string())
+        }
+        self.config.min_fee = update.min_fee.unwrap_or(self.config.min_fee);
+        self.config.min_fee_per_byte = update.min_fee_per_byte.unwrap_or(self.config.min_fee_per_byte);
+        self.config.dust_limit = update.dust_limit.unwrap_or(self.config.dust_limit);
+        self.config.max_outputs = update.max_outputs.unwrap_or(self.config.max_outputs);
+        self.config.allow_burns = update.allow_burns.unwrap_or(self.config.allow_burns);
+        self.config.transaction_work_bits = update.transaction_work_bits.unwrap_or(self.config.transaction_work_bits);
+        self.log(LogLevel::Info, "mempool policy updated");
+        Ok(self.mempool_policy())
+    }
+
+    // Suggests the median fee rate of the mempool, or the floor when the mempool is empty.
+    fn fee_estimate(&self) -> FeeEstimate {
+        let mut rates: Vec<f32> = self.current_transactions.iter()
+            .filter(|transaction| transaction.sender != COINBASE_SENDER)
+            .map(|transaction| transaction.fee_per_byte())
+            .collect();
+        rates.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        let median = rates.get(rates.len() / 2).cloned().unwrap_or(0.0);
+        FeeEstimate {
+            min_fee: self.config.min_fee,
+            min_fee_per_byte: self.config.min_fee_per_byte,
+            suggested_fee_per_byte: median.max(self.config.min_fee_per_byte)
+        }
+    }
+
+    // After the chain has been extended or replaced, drop any pending transaction that
+    // was confirmed by one of the new blocks or that can no longer be afforded.
+    fn revalidate_mempool(&mut self, previous_chain: &[Block]) {
+        let previous_hashes: HashSet<String> = previous_chain.iter().map(|block| block.hash()).collect();
+        let mut confirmed: Vec<&Transaction> = self.chain.iter()
+            .filter(|block| !previous_hashes.contains(&block.hash()))
+            .flat_map(|block| block.transactions.iter())
+            .collect();
+        let mut balances = Blockchain::balances(&self.chain);
+        let mut unspent = Blockchain::unspent_outputs(&self.chain);
+        let mut kept = Vec::new();
+        let mut removed = Vec::new();
+        for transaction in self.current_transactions.drain(..) {
+            if let Some(position) = confirmed.iter().position(|other| **other == transaction) {
+                confirmed.remove(position);
+                removed.push((transaction, "confirmed in a new block"));
+            } else if transaction.expired_at(self.chain.len() + 1) {
+                removed.push((transaction, "expired before it was mined"));
+            } else if Blockchain::overdraws(&balances, &transaction) {
+                removed.push((transaction, "sender balance is insufficient"));
+            } else if Blockchain::check_inputs(&unspent, &transaction).is_err() {
+                removed.push((transaction, "inputs were already spent"));
+            } else {
+                Blockchain::apply_transaction(&mut balances, &transaction);
+                Blockchain::spend_outputs(&mut unspent, &transaction);
+                kept.push(transaction);
+            }
+        }
+        self.current_transactions = kept;
+        for (transaction, reason) in &removed {
+            self.log(LogLevel::Info, &format!("dropped pending transaction: {}", reason));
+            self.log_transaction("dropped transaction", transaction);
+        }
+    }
+
+    fn full_chain(&self) -> FullChain {
+        FullChain {
+            chain: self.chain.clone(),
+            length: self.chain.len()
+        }
+    }
+
+    // Every supported schema version currently shares the block encoding, so
+    // there is nothing to translate yet; new versions convert blocks here.
+    fn versioned_chain(&self, version: u32) -> Option<FullChain> {
+        if !self.config.schema_versions.contains(&version) {
+            return None
+        }
+        Some(self.full_chain())
+    }
+
+    fn blocks_mined_by(&self, address: &str) -> Vec<MinedBlock> {
+        self.chain.iter()
+            .filter(|block| matches!(block.coinbase(), Some(coinbase) if coinbase.recipient == address))
+            .map(|block| MinedBlock { index: block.index, hash: block.hash() })
+            .collect()
+    }
+
+    // Fees are recomputed from each mined block's transactions, the same way
+    // balances() credits them to the coinbase recipient.
+    fn earnings_of(&self, address: &str) -> MinerEarnings {
+        let (blocks, rewards, fees) = self.chain.iter()
+            .filter_map(|block| block.coinbase().filter(|coinbase| coinbase.recipient == address).map(|coinbase| (block, coinbase)))
+            .fold((0, 0.0, 0.0), |(blocks, rewards, fees), (block, coinbase)| (blocks + 1, rewards + coinbase.amount, fees + block.fees()));
+        MinerEarnings {
+            address: address.to_string(),
+            blocks,
+            rewards,
+            fees,
+            total: rewards + fees
+        }
+    }
+
+    fn accumulated_work(chain: &[Block]) -> u128 {
+        chain.iter().fold(0u128, |work, block| work.saturating_add(block.work()))
+    }
+
+    fn chain_work(&self) -> ChainWork {
+        let tip = &self.chain[self.chain.len() - 1];
+        ChainWork {
+            work: Blockchain::accumulated_work(&self.chain),
+            length: self.chain.len(),
+            tip_index: tip.index,
+            tip_hash: tip.hash()
+        }
+    }
+
+    fn confirmed_in(&self, id: &str) -> Option<usize> {
+        self.chain.iter()
+            .find(|block| block.transactions.iter().any(|transaction| transaction.id() == id))
+            .map(|block| block.index)
+    }
+
+    fn genesis(&self) -> Genesis {
+        let block = self.chain[0].clone();
+        Genesis {
+            chain_id: self.config.chain_id.clone(),
+            hash: block.hash(),
+            allocations: block.transactions.clone(),
+            block
+        }
+    }
+
+    fn debug_state(&self) -> DebugState {
+        let tip = &self.chain[self.chain.len() - 1];
+        let mut peers: Vec<PeerState> = self.nodes.iter()
+            .map(|node| {
+                let stats = self.peer_stats.get(node).cloned().unwrap_or_default();
+                PeerState {
+                    node: node.clone(),
+                    reputation: stats.reputation,
+                    seconds_since_seen: stats.last_seen.map(|seen| seen.elapsed().as_secs())
+                }
+            })
+            .collect();
+        peers.sort_by(|a, b| a.node.cmp(&b.node));
+        DebugState {
+            length: self.chain.len(),
+            tip_index: tip.index,
+            tip_hash: tip.hash(),
+            pending_transactions: self.current_transactions.clone(),
+            peers,
+            config: Config { admin_key: self.config.admin_key.as_ref().map(|_| REDACTED.to_string()), ..self.config.clone() },
+            safe_mode_reason: self.corruption.clone(),
+            seconds_since_consensus: self.last_consensus.as_ref().map(|(ran_at, _)| ran_at.elapsed().as_secs()),
+            orphan_blocks: self.orphans.len(),
+            seen_blocks: self.seen_blocks.order.len(),
+            hashrate: self.hashrate()
+        }
+    }
+
+    fn chain_info(&self) -> ChainInfo {
+        let tip = &self.chain[self.chain.len() - 1];
+        ChainInfo {
+            chain_id: self.config.chain_id.clone(),
+            height: self.chain.len(),
+            tip_hash: tip.hash(),
+            tip_timestamp: tip.timestamp.clone(),
+            difficulty: self.next_difficulty(),
+            accumulated_work: Blockchain::accumulated_work(&self.chain),
+            genesis_hash: self.chain[0].hash()
+        }
+    }
+
+    // Every coinbase pays the id of the node that forged the block, so summing them
+    // attributes each minted coin to the node that created it. Burned coins count
+    // as minted but are no longer part of the supply.
+    fn audit(&self) -> Audit {
+        let mut minted_by_node = HashMap::new();
+        for coinbase in self.chain.iter().filter_map(|block| block.coinbase()) {
+            *minted_by_node.entry(coinbase.recipient.clone()).or_insert(0.0) += coinbase.amount;
+        }
+        let total_minted = minted_by_node.values().fold(0.0, |total, minted| total + minted);
+        let total_burned = Blockchain::burned(&self.chain);
+        Audit {
+            total_supply: total_minted - total_burned,
+            total_minted,
+            total_burned,
+            minted_by_node
+        }
+    }
+
+    fn burned(chain: &[Block]) -> f32 {
+        chain.iter()
+            .flat_map(|block| block.transactions.iter())
+            .filter(|transaction| transaction.recipient == BURN_ADDRESS)
+            .fold(0.0, |burned, transaction| burned + transaction.amount)
+    }
+
+    // Replays the chain a block at a time, checking after each one that nobody
+    // is below zero and that the balances add up to everything minted so far,
+    // less what was burned. Fees of blocks without a coinbase are credited to no
+    // one and leave the supply too.
+    fn verify_conservation(&self) -> Conservation {
+        let (mut balances, mut seen_ids, mut unspent) = (HashMap::new(), HashSet::new(), HashMap::new());
+        let (mut minted, mut burned, mut unclaimed): (f32, f32, f32) = (0.0, 0.0, 0.0);
+        let mut violation = None;
+        let mut blocks_checked = 0;
+        for block in &self.chain {
+            Blockchain::apply_block(block, &mut balances, &mut seen_ids, &mut unspent);
+            blocks_checked += 1;
+            minted += block.transactions.iter()
+                .filter(|transaction| transaction.sender == COINBASE_SENDER)
+                .fold(0.0, |minted, transaction| minted + transaction.amount);
+            burned += Blockchain::burned(std::slice::from_ref(block));
+            if block.coinbase().is_none() {
+                unclaimed += block.fees();
+            }
+            let expected = minted - burned - unclaimed;
+            let total = balances.values().fold(0.0, |total, balance| total + balance);
+            let tolerance = 1e-3 * minted.max(1.0);
+            if let Some((address, balance)) = balances.iter().find(|(_, balance)| **balance < -tolerance) {
+                violation = Some(Violation { index: block.index, reason: format!("{} goes negative at {}", address, balance) });
+            } else if (total - expected).abs() > tolerance {
+                violation = Some(Violation {
+                    index: block.index,
+                    reason: format!("balances add up to {} but {} was minted and not burned", total, expected)
+                });
+            }
+            if violation.is_some() {
+                break
+            }
+        }
+        Conservation {
+            conserved: violation.is_none(),
+            blocks_checked,
+            total_minted: minted,
+            total_burned: burned,
+            total_balances: balances.values().fold(0.0, |total, balance| total + balance),
+            violation
+        }
+    }
+
+    // An empty page hands the same cursor back, so a client that has caught up
+    // can keep polling with it. A cursor whose block was replaced by a
+    // reorganization is refused rather than silently resumed on the new branch.
+    fn blocks_after(&self, query: &CursorQuery) -> Result<BlockCursorPage, BlockchainError> {
+        let start = match &query.cursor {
+            Some(cursor) => {
+                let invalid = || BlockchainError::InvalidCursor { cursor: cursor.clone() };
+                let mut parts = cursor.splitn(2, ':');
+                let index: usize = parts.next().and_then(|index| index.parse().ok()).ok_or_else(invalid)?;
+                let hash = parts.next().ok_or_else(invalid)?;
+                if self.block(index).map(Block::hash).as_deref() != Some(hash) {
+                    return Err(BlockchainError::StaleCursor { cursor: cursor.clone() })
+                }
+                index
+            }
+            None => 0
+        };
+        let limit = query.limit.unwrap_or(DEFAULT_PAGE_LIMIT).max(1);
+        let blocks: Vec<Block> = self.chain.iter().skip(start).take(limit).cloned().collect();
+        let next_cursor = match blocks.last() {
+            Some(block) => format!("{}:{}", block.index, block.hash()),
+            None => query.cursor.clone().unwrap_or_default()
+        };
+        Ok(BlockCursorPage { blocks, next_cursor })
+    }
+
+    fn tail(&self, n: usize) -> Page<Block> {
+        let query = PageQuery { offset: Some(self.chain.len().saturating_sub(n)), limit: Some(n) };
+        Page::new(&self.chain, &query)
+    }
+
+    fn block(&self, index: usize) -> Option<&Block> {
+        index.checked_sub(1).and_then(|position| self.chain.get(position))
+    }
+
+    // The index is updated wherever the chain changes, so a miss means the hash
+    // isn't in the chain.
+    fn position_of_hash(&self, hash: &str) -> Option<usize> {
+        self.hash_index.get(hash).cloned()
+    }
+
+    // Indexes the blocks from `position` on, after they were appended.
+    fn index_from(&mut self, position: usize) {
+        for (offset, block) in self.chain[position..].iter().enumerate() {
+            self.hash_index.insert(block.hash(), position + offset);
+        }
+    }
+
+    fn hash_index(chain: &[Block]) -> HashMap<String, usize> {
+        chain.iter().enumerate().map(|(position, block)| (block.hash(), position)).collect()
+    }
+
+    // Throws away everything derived from the chain and works it out again: the
+    // hash index and whether the chain is sound enough to leave safe mode.
+    // Entries of the old index that were wrong or missing are counted as stale.
+    fn reindex(&mut self) -> Reindex {
+        let previous = std::mem::replace(&mut self.hash_index, Blockchain::hash_index(&self.chain));
+        let changed = self.hash_index.iter().filter(|(hash, position)| previous.get(*hash) != Some(position)).count();
+        let removed = previous.keys().filter(|hash| !self.hash_index.contains_key(*hash)).count();
+        self.self_check();
+        self.log(LogLevel::Info, &format!("reindexed {} blocks, {} stale entries", self.hash_index.len(), changed + removed));
+        Reindex {
+            hash_index_entries: self.hash_index.len(),
+            stale_entries: changed + removed,
+            safe_mode: self.corruption.is_some()
+        }
+    }
+
+    // The last ten blocks one by one, then every second, fourth and so on,
+    // always ending with genesis: about ten plus log2 of the length in all.
+    fn locator(&self) -> BlockLocator {
+        let mut hashes = Vec::new();
+        let (mut position, mut step) = (self.chain.len() - 1, 1);
+        while position > 0 {
+            hashes.push(self.chain[position].hash());
+            if hashes.len() >= 10 {
+                step *= 2;
+            }
+            position = position.saturating_sub(step);
+        }
+        hashes.push(self.chain[0].hash());
+        BlockLocator { hashes }
+    }
+
+    // The locator lists the peer's blocks from the tip down, so the first hash
+    // we also have is the highest block the two chains have in common.
+    fn fork_point(&self, locator: &BlockLocator) -> Option<ForkPoint> {
+        let position = locator.hashes.iter().find_map(|hash| self.position_of_hash(hash))?;
+        let block = &self.chain[position];
+        let hash = block.hash();
+        Some(ForkPoint {
+            index: block.index,
+            cursor: format!("{}:{}", block.index, hash),
+            hash
+        })
+    }
+
+    fn chain_at_hash(&self, hash: &str) -> Option<FullChain> {
+        let position = self.position_of_hash(hash)?;
+        Some(FullChain {
+            chain: self.chain[..=position].to_vec(),
+            length: position + 1
+        })
+    }
+
+    // Coinbase rewards are minted rather than transferred, so they are not counted
+    // towards the total transferred.
+    fn window_stats(&self, from: DateTime<Utc>, to: DateTime<Utc>) -> WindowStats {
+        let blocks: Vec<(&Block, DateTime<Utc>)> = self.chain.iter()
+            .filter_map(|block| block.time().map(|time| (block, time)))
+            .filter(|(_, time)| *time >= from && *time <= to)
+            .collect();
+        let transfers = blocks.iter()
+            .flat_map(|(block, _)| block.transactions.iter())
+            .filter(|transaction| transaction.sender != COINBASE_SENDER);
+        let average_block_interval = match (blocks.first(), blocks.last()) {
+            (Some((_, first)), Some((_, last))) if blocks.len() > 1 =>
+                (*last - *first).num_milliseconds() as f64 / 1000.0 / (blocks.len() - 1) as f64,
+            _ => 0.0
+        };
+        WindowStats {
+            blocks: blocks.len(),
+            transactions: blocks.iter().map(|(block, _)| block.transactions.len()).sum(),
+            total_transferred: transfers.fold(0.0, |total, transaction| total + transaction.amount),
+            average_block_interval
+        }
+    }
+
+    // Block timestamps are not required to increase along the chain, so every
+    // block is checked rather than binary searching for the ends of the range.
+    fn blocks_between(&self, from: DateTime<Utc>, to: DateTime<Utc>) -> Vec<Block> {
+        self.chain.iter()
+            .filter(|block| matches!(block.time(), Some(time) if time >= from && time <= to))
+            .cloned()
+            .collect()
+    }
+
+    fn transactions_by_category(&self, category: &str) -> Vec<Transaction> {
+        self.chain.iter()
+            .flat_map(|block| block.transactions.iter())
+            .filter(|transaction| transaction.category.as_deref() == Some(category))
+            .cloned()
+            .collect()
+    }
+
+    fn block_sizes(&self) -> Vec<BlockSize> {
+        self.chain.iter()
+            .map(|block| BlockSize {
+                index: block.index,
+                timestamp: block.timestamp.clone(),
+                transaction_count: block.transactions.len(),
+                byte_size: block.byte_size()
+            })
+            .collect()
+    }
+
+    fn difficulty_history(&self) -> Vec<DifficultyPoint> {
+        self.chain.iter()
+            .map(|block| DifficultyPoint {
+                index: block.index,
+                timestamp: block.timestamp.clone(),
+                difficulty: block.difficulty,
+                bits: block.bits()
+            })
+            .collect()
+    }
+
+    fn node_list(&self) -> NodeList {
+        let mut node_list = Vec::new();
+        for node in self.nodes.iter() {
+            node_list.push(node.to_string());
+        }
+        NodeList {
+            nodes: node_list
+        }
+    }
+}
+
+// The lock is held for the whole proof search, so concurrent /mine requests run
+// one after another and each mines on the tip the previous one left. Don't
+// release it while searching without rechecking the tip before new_block.
+#[get("/mine")]
+fn mine(blockchain: web::Data<Mutex<Blockchain>>, query: web::Query<MineQuery>) -> HttpResponse {
+    let mut local_blockchain = blockchain.lock().unwrap();
+    if let Some(reason) = &local_blockchain.corruption {
+        return HttpResponse::ServiceUnavailable().json(Response {
+            message: format!("Mining is disabled because the chain is corrupt: {}", reason)
+        })
+    }
+    if local_blockchain.mining_paused {
+        return HttpResponse::ServiceUnavailable().json(Response {
+            message: "Mining is paused; resume it with /mine/resume".to_string()
+        })
+    }
+    if let Err(response) = local_blockchain.accepts_mining() {
+        return response
+    }
+    let (pending, required) = (local_blockchain.pending_transfers(), local_blockchain.config.min_block_transactions);
+    if pending < required {
+        return HttpResponse::ServiceUnavailable().json(Response {
+            message: format!("Waiting for transactions: {} pending but a block needs at least {}", pending, required)
+        })
+    }
+    if let Some(last_block) = local_blockchain.chain.last() {
+        let started = Instant::now();
+        let start = query.start.unwrap_or(0);
+        let proof = match local_blockchain.proof_of_work(last_block, start) {
+            Ok(proof) => proof,
+            Err(err @ BlockchainError::ProofSpaceExhausted { .. }) => return HttpResponse::BadRequest().json(Response {
+                message: err.to_string()
+            }),
+            Err(err) => return HttpResponse::ServiceUnavailable().json(Response {
+                message: err.to_string()
+            })
+        };
+        let previous_hash = last_block.hash();
+        // The search starts at `start`, so a proof of p took p - start + 1 hashes to find.
+        local_blockchain.record_hashrate((proof - start).saturating_add(1), started.elapsed());
+        local_blockchain.reward_miner();
+        let block = local_blockchain.new_block(proof, &previous_hash).clone();
+        let compact = local_blockchain.compact_block(&block);
+        let peers = local_blockchain.node_list().nodes;
+        let logger = local_blockchain.logger();
+        // Peers call back for the transactions they lack while handling the
+        // announcement, so it can't block this request.
+        thread::spawn(move || broadcast_block(&peers, &compact, logger));
+        return HttpResponse::Ok().json(Mine {
+            message: "New block forged".to_string(),
+            index: block.index,
+            transactions: block.transactions,
+            proof: proof,
+            previous_hash: previous_hash
+        })
+    }
+    HttpResponse::InternalServerError().json(Response {
+        message: "There was an error mining".to_string()
+    })
+}
+
+#[post("/mine/pause")]
+fn pause_mining(blockchain: web::Data<Mutex<Blockchain>>, req: HttpRequest) -> HttpResponse {
+    set_mining_paused(&blockchain, &req, true)
+}
+
+#[post("/mine/resume")]
+fn resume_mining(blockchain: web::Data<Mutex<Blockchain>>, req: HttpRequest) -> HttpResponse {
+    set_mining_paused(&blockchain, &req, false)
+}
+
+fn set_mining_paused(blockchain: &Mutex<Blockchain>, req: &HttpRequest, paused: bool) -> HttpResponse {
+    let mut local_blockchain = blockchain.lock().unwrap();
+    if let Err(response) = local_blockchain.config.authorize_admin(req) {
+        return response
+    }
+    local_blockchain.mining_paused = paused;
+    local_blockchain.log(LogLevel::Info, if paused { "mining paused" } else { "mining resumed" });
+    HttpResponse::Ok().json(Response {
+        message: if paused { "Mining paused" } else { "Mining resumed" }.to_string()
+    })
+}
+
+#[get("/mine/preview")]
+fn mine_preview(blockchain: web::Data<Mutex<Blockchain>>, query: web::Query<PreviewQuery>) -> HttpResponse {
+    HttpResponse::Ok().json(blockchain.lock().unwrap().block_template(query.miner.as_ref().map(String::as_str)))
+}
+
+#[post("/mining/submit")]
+fn submit_block(blockchain: web::Data<Mutex<Blockchain>>, req: web::Json<BlockSubmission>) -> HttpResponse {
+    let mut local_blockchain = blockchain.lock().unwrap();
+    if let Some(reason) = &local_blockchain.corruption {
+        return HttpResponse::ServiceUnavailable().json(Response {
+            message: format!("Mining is disabled because the chain is corrupt: {}", reason)
+        })
+    }
+    if let Err(response) = local_blockchain.accepts_mining() {
+        return response
+    }
+    match local_blockchain.submit_block(req.into_inner()) {
+        Ok(block) => {
+            let compact = local_blockchain.compact_block(&block);
+            let peers = local_blockchain.node_list().nodes;
+            let logger = local_blockchain.logger();
+            thread::spawn(move || broadcast_block(&peers, &compact, logger));
+            HttpResponse::Ok().json(Mine {
+                message: "New block forged".to_string(),
+                index: block.index,
+                proof: block.proof,
+                previous_hash: block.previous_hash.clone(),
+                transactions: block.transactions
+            })
+        }
+        Err(err @ BlockchainError::StaleTemplate { .. }) => HttpResponse::Conflict().json(Response {
+            message: err.to_string()
+        }),
+        Err(err) => HttpResponse::BadRequest().json(Response {
+            message: err.to_string()
+        })
+    }
+}
+
+#[post("/transactions/new")]
+fn new_transaction(blockchain: web::Data<Mutex<Blockchain>>, req: web::Json<Transaction>) -> HttpResponse {
+    let transaction = req.into_inner().normalized();
+    let id = transaction.id();
+    let mut local_blockchain = blockchain.lock().unwrap();
+    if let Err(response) = local_blockchain.accepts_transactions() {
+        return response
+    }
+    match local_blockchain.new_transaction(transaction) {
+        Ok(index) => HttpResponse::Ok().json(NewTransaction {
+            message: format!("Your transaction will be in block: {}", index),
+            id
+        }),
+        Err(err) => HttpResponse::BadRequest().json(Response {
+            message: err.to_string()
+        })
+    }
+}
+
+// Normalizes the transaction the same way /transactions/new does, so the id
+// matches the one the node assigns on acceptance. Nothing is validated.
+#[post("/util/txid")]
+fn transaction_id(req: web::Json<Transaction>) -> HttpResponse {
+    HttpResponse::Ok().json(TransactionId {
+        id: req.into_inner().normalized().id()
+    })
+}
+
+// Measures the body the same way the node does when it checks fee rates.
+#[post("/util/estimate-size")]
+fn estimate_size(blockchain: web::Data<Mutex<Blockchain>>, req: web::Json<SizeRequest>) -> HttpResponse {
+    let estimate = match req.into_inner() {
+        SizeRequest::Block(block) => SizeEstimate { kind: "block".to_string(), size: block.byte_size(), required_fee: None },
+        SizeRequest::Transaction(transaction) => {
+            let size = transaction.normalized().byte_size();
+            let config = &blockchain.lock().unwrap().config;
+            SizeEstimate {
+                kind: "transaction".to_string(),
+                size,
+                required_fee: Some(config.min_fee.max(config.min_fee_per_byte * size as f32))
+            }
+        }
+    };
+    HttpResponse::Ok().json(estimate)
+}
+
+#[post("/transactions/simulate")]
+fn simulate_transaction(blockchain: web::Data<Mutex<Blockchain>>, req: web::Json<Transaction>) -> HttpResponse {
+    match blockchain.lock().unwrap().simulate_transaction(&req.into_inner().normalized()) {
+        Ok(simulation) => HttpResponse::Ok().json(simulation),
+        Err(err) => HttpResponse::BadRequest().json(Response {
+            message: err.to_string()
+        })
+    }
+}
+
+#[get("/transactions/pending/export")]
+fn export_pending(blockchain: web::Data<Mutex<Blockchain>>) -> HttpResponse {
+    HttpResponse::Ok()
+        .header("Content-Disposition", "attachment; filename=\"mempool.json\"")
+        .json(blockchain.lock().unwrap().current_transactions.clone())
+}
+
+#[post("/transactions/pending/import")]
+fn import_pending(blockchain: web::Data<Mutex<Blockchain>>, req: web::Json<Vec<Transaction>>) -> HttpResponse {
+    let mut local_blockchain = blockchain.lock().unwrap();
+    if let Err(response) = local_blockchain.accepts_transactions() {
+        return response
+    }
+    HttpResponse::Ok().json(local_blockchain.import_transactions(req.into_inner()))
+}
+
+#[post("/transactions/get")]
+fn get_transactions(blockchain: web::Data<Mutex<Blockchain>>, req: web::Json<TransactionIds>) -> HttpResponse {
+    HttpResponse::Ok().json(blockchain.lock().unwrap().find_transactions(&req.ids))
+}
+
+#[post("/block/receive")]
+fn receive_block(blockchain: web::Data<Mutex<Blockchain>>, req: web::Json<Block>) -> HttpResponse {
+    let block = req.into_inner();
+    let mut local_blockchain = blockchain.lock().unwrap();
+    if let Err(response) = local_blockchain.accepts_writes() {
+        return response
+    }
+    match local_blockchain.receive_block(block.clone()) {
+        Ok(Received::Accepted) => {
+            let peers = local_blockchain.node_list().nodes;
+            let logger = local_blockchain.logger();
+            thread::spawn(move || relay_block(&peers, &block, logger));
+            HttpResponse::Ok().json(Response {
+                message: "Block accepted".to_string()
+            })
+        },
+        Ok(Received::AlreadySeen) => HttpResponse::Ok().json(Response {
+            message: "Block already seen".to_string()
+        }),
+        Ok(Received::Buffered) => HttpResponse::Accepted().json(Response {
+            message: "Block buffered until its parent arrives".to_string()
+        }),
+        Err(err) => HttpResponse::BadRequest().json(Response {
+            message: err.to_string()
+        })
+    }
+}
+
+// Rebuilds an announced block from our mempool, fetching only the transactions we
+// don't have from the announcing node.
+#[post("/block/compact")]
+fn receive_compact_block(blockchain: web::Data<Mutex<Blockchain>>, req: web::Json<CompactBlock>) -> HttpResponse {
+    let compact = req.into_inner();
+    let logger = {
+        let local_blockchain = blockchain.lock().unwrap();
+        if let Err(response) = local_blockchain.accepts_writes() {
+            return response
+        }
+        local_blockchain.logger()
+    };
+    let mut transactions = blockchain.lock().unwrap().find_transactions(&compact.short_ids);
+    let missing: Vec<String> = compact.short_ids.iter()
+        .filter(|short_id| !transactions.iter().any(|transaction| transaction.short_id() == **short_id))
+        .cloned()
+        .collect();
+    if !missing.is_empty() {
+        transactions.extend(fetch_transactions(&compact.origin, &missing, logger));
+    }
+    let mut block_transactions = compact.prefilled.clone();
+    for short_id in &compact.short_ids {
+        match transactions.iter().find(|transaction| transaction.short_id() == *short_id) {
+            Some(transaction) => block_transactions.push(transaction.clone()),
+            None => return HttpResponse::BadRequest().json(Response {
+                message: BlockchainError::MissingTransactions {
+                    count: compact.short_ids.len() + compact.prefilled.len() - block_transactions.len()
+                }.to_string()
+            })
+        }
+    }
+    let block = Block {
+        index: compact.index,
+        timestamp: compact.timestamp,
+        transactions: block_transactions,
+        proof: compact.proof,
+        previous_hash: compact.previous_hash,
+        difficulty: compact.difficulty,
+        merkle_root: compact.merkle_root,
+        difficulty_bits: compact.difficulty_bits,
+        miner_key: compact.miner_key,
+        signature: compact.signature
+    };
+    match blockchain.lock().unwrap().receive_block(block) {
+        Ok(Received::Accepted) => HttpResponse::Ok().json(Response {
+            message: format!("Block reconstructed after fetching {} transactions", missing.len())
+        }),
+        Ok(Received::AlreadySeen) => HttpResponse::Ok().json(Response {
+            message: "Block already seen".to_string()
+        }),
+        Ok(Received::Buffered) => HttpResponse::Accepted().json(Response {
+            message: "Block buffered until its parent arrives".to_string()
+        }),
+        Err(err) => HttpResponse::BadRequest().json(Response {
+            message: err.to_string()
+        })
+    }
+}
+
+#[get("/fee/estimate")]
+fn fee_estimate(blockchain: web::Data<Mutex<Blockchain>>) -> HttpResponse {
+    HttpResponse::Ok().json(blockchain.lock().unwrap().fee_estimate())
+}
+
+#[get("/status")]
+fn status(blockchain: web::Data<Mutex<Blockchain>>) -> HttpResponse {
+    HttpResponse::Ok().json(blockchain.lock().unwrap().status())
+}
+
+// Long-polls on the blocking thread pool so waiters don't tie up the workers
+// that have to accept the block confirming them.
+#[get("/transactions/{id}/wait")]
+fn wait_for_transaction(blockchain: web::Data<Mutex<Blockchain>>, id: web::Path<String>, query: web::Query<WaitQuery>)
+    -> impl Future<Item = HttpResponse, Error = actix_web::Error> {
+    let id = id.into_inner();
+    let timeout = Duration::from_secs(query.timeout.unwrap_or(DEFAULT_WAIT_TIMEOUT).min(MAX_WAIT_TIMEOUT));
+    let waiting = id.clone();
+    web::block(move || -> Result<Option<usize>, ()> {
+        let deadline = Instant::now() + timeout;
+        let notifier = blockchain.lock().unwrap().block_notifier.clone();
+        loop {
+            let seen = notifier.generation();
+            if let Some(index) = blockchain.lock().unwrap().confirmed_in(&waiting) {
+                return Ok(Some(index))
+            }
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining == Duration::from_secs(0) || !notifier.wait(seen, remaining) {
+                return Ok(None)
+            }
+        }
+    })
+    .then(move |res| Ok(match res {
+        Ok(Some(block_index)) => HttpResponse::Ok().json(Confirmation { id, block_index }),
+        Ok(None) => HttpResponse::RequestTimeout().json(Response {
+            message: format!("Transaction {} was not confirmed in time", id)
+        }),
+        Err(_) => HttpResponse::InternalServerError().json(Response {
+            message: "There was an error waiting for the transaction".to_string()
+        })
+    }))
+}
+
+#[get("/transactions/by-category/{cat}")]
+fn transactions_by_category(blockchain: web::Data<Mutex<Blockchain>>, cat: web::Path<String>) -> HttpResponse {
+    HttpResponse::Ok().json(blockchain.lock().unwrap().transactions_by_category(&cat))
+}
+
+#[get("/chain")]
+fn full_chain(blockchain: web::Data<Mutex<Blockchain>>, query: web::Query<ChainQuery>) -> HttpResponse {
+    let local_blockchain = blockchain.lock().unwrap();
+    match query.version {
+        None => HttpResponse::Ok().json(local_blockchain.full_chain()),
+        Some(version) => match local_blockchain.versioned_chain(version) {
+            Some(chain) => HttpResponse::Ok().json(chain),
+            None => HttpResponse::BadRequest().json(Response {
+                message: format!("Unsupported schema version {}", version)
+            })
+        }
+    }
+}
+
+// Only the blocks are copied while the lock is held; each one is serialized as
+// its line is written, so the node keeps serving while a long chain streams out.
+#[get("/chain/ndjson")]
+fn chain_ndjson(blockchain: web::Data<Mutex<Blockchain>>) -> HttpResponse {
+    let chain = blockchain.lock().unwrap().chain.clone();
+    let lines = futures::stream::iter_ok::<_, actix_web::Error>(chain)
+        .map(|block| web::Bytes::from(format!("{}\n", serde_json::to_string(&block).unwrap())));
+    HttpResponse::Ok()
+        .content_type("application/x-ndjson")
+        .streaming(lines)
+}
+
+#[get("/version")]
+fn schema_versions(blockchain: web::Data<Mutex<Blockchain>>) -> HttpResponse {
+    let config = &blockchain.lock().unwrap().config;
+    HttpResponse::Ok().json(SchemaVersions { versions: config.schema_versions.clone(), base_path: config.base_path.clone() })
+}
+
+// Without a signing key the node's blocks are unsigned, so there is no public key.
+#[get("/identity")]
+fn identity(blockchain: web::Data<Mutex<Blockchain>>) -> HttpResponse {
+    let local_blockchain = blockchain.lock().unwrap();
+    let public_key = local_blockchain.signer.as_ref().map(|signer| signer.public_key.clone());
+    HttpResponse::Ok().json(Identity {
+        node_id: local_blockchain.config.node_identifier.clone(),
+        signature_algorithm: public_key.as_ref().map(|_| "ECDSA P-256 with SHA-256".to_string()),
+        public_key,
+        chain_id: local_blockchain.config.chain_id.clone(),
+        version: env!("CARGO_PKG_VERSION").to_string(),
+        schema_versions: local_blockchain.config.schema_versions.clone()
+    })
+}
+
+#[get("/chain/at-hash/{hash}")]
+fn chain_at_hash(blockchain: web::Data<Mutex<Blockchain>>, hash: web::Path<String>) -> HttpResponse {
+    match blockchain.lock().unwrap().chain_at_hash(&hash) {
+        Some(chain) => HttpResponse::Ok().json(chain),
+        None => HttpResponse::NotFound().json(Response {
+            message: format!("No block with hash {}", hash)
+        })
+    }
+}
+
+#[get("/chain/locator")]
+fn chain_locator(blockchain: web::Data<Mutex<Blockchain>>) -> HttpResponse {
+    HttpResponse::Ok().json(blockchain.lock().unwrap().locator())
+}
+
+#[post("/chain/locate")]
+fn locate_fork_point(blockchain: web::Data<Mutex<Blockchain>>, req: web::Json<BlockLocator>) -> HttpResponse {
+    match blockchain.lock().unwrap().fork_point(&req) {
+        Some(fork_point) => HttpResponse::Ok().json(fork_point),
+        None => HttpResponse::NotFound().json(Response {
+            message: "No block in the locator is on our chain".to_string()
+        })
+    }
+}
+
+#[get("/chain/blocks")]
+fn chain_blocks(blockchain: web::Data<Mutex<Blockchain>>, query: web::Query<CursorQuery>) -> HttpResponse {
+    match blockchain.lock().unwrap().blocks_after(&query) {
+        Ok(page) => HttpResponse::Ok().json(page),
+        Err(err @ BlockchainError::StaleCursor { .. }) => HttpResponse::Conflict().json(Response {
+            message: err.to_string()
+        }),
+        Err(err) => HttpResponse::BadRequest().json(Response {
+            message: err.to_string()
+        })
+    }
+}
+
+#[get("/blocks/orphans")]
+fn orphan_blocks(blockchain: web::Data<Mutex<Blockchain>>) -> HttpResponse {
+    HttpResponse::Ok().json(blockchain.lock().unwrap().off_chain_blocks())
+}
+
+#[get("/chain/tail/{n}")]
+fn chain_tail(blockchain: web::Data<Mutex<Blockchain>>, n: web::Path<usize>) -> HttpResponse {
+    HttpResponse::Ok().json(blockchain.lock().unwrap().tail(*n))
+}
+
+#[get("/chain/work")]
+fn chain_work(blockchain: web::Data<Mutex<Blockchain>>) -> HttpResponse {
+    HttpResponse::Ok().json(blockchain.lock().unwrap().chain_work())
+}
+
+#[get("/chain/info")]
+fn chain_info(blockchain: web::Data<Mutex<Blockchain>>) -> HttpResponse {
+    HttpResponse::Ok().json(blockchain.lock().unwrap().chain_info())
+}
+
+#[post("/nodes/register")]
+fn register_nodes(blockchain: web::Data<Mutex<Blockchain>>, req: web::Json<NodeList>) -> HttpResponse {
+    if let Err(response) = blockchain.lock().unwrap().accepts_writes() {
+        return response
+    }
+    if let Some(node) = req.nodes.iter().find(|node| canonical_node(node).is_none()) {
+        return HttpResponse::BadRequest().json(Response {
+            message: format!("{} is not a URL with a host, such as http://{}", node, node)
+        })
+    }
+    for node in &req.nodes {
+        let _ = blockchain.lock().unwrap().register_node(node);
+    }
+    HttpResponse::Ok().json(Response {
+        message: format!("Nodes successfully registered")
+    })
+}
+
+#[get("/nodes/resolve")]
+fn consensus(blockchain: web::Data<Mutex<Blockchain>>) -> HttpResponse {
+    let mut local_blockchain = blockchain.lock().unwrap();
+    if let Err(response) = local_blockchain.accepts_writes() {
+        return response
+    }
+    let result = local_blockchain.resolve_conflicts();
+    if result.network_error.is_some() {
+        return HttpResponse::ServiceUnavailable().json(result)
+    }
+    HttpResponse::Ok().json(result)
+}
+
+#[get("/nodes/resolve/dry-run")]
+fn consensus_dry_run(blockchain: web::Data<Mutex<Blockchain>>) -> HttpResponse {
+    HttpResponse::Ok().json(blockchain.lock().unwrap().resolve_dry_run())
+}
+
+#[post("/chain/rollback")]
+fn rollback(blockchain: web::Data<Mutex<Blockchain>>, req: HttpRequest, body: web::Json<Rollback>) -> HttpResponse {
+    let mut local_blockchain = blockchain.lock().unwrap();
+    if let Err(response) = local_blockchain.config.authorize_admin(&req) {
+        return response
+    }
+    match local_blockchain.rollback(body.blocks) {
+        Some(result) => HttpResponse::Ok().json(result),
+        None => HttpResponse::BadRequest().json(Response {
+            message: format!("Cannot roll back {} blocks from a chain of length {}", body.blocks, local_blockchain.chain.len())
+        })
+    }
+}
+
+#[get("/debug/state")]
+fn debug_state(blockchain: web::Data<Mutex<Blockchain>>, req: HttpRequest) -> HttpResponse {
+    let local_blockchain = blockchain.lock().unwrap();
+    if let Err(response) = local_blockchain.config.authorize_admin(&req) {
+        return response
+    }
+    HttpResponse::Ok().json(local_blockchain.debug_state())
+}
+
+#[get("/reward/schedule")]
+fn reward_schedule(blockchain: web::Data<Mutex<Blockchain>>) -> HttpResponse {
+    HttpResponse::Ok().json(blockchain.lock().unwrap().reward_schedule())
+}
+
+#[post("/reward/schedule")]
+fn update_reward_schedule(blockchain: web::Data<Mutex<Blockchain>>, req: HttpRequest, update: web::Json<RewardScheduleUpdate>)
+    -> HttpResponse {
+    let mut local_blockchain = blockchain.lock().unwrap();
+    if let Err(response) = local_blockchain.config.authorize_admin(&req) {
+        return response
+    }
+    match local_blockchain.update_reward_schedule(&update) {
+        Ok(schedule) => HttpResponse::Ok().json(schedule),
+        Err(message) => HttpResponse::BadRequest().json(Response { message })
+    }
+}
+
+#[get("/consensus/rules")]
+fn consensus_rules(blockchain: web::Data<Mutex<Blockchain>>) -> HttpResponse {
+    HttpResponse::Ok().json(blockchain.lock().unwrap().consensus_rules())
+}
+
+#[get("/mempool/policy")]
+fn mempool_policy(blockchain: web::Data<Mutex<Blockchain>>) -> HttpResponse {
+    HttpResponse::Ok().json(blockchain.lock().unwrap().mempool_policy())
+}
+
+#[post("/admin/policy")]
+fn update_policy(blockchain: web::Data<Mutex<Blockchain>>, req: HttpRequest, update: web::Json<PolicyUpdate>) -> HttpResponse {
+    let mut local_blockchain = blockchain.lock().unwrap();
+    if let Err(response) = local_blockchain.config.authorize_admin(&req) {
+        return response
+    }
+    match local_blockchain.update_policy(&update) {
+        Ok(policy) => HttpResponse::Ok().json(policy),
+        Err(message) => HttpResponse::BadRequest().json(Response { message })
+    }
+}
+
+// The admin check is the only part run under the lock; the test itself works
+// on a chain of its own.
+#[post("/selftest")]
+fn self_test(blockchain: web::Data<Mutex<Blockchain>>, req: HttpRequest) -> HttpResponse {
+    if let Err(response) = blockchain.lock().unwrap().config.authorize_admin(&req) {
+        return response
+    }
+    let result = Blockchain::self_test();
+    if result.passed {
+        HttpResponse::Ok().json(result)
+    } else {
+        HttpResponse::InternalServerError().json(result)
+    }
+}
+
+#[post("/admin/maintenance")]
+fn set_maintenance(blockchain: web::Data<Mutex<Blockchain>>, req: HttpRequest, body: web::Json<Maintenance>) -> HttpResponse {
+    let mut local_blockchain = blockchain.lock().unwrap();
+    if let Err(response) = local_blockchain.config.authorize_admin(&req) {
+        return response
+    }
+    local_blockchain.maintenance = body.enabled;
+    local_blockchain.log(LogLevel::Info, if body.enabled { "entered maintenance" } else { "left maintenance" });
+    HttpResponse::Ok().json(Maintenance { enabled: local_blockchain.maintenance })
+}
+
+#[post("/admin/reindex")]
+fn reindex(blockchain: web::Data<Mutex<Blockchain>>, req: HttpRequest) -> HttpResponse {
+    let mut local_blockchain = blockchain.lock().unwrap();
+    if let Err(response) = local_blockchain.config.authorize_admin(&req) {
+        return response
+    }
+    HttpResponse::Ok().json(local_blockchain.reindex())
+}
+
+#[post("/admin/compact")]
+fn compact_storage(blockchain: web::Data<Mutex<Blockchain>>, req: HttpRequest) -> HttpResponse {
+    let local_blockchain = blockchain.lock().unwrap();
+    if let Err(response) = local_blockchain.config.authorize_admin(&req) {
+        return response
+    }
+    match local_blockchain.compact_storage() {
+        Ok(Some(compaction)) => HttpResponse::Ok().json(compaction),
+        Ok(None) => HttpResponse::BadRequest().json(Response {
+            message: "No storage is configured".to_string()
+        }),
+        Err(err) => HttpResponse::InternalServerError().json(Response {
+            message: format!("Failed to compact the storage: {}", err)
+        })
+    }
+}
+
+#[get("/audit")]
+fn audit(blockchain: web::Data<Mutex<Blockchain>>) -> HttpResponse {
+    HttpResponse::Ok().json(blockchain.lock().unwrap().audit())
+}
+
+#[get("/network/supply")]
+fn network_supply(blockchain: web::Data<Mutex<Blockchain>>) -> HttpResponse {
+    let (local_supply, peers, limit) = {
+        let local_blockchain = blockchain.lock().unwrap();
+        (local_blockchain.audit().total_supply, local_blockchain.node_list().nodes, local_blockchain.config.max_peer_requests)
+    };
+    match collect_network_supply(local_supply, peers, limit) {
+        Ok(supply) => HttpResponse::Ok().json(supply),
+        Err(message) => HttpResponse::ServiceUnavailable().json(Response { message })
+    }
+}
+
+#[get("/chain/verify-conservation")]
+fn verify_conservation(blockchain: web::Data<Mutex<Blockchain>>) -> HttpResponse {
+    HttpResponse::Ok().json(blockchain.lock().unwrap().verify_conservation())
+}
+
+#[get("/miner/{addr}/earnings")]
+fn miner_earnings(blockchain: web::Data<Mutex<Blockchain>>, addr: web::Path<String>) -> HttpResponse {
+    HttpResponse::Ok().json(blockchain.lock().unwrap().earnings_of(&addr))
+}
+
+#[post("/balances")]
+fn batch_balances(blockchain: web::Data<Mutex<Blockchain>>, req: web::Json<BalanceQuery>) -> HttpResponse {
+    HttpResponse::Ok().json(blockchain.lock().unwrap().balances_of(&req.addresses))
+}
+
+#[get("/utxos/{addr}")]
+fn unspent_outputs(blockchain: web::Data<Mutex<Blockchain>>, addr: web::Path<String>) -> HttpResponse {
+    HttpResponse::Ok().json(blockchain.lock().unwrap().unspent_outputs_of(&addr))
+}
+
+#[get("/miner/{addr}/blocks")]
+fn miner_blocks(blockchain: web::Data<Mutex<Blockchain>>, addr: web::Path<String>) -> HttpResponse {
+    let blocks = blockchain.lock().unwrap().blocks_mined_by(&addr);
+    HttpResponse::Ok().json(MinerBlocks {
+        address: addr.into_inner(),
+        blocks
+    })
+}
+
+#[get("/stats/block-sizes")]
+fn block_sizes(blockchain: web::Data<Mutex<Blockchain>>, query: web::Query<PageQuery>) -> HttpResponse {
+    let block_sizes = blockchain.lock().unwrap().block_sizes();
+    HttpResponse::Ok().json(Page::new(&block_sizes, &query))
+}
+
+#[get("/stats/difficulty-history")]
+fn difficulty_history(blockchain: web::Data<Mutex<Blockchain>>, query: web::Query<PageQuery>) -> HttpResponse {
+    let history = blockchain.lock().unwrap().difficulty_history();
+    HttpResponse::Ok().json(Page::new(&history, &query))
+}
+
+#[get("/stats/window")]
+fn window_stats(blockchain: web::Data<Mutex<Blockchain>>, query: web::Query<WindowQuery>) -> HttpResponse {
+    match (DateTime::parse_from_rfc3339(&query.from), DateTime::parse_from_rfc3339(&query.to)) {
+        (Ok(from), Ok(to)) => HttpResponse::Ok().json(
+            blockchain.lock().unwrap().window_stats(from.with_timezone(&Utc), to.with_timezone(&Utc))
+        ),
+        _ => HttpResponse::BadRequest().json(Response {
+            message: "from and to must be RFC 3339 timestamps".to_string()
+        })
+    }
+}
+
+#[get("/blocks/by-time")]
+fn blocks_by_time(blockchain: web::Data<Mutex<Blockchain>>, query: web::Query<WindowQuery>) -> HttpResponse {
+    match (DateTime::parse_from_rfc3339(&query.from), DateTime::parse_from_rfc3339(&query.to)) {
+        (Ok(from), Ok(to)) => HttpResponse::Ok().json(
+            blockchain.lock().unwrap().blocks_between(from.with_timezone(&Utc), to.with_timezone(&Utc))
+        ),
+        _ => HttpResponse::BadRequest().json(Response {
+            message: "from and to must be RFC 3339 timestamps".to_string()
+        })
+    }
+}
+
+// Registered ahead of /block/{index}, which would otherwise reject "genesis" as an index.
+#[get("/block/genesis")]
+fn genesis_block(blockchain: web::Data<Mutex<Blockchain>>) -> HttpResponse {
+    HttpResponse::Ok().json(blockchain.lock().unwrap().genesis())
+}
+
+#[get("/block/{index}")]
+fn block_by_index(blockchain: web::Data<Mutex<Blockchain>>, index: web::Path<usize>) -> HttpResponse {
+    match blockchain.lock().unwrap().block(*index) {
+        Some(block) => HttpResponse::Ok().json(block),
+        None => HttpResponse::NotFound().json(Response {
+            message: format!("No block with index {}", index)
+        })
+    }
+}
+
+#[get("/block/{index}/preimage")]
+fn block_preimage(blockchain: web::Data<Mutex<Blockchain>>, index: web::Path<usize>) -> HttpResponse {
+    match blockchain.lock().unwrap().block(*index) {
+        Some(block) => {
+            let preimage = block.preimage();
+            HttpResponse::Ok().json(BlockPreimage {
+                index: block.index,
+                preimage_hex: preimage.bytes().map(|byte| format!("{:02x}", byte)).collect(),
+                preimage,
+                hash: block.hash()
+            })
+        },
+        None => HttpResponse::NotFound().json(Response {
+            message: format!("No block with index {}", index)
+        })
+    }
+}
+
+// Streams block and transaction events. A client reconnecting with Last-Event-ID
+// first gets the events it missed, as long as they are still in the history.
+#[get("/events")]
+fn events(blockchain: web::Data<Mutex<Blockchain>>, req: HttpRequest) -> HttpResponse {
+    let local_blockchain = blockchain.lock().unwrap();
+    if !local_blockchain.config.events {
+        return HttpResponse::NotFound().json(Response {
+            message: "Events are disabled on this node".to_string()
+        })
+    }
+    let last_event_id = req.headers().get(LAST_EVENT_ID_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse().ok());
+    let stream = local_blockchain.events.subscribe(last_event_id)
+        .map_err(|()| actix_web::error::ErrorInternalServerError("event stream closed"));
+    HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .header("Cache-Control", "no-cache")
+        .streaming(stream)
+}
+
+#[get("/mining/suggest-difficulty")]
+fn suggest_difficulty(blockchain: web::Data<Mutex<Blockchain>>, query: web::Query<DifficultyQuery>) -> HttpResponse {
+    let local_blockchain = blockchain.lock().unwrap();
+    let target_block_time = query.target_block_time.unwrap_or(local_blockchain.config.target_block_time as f64);
+    if !target_block_time.is_finite() || target_block_time <= 0.0 {
+        return HttpResponse::BadRequest().json(Response {
+            message: "target_block_time must be a positive number of seconds".to_string()
+        })
+    }
+    match local_blockchain.suggest_difficulty(target_block_time) {
+        Some(suggestion) => HttpResponse::Ok().json(suggestion),
+        None => HttpResponse::ServiceUnavailable().json(Response {
+            message: "No blocks mined yet to measure the hashrate".to_string()
+        })
+    }
+}
+
+fn retry_later(message: &str) -> HttpResponse {
+    HttpResponse::ServiceUnavailable()
+        .header("Retry-After", SYNC_RETRY_AFTER.to_string())
+        .json(Response { message: message.to_string() })
+}
+
+// Unlike /health, only ready once the node is synced and can take transactions
+// and mine, and not while it is down for maintenance.
+#[get("/ready")]
+fn ready(blockchain: web::Data<Mutex<Blockchain>>) -> HttpResponse {
+    let (state, maintenance) = {
+        let local_blockchain = blockchain.lock().unwrap();
+        (local_blockchain.sync_state(), local_blockchain.maintenance)
+    };
+    let message = if maintenance { "maintenance" } else { state.as_str() };
+    let response = Response { message: message.to_string() };
+    match state {
+        SyncState::Synced if !maintenance => HttpResponse::Ok().json(response),
+        _ => HttpResponse::ServiceUnavailable().header("Retry-After", SYNC_RETRY_AFTER.to_string()).json(response)
+    }
+}
+
+#[get("/health")]
+fn health() -> HttpResponse {
+    HttpResponse::Ok().json(Response {
+        message: "ok".to_string()
+    })
+}
+
+#[get("/nodes/latency")]
+fn latency(blockchain: web::Data<Mutex<Blockchain>>) -> HttpResponse {
+    let (peers, limit) = {
+        let local_blockchain = blockchain.lock().unwrap();
+        (local_blockchain.node_list().nodes, local_blockchain.config.max_peer_requests)
+    };
+    match measure_latency(peers, limit) {
+        Ok(latencies) => HttpResponse::Ok().json(latencies),
+        Err(message) => HttpResponse::ServiceUnavailable().json(Response { message })
+    }
+}
+
+#[get("/nodes/topology")]
+fn topology(blockchain: web::Data<Mutex<Blockchain>>, query: web::Query<TopologyQuery>) -> HttpResponse {
+    let (origin, peers, max_depth, limit, logger) = {
+        let local_blockchain = blockchain.lock().unwrap();
+        let config = &local_blockchain.config;
+        (config.origin(), local_blockchain.node_list().nodes, config.max_topology_depth, config.max_peer_requests, local_blockchain.logger())
+    };
+    let depth = query.depth.unwrap_or(max_depth).min(max_depth).max(1);
+    match discover_topology(&origin, peers, depth, limit, logger) {
+        Ok(discovered) => HttpResponse::Ok().json(discovered),
+        Err(message) => HttpResponse::ServiceUnavailable().json(Response { message })
+    }
+}
+
+#[get("/nodes")]
+fn nodes(blockchain: web::Data<Mutex<Blockchain>>) -> HttpResponse {
+    HttpResponse::Ok().json(blockchain.lock().unwrap().node_list())
+}
+
+fn broadcast_block(peers: &[String], compact: &CompactBlock, logger: Logger) {
+    let client = match reqwest::Client::builder().timeout(PEER_TIMEOUT).build() {
+        Ok(client) => client,
+        Err(err) => return logger.log(LogLevel::Error,
+            &format!("failed to announce block {}: the HTTP client could not be created: {}", compact.index, err))
+    };
+    for node in peers {
+        if let Err(err) = client.post(&format!("http://{}/block/compact", node)).json(compact).send() {
+            logger.log_peer(LogLevel::Info, node, &format!("failed to announce block {} to {}: {}", compact.index, node, err));
+        }
+    }
+}
+
+fn relay_block(peers: &[String], block: &Block, logger: Logger) {
+    let client = match reqwest::Client::builder().timeout(PEER_TIMEOUT).build() {
+        Ok(client) => client,
+        Err(err) => return logger.log(LogLevel::Error,
+            &format!("failed to relay block {}: the HTTP client could not be created: {}", block.index, err))
+    };
+    for node in peers {
+        if let Err(err) = client.post(&format!("http://{}/block/receive", node)).json(block).send() {
+            logger.log_peer(LogLevel::Info, node, &format!("failed to relay block {} to {}: {}", block.index, node, err));
+        }
+    }
+}
+
+// Tells a peer we never got an answer from, because of DNS or the connection,
+// apart from one that answered with something we can't use.
+enum FetchError {
+    Unreachable(String),
+    BadResponse(String)
+}
+
+impl fmt::Display for FetchError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FetchError::Unreachable(err) => write!(f, "unreachable: {}", err),
+            FetchError::BadResponse(err) => write!(f, "{}", err)
+        }
+    }
+}
+
+fn canonical_node(node: &str) -> Option<String> {
+    let parsed_url = Url::parse(node).ok()?;
+    let path = parsed_url.path().trim_end_matches('/');
+    let host = parsed_url.host_str()?.trim_end_matches('.').to_lowercase();
+    match parsed_url.port_or_known_default().filter(|port| *port != 80) {
+        Some(port) => Some(format!("{}:{}{}", host, port, path)),
+        None => Some(format!("{}{}", host, path))
+    }
+}
+
+// A peer registered with a path is reached there. One registered by host alone
+// is reached under the base path it advertised in its /version handshake.
+fn peer_root(node: &str, base_path: &str) -> String {
+    if node.contains('/') {
+        node.to_string()
+    } else {
+        format!("{}{}", node, base_path)
+    }
+}
+
+// Picks the newest schema version both sides support, along with where the
+// peer's API is mounted. Peers that predate version negotiation only speak the
+// original schema, at the root.
+fn negotiate_version(client: &reqwest::Client, node: &str, supported: &[u32]) -> Option<(u32, String)> {
+    let remote = client.get(&format!("http://{}/version", node)).send()
+        .and_then(|res| res.error_for_status())
+        .and_then(|mut res| res.json::<SchemaVersions>())
+        .unwrap_or_else(|_| SchemaVersions { versions: vec![SCHEMA_VERSION], base_path: String::new() });
+    let version = supported.iter()
+        .filter(|version| remote.versions.contains(version))
+        .max()
+        .cloned()?;
+    Some((version, peer_root(node, &remote.base_path)))
+}
+
+// Reads at most `max_bytes` of the body, so a peer can't make us buffer an
+// arbitrarily large chain.
+fn fetch_chain(client: &reqwest::Client, url: &str, max_bytes: u64) -> Result<FullChain, FetchError> {
+    let res = client.get(url).send().map_err(|err| FetchError::Unreachable(err.to_string()))?;
+    let too_large = || FetchError::BadResponse(format!("response exceeds the limit of {} bytes", max_bytes));
+    if matches!(res.content_length(), Some(length) if length > max_bytes) {
+        return Err(too_large())
+    }
+    let mut body = Vec::new();
+    res.take(max_bytes + 1).read_to_end(&mut body).map_err(|err| FetchError::BadResponse(err.to_string()))?;
+    if body.len() as u64 > max_bytes {
+        return Err(too_large())
+    }
+    serde_json::from_slice(&body).map_err(|err| FetchError::BadResponse(err.to_string()))
+}
+
+fn fetch_transactions(origin: &str, short_ids: &[String], logger: Logger) -> Vec<Transaction> {
+    let ids = TransactionIds { ids: short_ids.to_vec() };
+    let client = match reqwest::Client::builder().timeout(PEER_TIMEOUT).build() {
+        Ok(client) => client,
+        Err(err) => {
+            logger.log(LogLevel::Error, &format!("failed to fetch {} transactions: the HTTP client could not be created: {}",
+                short_ids.len(), err));
+            return Vec::new()
+        }
+    };
+    client.post(&format!("http://{}/transactions/get", origin)).json(&ids).send()
+        .and_then(|mut res| res.json())
+        .unwrap_or_else(|err| {
+            logger.log_peer(LogLevel::Info, origin,
+                &format!("failed to fetch {} transactions from {}: {}", short_ids.len(), origin, err));
+            Vec::new()
+        })
+}
+
+// Pings every peer at once, each on its own thread sharing one client, so the
+// whole measurement takes at most one peer timeout.
+fn measure_latency(peers: Vec<String>, limit: usize) -> Result<Vec<PeerLatency>, String> {
+    let client = reqwest::Client::builder().timeout(PEER_TIMEOUT).build()
+        .map_err(|err| format!("the HTTP client could not be created: {}", err))?;
+    let mut latencies = query_peers(peers, limit, move |node| {
+        let started = Instant::now();
+        match client.get(&format!("http://{}/health", node)).send().and_then(|res| res.error_for_status()) {
+            Ok(_) => PeerLatency { node, latency_ms: Some(started.elapsed().as_secs_f64() * 1000.0), error: None },
+            Err(err) => PeerLatency { node, latency_ms: None, error: Some(err.to_string()) }
+        }
+    })?;
+    latencies.sort_by(|a, b| a.node.cmp(&b.node));
+    Ok(latencies)
+}
+
+// Asks every peer for its audit and settles on the supply reported by the most
+// nodes, this one included. A peer whose supply differs from ours is most likely
+// on another chain; unreachable peers are listed but neither agree nor count.
+fn collect_network_supply(local_supply: f32, peers: Vec<String>, limit: usize) -> Result<NetworkSupply, String> {
+    let same = |a: f32, b: f32| (a - b).abs() <= 1e-3 * a.abs().max(b.abs()).max(1.0);
+    let client = reqwest::Client::builder().timeout(PEER_TIMEOUT).build()
+        .map_err(|err| format!("the HTTP client could not be created: {}", err))?;
+    let mut peers = query_peers(peers, limit, move |node| {
+        let res = client.get(&format!("http://{}/audit", node)).send()
+            .and_then(|res| res.error_for_status())
+            .and_then(|mut res| res.json::<Audit>());
+        match res {
+            Ok(peer_audit) => PeerSupply {
+                node,
+                total_supply: Some(peer_audit.total_supply),
+                agrees: same(peer_audit.total_supply, local_supply),
+                error: None
+            },
+            Err(err) => PeerSupply { node, total_supply: None, agrees: false, error: Some(err.to_string()) }
+        }
+    })?;
+    peers.sort_by(|a, b| a.node.cmp(&b.node));
+    let reported: Vec<f32> = std::iter::once(local_supply)
+        .chain(peers.iter().filter_map(|peer| peer.total_supply))
+        .collect();
+    let votes = |supply: f32| reported.iter().filter(|other| same(supply, **other)).count();
+    // Ties go to our own supply, which comes first.
+    let agreed_supply = reported.iter().cloned().fold(local_supply, |best, supply| {
+        if votes(supply) > votes(best) { supply } else { best }
+    });
+    Ok(NetworkSupply {
+        local_supply,
+        agreed_supply,
+        reporting: reported.len(),
+        agreeing: votes(agreed_supply),
+        peers
+    })
+}
+
+// Sends `request` to every peer from at most `limit` threads, so no more than
+// that many requests are in flight while the rest wait their turn. A request
+// that panics fails the whole query rather than losing the peers its thread had.
+fn query_peers<T, F>(peers: Vec<String>, limit: usize, request: F) -> Result<Vec<T>, String>
+    where T: Send + 'static, F: Fn(String) -> T + Send + Sync + 'static {
+    let workers = limit.max(1).min(peers.len());
+    let queue = Arc::new(Mutex::new(peers.into_iter().enumerate().collect::<VecDeque<_>>()));
+    let request = Arc::new(request);
+    let handles: Vec<_> = (0..workers)
+        .map(|_| {
+            let (queue, request) = (queue.clone(), request.clone());
+            thread::spawn(move || {
+                let mut results = Vec::new();
+                loop {
+                    // Popped in a statement of its own so the queue isn't locked during the request.
+                    let next = queue.lock().unwrap().pop_front();
+                    match next {
+                        Some((position, node)) => results.push((position, request(node))),
+                        None => return results
+                    }
+                }
+            })
+        })
+        .collect();
+    let mut results: Vec<(usize, T)> = Vec::new();
+    for handle in handles {
+        results.extend(handle.join().map_err(|_| "a request to a peer panicked".to_string())?);
+    }
+    results.sort_by_key(|(position, _)| *position);
+    Ok(results.into_iter().map(|(_, result)| result).collect())
+}
+
+// Walks the network breadth-first from this node, asking each peer for its own
+// peer list, and stops at the depth limit or once every reachable node was visited.
+fn discover_topology(origin: &str, peers: Vec<String>, depth: usize, limit: usize, logger: Logger) -> Result<Topology, String> {
+    let client = reqwest::Client::builder().timeout(PEER_TIMEOUT).build()
+        .map_err(|err| format!("the HTTP client could not be created: {}", err))?;
+    let mut visited: HashSet<String> = HashSet::new();
+    visited.insert(origin.to_string());
+    let mut edges: Vec<Edge> = peers.iter().map(|peer| Edge { from: origin.to_string(), to: peer.clone() }).collect();
+    let mut frontier = peers;
+    for _ in 1..depth {
+        let mut next = Vec::new();
+        let unvisited: Vec<String> = frontier.into_iter().filter(|node| visited.insert(node.clone())).collect();
+        let client = client.clone();
+        let fetched = query_peers(unvisited.clone(), limit, move |node| {
+            client.get(&format!("http://{}/nodes", node)).send().and_then(|mut res| res.json::<NodeList>())
+        })?;
+        for (node, res) in unvisited.into_iter().zip(fetched) {
+            match res {
+                Ok(node_list) => for peer in node_list.nodes {
+                    edges.push(Edge { from: node.clone(), to: peer.clone() });
+                    next.push(peer);
+                },
+                Err(err) => logger.log_peer(LogLevel::Info, &node, &format!("failed to fetch the peers of {}: {}", node, err))
+            }
+        }
+        frontier = next;
+    }
+    visited.extend(frontier);
+    let mut discovered: Vec<String> = visited.into_iter().collect();
+    discovered.sort();
+    Ok(Topology { nodes: discovered, edges })
+}
+
+// POST bodies must be declared as application/json unless strict checking is turned off,
+// in which case any content type is parsed as JSON.
+fn json_config(strict_content_type: bool) -> web::JsonConfig {
+    let json_config = web::JsonConfig::default()
+        .error_handler(|err, _req| {
+            let response = match err {
+                JsonPayloadError::ContentType => HttpResponse::UnsupportedMediaType().json(Response {
+                    message: "Request body must be sent with Content-Type: application/json".to_string()
+                }),
+                JsonPayloadError::Overflow => HttpResponse::PayloadTooLarge().json(Response {
+                    message: err.to_string()
+                }),
+                _ => HttpResponse::BadRequest().json(Response {
+                    message: err.to_string()
+                })
+            };
+            InternalError::from_response(err, response).into()
+        });
+    if strict_content_type {
+        json_config
+    } else {
+        json_config.content_type(|_| true)
+    }
+}
+
+// The API is mounted under the base path. A node with one also answers /version
+// at the root, so that peers registered by host alone can learn where it is.
+fn mount(config: &mut web::ServiceConfig, base_path: &str) {
+    if !base_path.is_empty() {
+        config.service(schema_versions);
+    }
+    config.service(web::scope(base_path).configure(routes));
+}
+
+fn routes(config: &mut web::ServiceConfig) {
+    config
+        .service(new_transaction)
+        .service(transaction_id)
+        .service(estimate_size)
+        .service(simulate_transaction)
+        .service(transactions_by_category)
+        .service(wait_for_transaction)
+        .service(get_transactions)
+        .service(export_pending)
+        .service(import_pending)
+        .service(receive_block)
+        .service(receive_compact_block)
+        .service(status)
+        .service(fee_estimate)
+        .service(full_chain)
+        .service(schema_versions)
+        .service(identity)
+        .service(chain_ndjson)
+        .service(chain_work)
+        .service(chain_info)
+        .service(chain_blocks)
+        .service(chain_locator)
+        .service(locate_fork_point)
+        .service(chain_tail)
+        .service(orphan_blocks)
+        .service(chain_at_hash)
+        .service(mine)
+        .service(pause_mining)
+        .service(mine_preview)
+        .service(submit_block)
+        .service(resume_mining)
+        .service(miner_blocks)
+        .service(miner_earnings)
+        .service(unspent_outputs)
+        .service(batch_balances)
+        .service(audit)
+        .service(verify_conservation)
+        .service(network_supply)
+        .service(block_sizes)
+        .service(window_stats)
+        .service(difficulty_history)
+        .service(blocks_by_time)
+        .service(genesis_block)
+        .service(block_by_index)
+        .service(block_preimage)
+        .service(nodes)
+        .service(topology)
+        .service(latency)
+        .service(health)
+        .service(ready)
+        .service(suggest_difficulty)
+        .service(events)
+        .service(register_nodes)
+        .service(consensus)
+        .service(consensus_dry_run)
+        .service(rollback)
+        .service(compact_storage)
+        .service(reindex)
+        .service(set_maintenance)
+        .service(self_test)
+        .service(consensus_rules)
+        .service(reward_schedule)
+        .service(update_reward_schedule)
+        .service(mempool_policy)
+        .service(update_policy)
+        .service(debug_state);
+}
+
+// Writes out whatever changed since the last tick, once per interval.
+fn spawn_saver(blockchain: web::Data<Mutex<Blockchain>>, interval: Duration) {
+    thread::spawn(move || loop {
+        thread::sleep(interval);
+        blockchain.lock().unwrap().save_if_unsaved();
+    });
+}
+
+fn main() -> io::Result<()> {
+    let args: Vec<String> = env::args().collect();
+    let mut config = match Config::from_args(&args).and_then(|config| config.validate().map(|()| config)) {
+        Ok(config) => config,
+        Err(err) => {
+            eprintln!("invalid configuration: {}", err);
+            std::process::exit(1);
+        }
+    };
+    let signer = match &config.signing_key {
+        Some(path) => Some(Arc::new(Signer::load_or_create(path)?)),
+        None => None
+    };
+    // A signing node mines under the address of its key, so that peers can
+    // check its blocks are signed by whoever their coinbase pays.
+    if let Some(signer) = &signer {
+        config.node_identifier = signer.address();
+    }
+    if let Some(max_difficulty) = config.benchmark {
+        let timings = Blockchain::benchmark(max_difficulty);
+        for (difficulty, elapsed) in &timings {
+            println!("difficulty {}: {:?}", difficulty, elapsed);
+        }
+        println!("recommended difficulty for a {}s block time: {}",
+            config.target_block_time, Blockchain::recommend_difficulty(&timings, config.target_block_time));
+    }
+    let port = config.port.clone();
+    let strict_content_type = config.strict_content_type;
+    let base_path = config.base_path.clone();
+    let save_interval = config.save_interval;
+    let mut blockchain = Blockchain::new(config);
+    blockchain.signer = signer;
+    blockchain.attach_storage()?;
+    let blockchain = web::Data::new(Mutex::new(blockchain));
+    if let Some(seconds) = save_interval {
+        spawn_saver(blockchain.clone(), Duration::from_secs(seconds));
+    }
+    HttpServer::new(move || {
+        App::new()
+            .register_data(blockchain.clone())
+            .data(json_config(strict_content_type))
+            .wrap(middleware::Logger::default())
+            .configure(|config| mount(config, &base_path))
+    })
+    .bind(format!("127.0.0.1:{}", port))?
+    .run()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::http::StatusCode;
+    use actix_web::test;
+
+    fn test_config() -> Config {
+        Config { difficulty: 1, ..Config::default() }
+    }
+
+    fn temp_path(name: &str) -> String {
+        env::temp_dir().join(format!("{}-{}", name, Uuid::new_v4())).to_string_lossy().into_owned()
+    }
+
+    fn mine_block(blockchain: &mut Blockchain) -> Block {
+        let last_block = blockchain.chain[blockchain.chain.len() - 1].clone();
+        let proof = blockchain.proof_of_work(&last_block, 0).unwrap();
+        blockchain.reward_miner();
+        blockchain.new_block(proof, &last_block.hash()).clone()
+    }
+
+    // A second node that shares the chain so far, as a peer whose blocks extend ours.
+    fn peer_of(blockchain: &Blockchain) -> Blockchain {
+        let mut peer = Blockchain::new(Config { node_identifier: "peer".to_string(), ..blockchain.config.clone() });
+        peer.chain = blockchain.chain.clone();
+        peer.hash_index = Blockchain::hash_index(&peer.chain);
+        peer
+    }
+
+    fn hashes(chain: &[Block]) -> Vec<String> {
+        chain.iter().map(Block::hash).collect()
+    }
+
+    // Hands out whatever time it was last set to, so that blocks get exactly the
+    // timestamps a test asks for.
+    #[derive(Debug)]
+    struct MockClock(Mutex<DateTime<Utc>>);
+
+    impl MockClock {
+        fn set(&self, time: DateTime<Utc>) {
+            *self.0.lock().unwrap() = time;
+        }
+    }
+
+    impl Clock for MockClock {
+        fn now(&self) -> DateTime<Utc> {
+            *self.0.lock().unwrap()
+        }
+    }
+
+    fn at(seconds: i64) -> DateTime<Utc> {
+        Utc.ymd(2020, 1, 1).and_hms(0, 0, 0) + chrono::Duration::seconds(seconds)
+    }
+
+    // A chain with a genesis block at 0s and one more block at each of `times`.
+    fn mine_at(config: Config, times: &[i64]) -> (Blockchain, Arc<MockClock>) {
+        let clock = Arc::new(MockClock(Mutex::new(at(0))));
+        let mut blockchain = Blockchain::with_clock(config, clock.clone());
+        for time in times {
+            clock.set(at(*time));
+            mine_block(&mut blockchain);
+        }
+        (blockchain, clock)
+    }
+
+    fn call_raw(blockchain: &web::Data<Mutex<Blockchain>>, request: test::TestRequest) -> (StatusCode, String) {
+        let mut app = test::init_service(App::new()
+            .register_data(blockchain.clone())
+            .data(json_config(false))
+            .configure(routes));
+        let response = test::call_service(&mut app, request.to_request());
+        let code = response.status();
+        (code, String::from_utf8(test::read_body(response).to_vec()).unwrap())
+    }
+
+    fn call(blockchain: &web::Data<Mutex<Blockchain>>, request: test::TestRequest) -> (StatusCode, serde_json::Value) {
+        let (code, body) = call_raw(blockchain, request);
+        (code, serde_json::from_str(&body).unwrap_or(serde_json::Value::Null))
+    }
+
+    fn get(blockchain: &web::Data<Mutex<Blockchain>>, path: &str) -> (StatusCode, serde_json::Value) {
+        call(blockchain, test::TestRequest::get().uri(path))
+    }
+
+    // Runs a node on its own HTTP server, as a peer for consensus tests, and
+    // returns its address.
+    fn serve(blockchain: Blockchain) -> (String, web::Data<Mutex<Blockchain>>) {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let base_path = blockchain.config.base_path.clone();
+        let blockchain = web::Data::new(Mutex::new(blockchain));
+        let served = blockchain.clone();
+        thread::spawn(move || {
+            HttpServer::new(move || {
+                let base_path = base_path.clone();
+                App::new()
+                    .register_data(served.clone())
+                    .data(json_config(false))
+                    .configure(move |config| mount(config, &base_path))
+            })
+            .listen(listener).unwrap()
+            .workers(1)
+            .disable_signals()
+            .run()
+        });
+        (address, blockchain)
+    }
+
+    #[test]
+    fn the_benchmark_times_each_difficulty_without_touching_the_chain() {
+        let blockchain = Blockchain::new(test_config());
+        let genesis = hashes(&blockchain.chain);
+        let timings = Blockchain::benchmark(2);
+        assert_eq!(timings.iter().map(|(difficulty, _)| *difficulty).collect::<Vec<_>>(), vec![1, 2]);
+        assert_eq!(hashes(&blockchain.chain), genesis);
+        let slow = [(1, Duration::from_millis(1)), (2, Duration::from_secs(20))];
+        assert_eq!(Blockchain::recommend_difficulty(&slow, 10), 2);
+        let fast = [(1, Duration::from_secs(1))];
+        assert_eq!(Blockchain::recommend_difficulty(&fast, 20), 2);
+    }
+
+    #[test]
+    fn miner_blocks_lists_only_the_blocks_paying_that_miner() {
+        let mut blockchain = Blockchain::new(test_config());
+        for miner in &["alice", "bob", "alice", "bob", "bob"] {
+            blockchain.config.node_identifier = miner.to_string();
+            mine_block(&mut blockchain);
+        }
+        let expected: Vec<_> = [3, 5, 6].iter().map(|index| serde_json::json!({ "index": index, "hash": blockchain.chain[index - 1].hash() })).collect();
+        let blockchain = web::Data::new(Mutex::new(blockchain));
+        let (code, body) = get(&blockchain, "/miner/bob/blocks");
+        assert_eq!(code, StatusCode::OK);
+        assert_eq!(body["address"], "bob");
+        assert_eq!(body["blocks"], serde_json::json!(expected));
+    }
+
+    #[test]
+    fn logged_chain_errors_redact_addresses() {
+        let overdraft = BlockchainError::Overdraft { index: 2, sender: "alice".to_string() };
+        let blockchain = Blockchain::new(Config { redact_logs: true, ..test_config() });
+        assert_eq!(blockchain.log_error(&overdraft), format!("a transaction in block 2 spends more than {} has", REDACTED));
+        assert!(Blockchain::new(test_config()).log_error(&overdraft).contains("alice"));
+    }
+
+    #[test]
+    fn logged_transactions_redact_addresses() {
+        let transaction = Transaction::new("alice", "bob", 1.0, 0.0);
+        let redacted = Blockchain::new(Config { redact_logs: true, ..test_config() }).transaction_log_line("new transaction", &transaction);
+        assert!(!redacted.contains("alice") && !redacted.contains("bob"), "{}", redacted);
+        assert!(redacted.contains(REDACTED));
+        let plain = Blockchain::new(test_config()).transaction_log_line("new transaction", &transaction);
+        assert_eq!(plain, "new transaction: alice -> bob (1)");
+    }
+
+    #[test]
+    fn a_received_block_drops_pending_transactions_it_makes_unaffordable() {
+        let mut blockchain = Blockchain::new(test_config());
+        let reward = mine_block(&mut blockchain).coinbase().unwrap().amount;
+        let miner = blockchain.config.node_identifier.clone();
+        let mut peer = peer_of(&blockchain);
+        blockchain.new_transaction(Transaction::new(&miner, "bob", reward, 0.0)).unwrap();
+        peer.new_transaction(Transaction::new(&miner, "carol", reward, 0.0)).unwrap();
+        let block = mine_block(&mut peer);
+        assert!(matches!(blockchain.receive_block(block), Ok(Received::Accepted)));
+        assert!(blockchain.current_transactions.is_empty());
+        assert_eq!(Blockchain::balances(&blockchain.chain).get("carol"), Some(&reward));
+    }
+
+    #[test]
+    fn block_sizes_count_the_transactions_of_each_block() {
+        let mut blockchain = Blockchain::new(test_config());
+        let miner = blockchain.config.node_identifier.clone();
+        for (block, transfers) in [0, 2, 1].iter().enumerate() {
+            for n in 0..*transfers {
+                blockchain.new_transaction(Transaction::new(&miner, &format!("payee-{}-{}", block, n), 0.25, 0.0)).unwrap();
+            }
+            mine_block(&mut blockchain);
+        }
+        let blockchain = web::Data::new(Mutex::new(blockchain));
+        let (code, body) = get(&blockchain, "/stats/block-sizes");
+        assert_eq!(code, StatusCode::OK);
+        let counts: Vec<_> = body["items"].as_array().unwrap().iter().map(|item| item["transaction_count"].as_u64().unwrap()).collect();
+        assert_eq!(counts, vec![0, 1, 3, 2]);
+        let (_, page) = get(&blockchain, "/stats/block-sizes?offset=2&limit=1");
+        assert_eq!(page["items"][0]["index"], 3);
+        assert_eq!(page["total"], 4);
+    }
+
+    #[test]
+    fn the_standalone_verifiers_accept_mined_blocks_and_reject_tampered_ones() {
+        let mut blockchain = Blockchain::new(test_config());
+        let block = mine_block(&mut blockchain);
+        let genesis = blockchain.chain[0].clone();
+        let bits = blockchain.config.required_bits();
+        assert!(verify_proof(genesis.proof, block.proof, &genesis.hash(), block.bits()));
+        assert!(verify_block(&block, &genesis, bits).is_ok());
+        let wrong_proof = (block.proof..).find(|proof| !verify_proof(genesis.proof, *proof, &genesis.hash(), block.bits())).unwrap();
+        let forged = Block { proof: wrong_proof, ..block.clone() };
+        assert!(matches!(verify_block(&forged, &genesis, bits), Err(BlockchainError::InvalidProof { index: 2 })));
+        let unlinked = Block { previous_hash: block.hash(), ..block.clone() };
+        assert!(matches!(verify_block(&unlinked, &genesis, bits), Err(BlockchainError::BrokenLink { index: 2 })));
+        assert!(matches!(verify_block(&genesis, &block, bits), Err(BlockchainError::IndexMismatch { index: 1, expected: 3 })));
+    }
+
+    #[test]
+    fn a_second_resolve_within_the_interval_is_served_from_the_cache() {
+        let mut peer = Blockchain::new(test_config());
+        mine_block(&mut peer);
+        let (address, peer) = serve(peer);
+        let mut blockchain = Blockchain::new(Config { consensus_interval: 60, ..test_config() });
+        blockchain.register_node(&format!("http://{}", address));
+        let blockchain = web::Data::new(Mutex::new(blockchain));
+        let (_, first) = get(&blockchain, "/nodes/resolve");
+        assert_eq!(first["cached"], false);
+        mine_block(&mut peer.lock().unwrap());
+        let (code, second) = get(&blockchain, "/nodes/resolve");
+        assert_eq!(code, StatusCode::OK);
+        assert_eq!(second["cached"], true);
+        assert_eq!(second["message"], first["message"]);
+        let local_blockchain = blockchain.lock().unwrap();
+        assert_eq!(local_blockchain.chain.len(), 2);
+        assert_eq!(local_blockchain.peer_stats[&address].reputation, 1);
+    }
+
+    #[test]
+    fn hashing_the_preimage_reproduces_the_block_hash() {
+        let mut blockchain = Blockchain::new(test_config());
+        let block = mine_block(&mut blockchain);
+        let blockchain = web::Data::new(Mutex::new(blockchain));
+        let (code, body) = get(&blockchain, "/block/2/preimage");
+        assert_eq!(code, StatusCode::OK);
+        let preimage = body["preimage"].as_str().unwrap();
+        assert_eq!(format!("{:x}", Sha256::new().chain(preimage).result()), block.hash());
+        assert_eq!(body["hash"], block.hash());
+        let hex: String = preimage.bytes().map(|byte| format!("{:02x}", byte)).collect();
+        assert_eq!(body["preimage_hex"], hex);
+    }
+
+    #[test]
+    fn a_fresh_node_adopts_a_peers_chain_on_first_consensus() {
+        let (peer, _) = mine_at(test_config(), &[]);
+        let (address, _peer) = serve(peer);
+        let mut blockchain = Blockchain::new(Config { consensus_interval: 0, ..test_config() });
+        blockchain.register_node(&format!("http://{}", address));
+        // As good as ours, which only counts while bootstrapping.
+        assert!(blockchain.resolve_conflicts().replaced);
+        assert!(blockchain.bootstrapped);
+        let (longer, _) = mine_at(test_config(), &[10, 20, 30]);
+        let expected = hashes(&longer.chain);
+        let (address, _longer) = serve(longer);
+        let mut fresh = Blockchain::new(test_config());
+        fresh.register_node(&format!("http://{}", address));
+        let result = fresh.resolve_conflicts();
+        assert!(result.replaced, "{}", result.message);
+        assert_eq!(hashes(&fresh.chain), expected);
+    }
+
+    #[test]
+    fn a_chain_repeating_a_block_is_rejected() {
+        let mut blockchain = Blockchain::new(test_config());
+        let block = mine_block(&mut blockchain);
+        let mut chain = blockchain.chain.clone();
+        assert!(blockchain.valid_chain(&chain));
+        chain.push(block);
+        assert!(Blockchain::check_chain(&chain, &blockchain.config).is_err());
+        assert!(!blockchain.valid_chain(&chain));
+    }
+
+    #[test]
+    fn rolling_back_returns_the_removed_transactions_to_the_mempool() {
+        let mut blockchain = Blockchain::new(Config { admin_key: Some("key".to_string()), ..test_config() });
+        let miner = blockchain.config.node_identifier.clone();
+        mine_block(&mut blockchain);
+        mine_block(&mut blockchain);
+        let transfers = vec![Transaction::new(&miner, "bob", 0.25, 0.0), Transaction::new(&miner, "carol", 0.5, 0.0)];
+        for transfer in &transfers {
+            blockchain.new_transaction(transfer.clone()).unwrap();
+            mine_block(&mut blockchain);
+        }
+        let tip = blockchain.chain[2].hash();
+        let blockchain = web::Data::new(Mutex::new(blockchain));
+        let request = || test::TestRequest::post().uri("/chain/rollback").set_json(&serde_json::json!({ "blocks": 2 }));
+        assert_eq!(call(&blockchain, request()).0, StatusCode::UNAUTHORIZED);
+        let (code, body) = call(&blockchain, request().header(ADMIN_KEY_HEADER, "key"));
+        assert_eq!(code, StatusCode::OK);
+        assert_eq!(body["removed"], 2);
+        assert_eq!(body["restored_transactions"], 2);
+        assert_eq!(body["tip_index"], 3);
+        assert_eq!(body["tip_hash"], tip);
+        let local_blockchain = blockchain.lock().unwrap();
+        assert_eq!(local_blockchain.chain.len(), 3);
+        assert_eq!(local_blockchain.current_transactions, transfers);
+    }
+
+    #[test]
+    fn strict_nodes_reject_bodies_not_sent_as_json() {
+        let blockchain = web::Data::new(Mutex::new(Blockchain::new(test_config())));
+        let mut app = test::init_service(App::new()
+            .register_data(blockchain.clone())
+            .data(json_config(true))
+            .configure(routes));
+        let body = serde_json::to_string(&Transaction::new("alice", "bob", 1.0, 0.0)).unwrap();
+        let request = test::TestRequest::post().uri("/transactions/new")
+            .header("Content-Type", "text/plain")
+            .set_payload(body.clone());
+        let response = test::call_service(&mut app, request.to_request());
+        assert_eq!(response.status(), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+        let message: Response = serde_json::from_slice(&test::read_body(response)).unwrap();
+        assert!(message.message.contains("Content-Type: application/json"));
+        // The lenient default still parses it, and fails on its merits instead.
+        let (code, _) = call(&blockchain, test::TestRequest::post().uri("/transactions/new")
+            .header("Content-Type", "text/plain")
+            .set_payload(body));
+        assert_eq!(code, StatusCode::BAD_REQUEST);
+    }
+
+    #[test]
+    fn harder_chains_report_more_accumulated_work() {
+        let mut easy = Blockchain::new(test_config());
+        let mut hard = Blockchain::new(Config { difficulty: 2, ..test_config() });
+        for _ in 0..2 {
+            mine_block(&mut easy);
+            mine_block(&mut hard);
+        }
+        assert_eq!(easy.chain.len(), hard.chain.len());
+        let work = |blockchain: Blockchain| {
+            let (code, body) = get(&web::Data::new(Mutex::new(blockchain)), "/chain/work");
+            assert_eq!(code, StatusCode::OK);
+            assert_eq!(body["length"], 3);
+            body["work"].as_u64().unwrap()
+        };
+        assert!(work(hard) > work(easy));
+    }
+
+    #[test]
+    fn dry_run_previews_the_reorg_without_rating_peers() {
+        let mut peer = Blockchain::new(test_config());
+        mine_block(&mut peer);
+        mine_block(&mut peer);
+        let (address, _peer) = serve(peer);
+        let mut blockchain = Blockchain::new(test_config());
+        blockchain.register_node(&format!("http://{}", address));
+        let dry_run = blockchain.resolve_dry_run();
+        assert!(dry_run.would_replace);
+        assert_eq!((dry_run.rolled_back, dry_run.added), (1, 3));
+        assert!(blockchain.peer_stats.is_empty());
+        assert_eq!(blockchain.chain.len(), 1);
+        assert!(blockchain.resolve_conflicts().replaced);
+        assert_eq!(blockchain.chain.len(), 3);
+        assert_eq!(blockchain.peer_stats[&address].reputation, 1);
+    }
+
+    #[test]
+    fn amounts_too_large_for_a_float_are_rejected_cleanly() {
+        let mut blockchain = Blockchain::new(test_config());
+        let miner = blockchain.config.node_identifier.clone();
+        mine_block(&mut blockchain);
+        let before = Blockchain::balances(&blockchain.chain);
+        let blockchain = web::Data::new(Mutex::new(blockchain));
+        // Past an f64 the JSON itself is refused; past an f32 it parses to infinity.
+        let cases = [("1e400", "number out of range".to_string()), ("3.5e38", BlockchainError::NonFiniteAmount.to_string())];
+        for (amount, message) in &cases {
+            let body = format!(r#"{{"sender": "{}", "recipient": "bob", "amount": {}}}"#, miner, amount);
+            let (code, response) = call(&blockchain, test::TestRequest::post().uri("/transactions/new")
+                .header("Content-Type", "application/json")
+                .set_payload(body));
+            assert_eq!(code, StatusCode::BAD_REQUEST, "{}", response);
+            assert!(response["message"].as_str().unwrap().contains(message.as_str()), "{}", response);
+        }
+        let local_blockchain = blockchain.lock().unwrap();
+        assert!(local_blockchain.current_transactions.is_empty());
+        assert_eq!(Blockchain::balances(&local_blockchain.chain), before);
+    }
+
+    #[test]
+    fn the_audit_attributes_minted_coins_to_each_miner() {
+        let mut blockchain = Blockchain::new(test_config());
+        blockchain.config.node_identifier = "alice".to_string();
+        mine_block(&mut blockchain);
+        mine_block(&mut blockchain);
+        // A node paying itself a different reward than the schedule can't get its block accepted.
+        let mut greedy = peer_of(&blockchain);
+        greedy.config.mining_reward = 5.0;
+        let block = mine_block(&mut greedy);
+        assert!(matches!(blockchain.receive_block(block), Err(BlockchainError::UnexpectedReward { index: 4, .. })));
+        blockchain.update_reward_schedule(&RewardScheduleUpdate { base_reward: Some(2.5), halving_interval: None, supply_cap: None }).unwrap();
+        blockchain.config.node_identifier = "bob".to_string();
+        mine_block(&mut blockchain);
+        let blockchain = web::Data::new(Mutex::new(blockchain));
+        let (code, body) = get(&blockchain, "/audit");
+        assert_eq!(code, StatusCode::OK);
+        assert_eq!(body["minted_by_node"], serde_json::json!({ "alice": 2.0, "bob": 2.5 }));
+        assert_eq!(body["total_supply"], 4.5);
+    }
+
+    #[test]
+    fn the_tail_is_the_last_n_blocks() {
+        let mut blockchain = Blockchain::new(test_config());
+        for _ in 0..4 {
+            mine_block(&mut blockchain);
+        }
+        let blockchain = web::Data::new(Mutex::new(blockchain));
+        let (code, body) = get(&blockchain, "/chain/tail/3");
+        assert_eq!(code, StatusCode::OK);
+        let indexes: Vec<_> = body["items"].as_array().unwrap().iter().map(|block| block["index"].as_u64().unwrap()).collect();
+        assert_eq!(indexes, vec![3, 4, 5]);
+        assert_eq!(get(&blockchain, "/chain/tail/10").1["items"].as_array().unwrap().len(), 5);
+    }
+
+    #[test]
+    fn block_by_index_is_served_from_memory() {
+        let path = temp_path("block-by-index");
+        let mut blockchain = Blockchain::new(Config { storage_path: Some(path.clone()), ..test_config() });
+        blockchain.attach_storage().unwrap();
+        let block = mine_block(&mut blockchain);
+        fs::remove_file(&path).unwrap();
+        let blockchain = web::Data::new(Mutex::new(blockchain));
+        let (code, body) = get(&blockchain, "/block/2");
+        assert_eq!(code, StatusCode::OK);
+        assert_eq!(body["proof"], block.proof);
+        assert_eq!(get(&blockchain, "/block/3").0, StatusCode::NOT_FOUND);
+    }
+
+    #[test]
+    fn batched_saves_still_serve_unsaved_blocks() {
+        let path = temp_path("save-interval");
+        let mut blockchain = Blockchain::new(Config { storage_path: Some(path.clone()), save_interval: Some(60), ..test_config() });
+        blockchain.attach_storage().unwrap();
+        blockchain.save_if_unsaved();
+        let block = mine_block(&mut blockchain);
+        assert!(blockchain.unsaved);
+        assert_eq!(FileStore::new(&path).load().unwrap().unwrap().chain.len(), 1);
+        let blockchain = web::Data::new(Mutex::new(blockchain));
+        let (code, body) = get(&blockchain, "/block/2");
+        assert_eq!(code, StatusCode::OK);
+        assert_eq!(body["proof"], block.proof);
+        blockchain.lock().unwrap().save_if_unsaved();
+        assert_eq!(FileStore::new(&path).load().unwrap().unwrap().chain.len(), 2);
+    }
+
+    #[test]
+    fn file_store_round_trips_the_chain() {
+        let path = temp_path("file-store");
+        let mut blockchain = Blockchain::new(Config { storage_path: Some(path.clone()), ..test_config() });
+        blockchain.attach_storage().unwrap();
+        mine_block(&mut blockchain);
+        let mut restored = Blockchain::new(Config { storage_path: Some(path), ..test_config() });
+        restored.attach_storage().unwrap();
+        assert_eq!(hashes(&restored.chain), hashes(&blockchain.chain));
+    }
+
+    fn args(line: &str) -> Vec<String> {
+        line.split_whitespace().map(String::from).collect()
+    }
+
+    #[test]
+    fn bad_command_line_options_are_errors_rather_than_panics() {
+        let config = Config::from_args(&args("blockchain 5001 --difficulty 2 --log-level debug")).unwrap();
+        assert_eq!((config.port.as_str(), config.difficulty, config.log_level), ("5001", 2, LogLevel::Debug));
+        assert_eq!(Config::from_args(&args("blockchain --log-level error")).unwrap().log_level, LogLevel::Error);
+        assert_eq!(Config::from_args(&args("blockchain 5001 --difficulty two")).unwrap_err(),
+            "invalid value two for --difficulty: invalid digit found in string");
+        assert_eq!(Config::from_args(&args("blockchain 5001 --difficulty")).unwrap_err(), "missing value for --difficulty");
+        assert_eq!(Config::from_args(&args("blockchain --storage-backend tape")).unwrap_err(), "unknown storage backend tape");
+        assert_eq!(Config::from_args(&args("blockchain --colour blue")).unwrap_err(), "unknown option --colour");
+    }
+
+    #[test]
+    fn conservation_holds_until_a_block_overdraws_an_account() {
+        let mut blockchain = Blockchain::new(test_config());
+        mine_block(&mut blockchain);
+        let miner = blockchain.config.node_identifier.clone();
+        blockchain.new_transaction(Transaction::new(&miner, "alice", 0.25, 0.0)).unwrap();
+        mine_block(&mut blockchain);
+        let blockchain = web::Data::new(Mutex::new(blockchain));
+        let (code, body) = get(&blockchain, "/chain/verify-conservation");
+        assert_eq!(code, StatusCode::OK);
+        assert_eq!(body["conserved"], true);
+        assert_eq!((body["blocks_checked"].as_u64(), body["total_minted"].as_f64()), (Some(3), Some(2.0)));
+        assert_eq!(body["violation"], serde_json::Value::Null);
+        {
+            // Mined without going through new_transaction, as a faulty node might.
+            let mut local_blockchain = blockchain.lock().unwrap();
+            local_blockchain.current_transactions.push(Transaction::new("alice", "bob", 0.75, 0.0));
+            mine_block(&mut local_blockchain);
+            mine_block(&mut local_blockchain);
+        }
+        let body = get(&blockchain, "/chain/verify-conservation").1;
+        assert_eq!(body["conserved"], false);
+        assert_eq!(body["blocks_checked"], 4);
+        assert_eq!(body["violation"], serde_json::json!({ "index": 4, "reason": "alice goes negative at -0.5" }));
+    }
+
+    #[test]
+    fn proofs_commit_to_the_merkle_root_of_the_template() {
+        let blockchain = web::Data::new(Mutex::new(Blockchain::new(Config { merkle_root: true, ..test_config() })));
+        let template: BlockTemplate = serde_json::from_value(get(&blockchain, "/mine/preview?miner=miner").1).unwrap();
+        let target = proof_target(&template.previous_hash, template.merkle_root.as_deref());
+        let proof = (0..).find(|proof| verify_proof(template.last_proof, *proof, &target, template.bits)).unwrap();
+        let submission = BlockSubmission { previous_hash: template.previous_hash, proof, bits: template.bits, transactions: template.transactions };
+        let (code, body) = call(&blockchain, test::TestRequest::post().uri("/mining/submit").set_json(&submission));
+        assert_eq!(code, StatusCode::OK, "{}", body);
+        let (code, body) = get(&blockchain, "/mine");
+        assert_eq!(code, StatusCode::OK, "{}", body);
+        assert!(blockchain.lock().unwrap().chain.iter().skip(1).all(|block| block.merkle_root.is_some()));
+    }
+
+    #[test]
+    fn coins_sent_to_the_burn_address_leave_the_supply() {
+        let mut blockchain = Blockchain::new(test_config());
+        mine_block(&mut blockchain);
+        let miner = blockchain.config.node_identifier.clone();
+        let blockchain = web::Data::new(Mutex::new(blockchain));
+        let burn = Transaction::new(&miner, BURN_ADDRESS, 0.25, 0.0);
+        let submit = || call(&blockchain, test::TestRequest::post().uri("/transactions/new").set_json(&burn));
+        assert_eq!(submit().0, StatusCode::OK);
+        assert_eq!(get(&blockchain, "/mine").0, StatusCode::OK);
+        let body = get(&blockchain, "/audit").1;
+        assert_eq!((body["total_minted"].as_f64(), body["total_burned"].as_f64()), (Some(2.0), Some(0.25)));
+        assert_eq!(body["total_supply"], 1.75);
+        let body = call(&blockchain, test::TestRequest::post().uri("/balances")
+            .set_json(&serde_json::json!({ "addresses": [miner, BURN_ADDRESS] }))).1;
+        assert_eq!(body, serde_json::json!({ miner: 1.75, BURN_ADDRESS: 0.0 }));
+        blockchain.lock().unwrap().config.allow_burns = false;
+        let (code, body) = submit();
+        assert_eq!(code, StatusCode::BAD_REQUEST);
+        assert_eq!(body["message"], BlockchainError::BurnsDisabled.to_string());
+    }
+
+    #[test]
+    fn cursors_see_every_block_once_while_mining_continues() {
+        let mut blockchain = Blockchain::new(test_config());
+        mine_block(&mut blockchain);
+        mine_block(&mut blockchain);
+        let blockchain = web::Data::new(Mutex::new(blockchain));
+        let (mut seen, mut cursor) = (Vec::new(), None);
+        loop {
+            let path = match &cursor {
+                Some(cursor) => format!("/chain/blocks?limit=2&cursor={}", cursor),
+                None => "/chain/blocks?limit=2".to_string()
+            };
+            let (code, body) = get(&blockchain, &path);
+            assert_eq!(code, StatusCode::OK, "{}", body);
+            let page: Vec<Block> = serde_json::from_value(body["blocks"].clone()).unwrap();
+            if page.is_empty() {
+                break
+            }
+            seen.extend(page);
+            cursor = Some(body["next_cursor"].as_str().unwrap().to_string());
+            let mut local_blockchain = blockchain.lock().unwrap();
+            if local_blockchain.chain.len() < 6 {
+                mine_block(&mut local_blockchain);
+            }
+        }
+        assert_eq!(hashes(&seen), hashes(&blockchain.lock().unwrap().chain));
+        assert_eq!(seen.len(), 6);
+    }
+
+    #[test]
+    fn transactions_need_the_configured_work_to_be_accepted() {
+        let mut blockchain = Blockchain::new(Config { transaction_work_bits: 8, ..test_config() });
+        mine_block(&mut blockchain);
+        let miner = blockchain.config.node_identifier.clone();
+        let blockchain = web::Data::new(Mutex::new(blockchain));
+        assert_eq!(get(&blockchain, "/mempool/policy").1["transaction_work_bits"], 8);
+        let with_work = |work| Transaction { work: Some(work), ..Transaction::new(&miner, "alice", 0.25, 0.0) };
+        let submit = |transaction: &Transaction| call(&blockchain, test::TestRequest::post().uri("/transactions/new").set_json(transaction));
+        let lazy = (0..).map(with_work).find(|transaction| transaction.work_bits() < 8).unwrap();
+        let (code, body) = submit(&lazy);
+        assert_eq!(code, StatusCode::BAD_REQUEST);
+        assert_eq!(body["message"], BlockchainError::InsufficientWork { bits: lazy.work_bits(), required: 8 }.to_string());
+        let worked = (0..).map(with_work).find(|transaction| transaction.work_bits() >= 8).unwrap();
+        let (code, body) = submit(&worked);
+        assert_eq!(code, StatusCode::OK, "{}", body);
+        assert_eq!(blockchain.lock().unwrap().current_transactions, vec![worked]);
+    }
+
+    #[test]
+    fn equivalent_peer_addresses_register_once() {
+        let mut blockchain = Blockchain::new(test_config());
+        assert!(blockchain.register_node("http://peer"));
+        for node in &["HTTP://Peer:80", "http://PEER/", "http://peer.:80/"] {
+            assert!(!blockchain.register_node(node), "{} registered twice", node);
+        }
+        assert_eq!(blockchain.nodes.len(), 1);
+    }
+
+    #[test]
+    fn registering_an_address_without_a_scheme_is_rejected() {
+        let blockchain = web::Data::new(Mutex::new(Blockchain::new(test_config())));
+        let peers = NodeList { nodes: vec!["http://peer:5000".to_string(), "127.0.0.1:5000".to_string()] };
+        let (code, _) = call(&blockchain, test::TestRequest::post().uri("/nodes/register").set_json(&peers));
+        assert_eq!(code, StatusCode::BAD_REQUEST);
+        assert!(blockchain.lock().unwrap().nodes.is_empty());
+        assert!(!blockchain.lock().unwrap().register_node("127.0.0.1:5000"));
+    }
+
+    #[test]
+    fn the_precomputed_txid_is_the_id_assigned_on_submission() {
+        let mut blockchain = Blockchain::new(test_config());
+        mine_block(&mut blockchain);
+        let miner = blockchain.config.node_identifier.clone();
+        let blockchain = web::Data::new(Mutex::new(blockchain));
+        // Not yet in canonical form, so the id is only right if both normalize the same way.
+        let transaction = Transaction::new(&miner, "cafe\u{301}", 0.25, 0.1);
+        let (code, precomputed) = call(&blockchain, test::TestRequest::post().uri("/util/txid").set_json(&transaction));
+        assert_eq!(code, StatusCode::OK);
+        let (code, submitted) = call(&blockchain, test::TestRequest::post().uri("/transactions/new").set_json(&transaction));
+        assert_eq!(code, StatusCode::OK, "{}", submitted);
+        assert_eq!(precomputed["id"], submitted["id"]);
+        assert_eq!(precomputed["id"], blockchain.lock().unwrap().current_transactions[0].id());
+    }
+
+    #[test]
+    fn resolve_reports_a_networking_failure_when_no_peer_resolves() {
+        let mut blockchain = Blockchain::new(test_config());
+        blockchain.register_node("http://peer.invalid:5000");
+        let blockchain = web::Data::new(Mutex::new(blockchain));
+        let (code, body) = get(&blockchain, "/nodes/resolve");
+        assert_eq!(code, StatusCode::SERVICE_UNAVAILABLE);
+        assert!(body["network_error"].as_str().unwrap().contains("none of the 1 peers could be reached"));
+    }
+
+    #[test]
+    fn consensus_gives_up_on_a_peer_that_never_answers() {
+        let silent = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut blockchain = Blockchain::new(test_config());
+        blockchain.register_node(&format!("http://{}", silent.local_addr().unwrap()));
+        let started = Instant::now();
+        let result = blockchain.resolve_conflicts();
+        assert!(result.network_error.is_some());
+        assert!(started.elapsed() < PEER_TIMEOUT * 3);
+    }
+
+    #[test]
+    fn a_time_range_returns_exactly_the_blocks_inside_it() {
+        let (blockchain, _) = mine_at(test_config(), &[10, 20, 30, 40]);
+        let blockchain = web::Data::new(Mutex::new(blockchain));
+        let range = |from: i64, to: i64| {
+            let path = format!("/blocks/by-time?from={}&to={}", at(from).to_rfc3339(), at(to).to_rfc3339()).replace('+', "%2B");
+            let (code, body) = get(&blockchain, &path);
+            assert_eq!(code, StatusCode::OK, "{}", body);
+            serde_json::from_value::<Vec<Block>>(body).unwrap().iter().map(|block| block.index).collect::<Vec<_>>()
+        };
+        assert_eq!(range(15, 35), vec![3, 4]);
+        assert_eq!(range(20, 30), vec![3, 4]);
+        assert_eq!(range(0, 100), vec![1, 2, 3, 4, 5]);
+        assert_eq!(range(41, 100), Vec::<usize>::new());
+        assert_eq!(get(&blockchain, "/blocks/by-time?from=yesterday&to=today").0, StatusCode::BAD_REQUEST);
+    }
+
+    #[test]
+    fn mining_threads_take_the_configured_niceness() {
+        // Mines on a thread of its own, so the niceness doesn't stick to a test thread.
+        let mined = thread::spawn(|| {
+            let mut blockchain = Blockchain::new(Config { mining_niceness: Some(19), ..test_config() });
+            mine_block(&mut blockchain);
+            #[cfg(target_os = "linux")]
+            assert_eq!(unsafe { libc::getpriority(libc::PRIO_PROCESS as _, libc::syscall(libc::SYS_gettid) as libc::id_t) }, 19);
+            blockchain.chain.len()
+        });
+        // Elsewhere the setting is only logged as ignored, and mining goes on regardless.
+        assert_eq!(mined.join().unwrap(), 2);
+        #[cfg(not(target_os = "linux"))]
+        assert!(set_thread_niceness(19).is_err());
+    }
+
+    #[test]
+    fn the_identity_key_is_stable_and_signs_the_nodes_blocks() {
+        let path = temp_path("node-key");
+        let signer = Arc::new(Signer::load_or_create(&path).unwrap());
+        let mut blockchain = Blockchain::new(Config { node_identifier: signer.address(), ..test_config() });
+        blockchain.signer = Some(signer);
+        let blockchain = web::Data::new(Mutex::new(blockchain));
+        let (code, node) = get(&blockchain, "/identity");
+        assert_eq!(code, StatusCode::OK);
+        assert_eq!(get(&blockchain, "/identity").1, node);
+        // The key is loaded again, not regenerated, when the node restarts.
+        assert_eq!(node["public_key"], Signer::load_or_create(&path).unwrap().public_key);
+        let fields: Vec<&String> = node.as_object().unwrap().keys().collect();
+        assert_eq!(fields, ["chain_id", "node_id", "public_key", "schema_versions", "signature_algorithm", "version"]);
+        assert_eq!(get(&blockchain, "/mine").0, StatusCode::OK);
+        let local_blockchain = blockchain.lock().unwrap();
+        let block = &local_blockchain.chain[1];
+        assert_eq!(block.miner_key.as_deref(), node["public_key"].as_str());
+        assert_eq!(key_address(block.miner_key.as_ref().unwrap()), node["node_id"]);
+        assert!(local_blockchain.valid_chain(&local_blockchain.chain));
+    }
+
+    #[test]
+    fn a_block_below_the_retargeted_difficulty_is_rejected() {
+        let mut blockchain = Blockchain::new(Config { mempool_difficulty: true, ..test_config() });
+        mine_block(&mut blockchain);
+        mine_block(&mut blockchain);
+        assert_eq!(blockchain.chain[2].difficulty, 3);
+        // A peer ignoring retargeting mines at the floor instead of within one step of 3.
+        let mut peer = peer_of(&blockchain);
+        peer.config.mempool_difficulty = false;
+        let easy = mine_block(&mut peer);
+        assert_eq!(easy.difficulty, 1);
+        assert!(!blockchain.valid_chain(&peer.chain));
+        let blockchain = web::Data::new(Mutex::new(blockchain));
+        let (code, body) = call(&blockchain, test::TestRequest::post().uri("/block/receive").set_json(&easy));
+        assert_eq!(code, StatusCode::BAD_REQUEST);
+        assert_eq!(body["message"], BlockchainError::UnexpectedDifficulty { index: 4, difficulty: 1, min: 2, max: 3 }.to_string());
+        assert_eq!(blockchain.lock().unwrap().chain.len(), 3);
+    }
+
+    #[test]
+    fn competing_and_early_blocks_are_listed_as_off_chain() {
+        let mut blockchain = Blockchain::new(test_config());
+        let mut rival = peer_of(&blockchain);
+        mine_block(&mut blockchain);
+        let competing = mine_block(&mut rival);
+        mine_block(&mut rival);
+        let early = mine_block(&mut rival);
+        let blockchain = web::Data::new(Mutex::new(blockchain));
+        let deliver = |block: &Block| call(&blockchain, test::TestRequest::post().uri("/block/receive").set_json(block)).0;
+        assert_eq!(deliver(&competing), StatusCode::BAD_REQUEST);
+        assert_eq!(deliver(&early), StatusCode::ACCEPTED);
+        let (code, body) = get(&blockchain, "/blocks/orphans");
+        assert_eq!(code, StatusCode::OK);
+        let listed: Vec<OffChainBlock> = serde_json::from_value(body).unwrap();
+        let summary: Vec<(String, String)> = listed.into_iter().map(|side| (side.hash, side.reason)).collect();
+        assert_eq!(summary, vec![
+            (early.hash(), format!("waiting for its parent {}", early.previous_hash)),
+            (competing.hash(), BlockchainError::DoesNotExtendTip { index: 2 }.to_string())
+        ]);
+        assert_eq!(blockchain.lock().unwrap().chain.len(), 2);
+    }
+
+    #[test]
+    fn mining_waits_for_the_minimum_number_of_transactions() {
+        let mut blockchain = Blockchain::new(test_config());
+        mine_block(&mut blockchain);
+        blockchain.config.min_block_transactions = 3;
+        let miner = blockchain.config.node_identifier.clone();
+        blockchain.new_transaction(Transaction::new(&miner, "alice", 0.25, 0.0)).unwrap();
+        blockchain.new_transaction(Transaction::new(&miner, "bob", 0.25, 0.0)).unwrap();
+        let blockchain = web::Data::new(Mutex::new(blockchain));
+        let (code, body) = get(&blockchain, "/mine");
+        assert_eq!(code, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(body["message"], "Waiting for transactions: 2 pending but a block needs at least 3");
+        assert_eq!(blockchain.lock().unwrap().chain.len(), 2);
+        blockchain.lock().unwrap().new_transaction(Transaction::new(&miner, "carol", 0.25, 0.0)).unwrap();
+        let (code, body) = get(&blockchain, "/mine");
+        assert_eq!(code, StatusCode::OK, "{}", body);
+        // The coinbase comes on top of the three transfers.
+        assert_eq!(body["transactions"].as_array().unwrap().len(), 4);
+    }
+
+    #[test]
+    fn size_estimates_match_the_sizes_the_node_measures() {
+        let mut blockchain = Blockchain::new(Config { min_fee_per_byte: 0.001, ..test_config() });
+        mine_block(&mut blockchain);
+        let miner = blockchain.config.node_identifier.clone();
+        let blockchain = web::Data::new(Mutex::new(blockchain));
+        let estimate = |body: &serde_json::Value| call(&blockchain, test::TestRequest::post().uri("/util/estimate-size").set_json(body)).1;
+        let transaction = Transaction::new(&miner, "cafe\u{301}", 0.25, 0.5);
+        let body = estimate(&serde_json::to_value(&transaction).unwrap());
+        assert_eq!(body["kind"], "transaction");
+        let (code, _) = call(&blockchain, test::TestRequest::post().uri("/transactions/new").set_json(&transaction));
+        assert_eq!(code, StatusCode::OK);
+        let size = blockchain.lock().unwrap().current_transactions[0].byte_size();
+        assert_eq!(body["size"], size);
+        assert_eq!(body["required_fee"].as_f64().map(|fee| fee as f32), Some(0.001 * size as f32));
+        assert_eq!(get(&blockchain, "/mine").0, StatusCode::OK);
+        let block = blockchain.lock().unwrap().chain[2].clone();
+        let body = estimate(&serde_json::to_value(&block).unwrap());
+        assert_eq!(body["kind"], "block");
+        assert_eq!(body["size"], get(&blockchain, "/stats/block-sizes").1["items"][2]["byte_size"]);
+    }
+
+    #[test]
+    fn the_self_test_passes_every_check_without_touching_the_chain() {
+        let mut blockchain = Blockchain::new(Config { admin_key: Some("key".to_string()), ..test_config() });
+        mine_block(&mut blockchain);
+        let before = hashes(&blockchain.chain);
+        let blockchain = web::Data::new(Mutex::new(blockchain));
+        let request = || test::TestRequest::post().uri("/selftest");
+        assert_eq!(call(&blockchain, request()).0, StatusCode::UNAUTHORIZED);
+        let (code, body) = call(&blockchain, request().header(ADMIN_KEY_HEADER, "key"));
+        assert_eq!(code, StatusCode::OK, "{}", body);
+        let result: SelfTest = serde_json::from_value(body).unwrap();
+        assert!(result.passed);
+        let names: Vec<&str> = result.checks.iter().map(|check| check.name.as_str()).collect();
+        assert_eq!(names, ["mine blocks", "submit transaction", "confirm transaction", "validate chain"]);
+        assert!(result.checks.iter().all(|check| check.passed && check.error.is_none() && check.elapsed_ms >= 0.0));
+        let elapsed: f64 = result.checks.iter().map(|check| check.elapsed_ms).sum();
+        assert!(result.total_ms >= elapsed && result.total_ms < 60_000.0, "{} ms", result.total_ms);
+        assert_eq!(hashes(&blockchain.lock().unwrap().chain), before);
+    }
+
+    #[test]
+    fn transactions_wait_until_the_node_has_synced() {
+        let (peer, _) = mine_at(test_config(), &[10]);
+        let payer = peer.config.node_identifier.clone();
+        let (address, _peer) = serve(peer);
+        let mut blockchain = Blockchain::new(Config { sync_on_start: true, consensus_interval: 0, ..test_config() });
+        blockchain.register_node(&format!("http://{}", address));
+        let blockchain = web::Data::new(Mutex::new(blockchain));
+        let transaction = Transaction::new(&payer, "alice", 0.25, 0.0);
+        let submit = || test::TestRequest::post().uri("/transactions/new").set_json(&transaction);
+        let mut app = test::init_service(App::new()
+            .register_data(blockchain.clone())
+            .data(json_config(false))
+            .configure(routes));
+        let response = test::call_service(&mut app, submit().to_request());
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(response.headers().get("Retry-After").unwrap(), &SYNC_RETRY_AFTER.to_string());
+        assert_eq!(get(&blockchain, "/status").1["sync_state"], "syncing");
+        assert_eq!(get(&blockchain, "/ready"), (StatusCode::SERVICE_UNAVAILABLE, serde_json::json!({ "message": "syncing" })));
+        assert!(blockchain.lock().unwrap().resolve_conflicts().replaced);
+        assert_eq!(get(&blockchain, "/status").1["sync_state"], "synced");
+        assert_eq!(get(&blockchain, "/ready").0, StatusCode::OK);
+        let (code, body) = call(&blockchain, submit());
+        assert_eq!(code, StatusCode::OK, "{}", body);
+    }
+
+    #[test]
+    fn reindexing_repairs_a_drifted_hash_index() {
+        let mut blockchain = Blockchain::new(Config { admin_key: Some("key".to_string()), ..test_config() });
+        mine_block(&mut blockchain);
+        mine_block(&mut blockchain);
+        let (middle, tip) = (blockchain.chain[1].hash(), blockchain.chain[2].hash());
+        // One entry points at the wrong block, one is missing and one names no block at all.
+        blockchain.hash_index.insert(middle.clone(), 2);
+        blockchain.hash_index.remove(&tip);
+        blockchain.hash_index.insert("not-a-block".to_string(), 0);
+        let blockchain = web::Data::new(Mutex::new(blockchain));
+        let at_hash = |hash: &str| get(&blockchain, &format!("/chain/at-hash/{}", hash));
+        assert_eq!(at_hash(&middle).1["length"], 3);
+        assert_eq!(at_hash(&tip).0, StatusCode::NOT_FOUND);
+        let (code, body) = call(&blockchain, test::TestRequest::post().uri("/admin/reindex").header(ADMIN_KEY_HEADER, "key"));
+        assert_eq!(code, StatusCode::OK);
+        assert_eq!(body, serde_json::json!({ "hash_index_entries": 3, "stale_entries": 3, "safe_mode": false }));
+        assert_eq!(at_hash(&middle).1["length"], 2);
+        assert_eq!(at_hash(&tip).1["length"], 3);
+        assert_eq!(at_hash("not-a-block").0, StatusCode::NOT_FOUND);
+    }
+
+    #[test]
+    fn concurrent_mines_each_extend_the_tip() {
+        let blockchain = web::Data::new(Mutex::new(Blockchain::new(test_config())));
+        let miners: Vec<_> = (0..2).map(|_| {
+            let blockchain = blockchain.clone();
+            thread::spawn(move || get(&blockchain, "/mine"))
+        }).collect();
+        for miner in miners {
+            assert_eq!(miner.join().unwrap().0, StatusCode::OK);
+        }
+        let local_blockchain = blockchain.lock().unwrap();
+        assert_eq!(local_blockchain.chain.len(), 3);
+        for pair in local_blockchain.chain.windows(2) {
+            assert_eq!(pair[1].index, pair[0].index + 1);
+            assert_eq!(pair[1].previous_hash, pair[0].hash());
+        }
+        assert!(local_blockchain.valid_chain(&local_blockchain.chain));
+    }
+
+    // Our chain of two blocks, and peers offering: genesis only, one more block at
+    // difficulty 3, and two more blocks at difficulty 1.
+    fn crafted_peer_chains() -> (Vec<Block>, Vec<PeerChain>) {
+        let mut blockchain = Blockchain::new(test_config());
+        let short = blockchain.chain.clone();
+        mine_block(&mut blockchain);
+        let mut hard = peer_of(&blockchain);
+        hard.config.difficulty = 3;
+        mine_block(&mut hard);
+        let mut long = peer_of(&blockchain);
+        mine_block(&mut long);
+        mine_block(&mut long);
+        let peer = |node: &str, chain: Vec<Block>| PeerChain { node: node.to_string(), chain };
+        (blockchain.chain, vec![peer("short", short), peer("hard", hard.chain), peer("long", long.chain)])
+    }
+
+    #[test]
+    fn longest_chain_ranks_longer_chains_first() {
+        let (local, mut peers) = crafted_peer_chains();
+        assert_eq!(LongestChain.rank(&local, &peers, false), vec![2, 1]);
+        peers.push(PeerChain { node: "same".to_string(), chain: local.clone() });
+        assert_eq!(LongestChain.rank(&local, &peers, false), vec![2, 1]);
+        assert_eq!(LongestChain.rank(&local, &peers, true), vec![2, 1, 3]);
+    }
+
+    #[test]
+    fn most_work_prefers_a_harder_chain_over_a_longer_one() {
+        let (local, peers) = crafted_peer_chains();
+        assert!(Blockchain::accumulated_work(&peers[1].chain) > Blockchain::accumulated_work(&peers[2].chain));
+        assert_eq!(MostWork.rank(&local, &peers, false), vec![1, 2]);
+        assert_eq!(MostWork.rank(&local, &peers[..1], true), Vec::<usize>::new());
+    }
+
+    #[test]
+    fn quorum_only_ranks_tips_most_peers_agree_on() {
+        let (local, mut peers) = crafted_peer_chains();
+        assert_eq!(Quorum.rank(&local, &peers, false), Vec::<usize>::new());
+        let agreeing = PeerChain { node: "agreeing".to_string(), chain: peers[2].chain.clone() };
+        peers.push(agreeing);
+        assert_eq!(Quorum.rank(&local, &peers, false), Vec::<usize>::new());
+        peers.remove(0);
+        assert_eq!(Quorum.rank(&local, &peers, false), vec![1, 2]);
+    }
+
+    #[test]
+    fn the_difficulty_history_shows_the_change_at_its_height() {
+        let (mut blockchain, clock) = mine_at(test_config(), &[10, 20]);
+        blockchain.config.difficulty = 2;
+        for time in &[30, 40] {
+            clock.set(at(*time));
+            mine_block(&mut blockchain);
+        }
+        let blockchain = web::Data::new(Mutex::new(blockchain));
+        let (code, body) = get(&blockchain, "/stats/difficulty-history?offset=1&limit=10");
+        assert_eq!(code, StatusCode::OK);
+        assert_eq!(body["total"], 5);
+        let history: Vec<DifficultyPoint> = serde_json::from_value(body["items"].clone()).unwrap();
+        let chain = blockchain.lock().unwrap().chain.clone();
+        assert!(history.iter().all(|point| point.timestamp == chain[point.index - 1].timestamp));
+        let history: Vec<(usize, usize, usize)> = history.iter().map(|point| (point.index, point.difficulty, point.bits)).collect();
+        assert_eq!(history, vec![(2, 1, 4), (3, 1, 4), (4, 2, 8), (5, 2, 8)]);
+    }
+
+    #[test]
+    fn no_more_than_the_configured_peer_requests_are_in_flight() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        let (in_flight, most) = (Arc::new(AtomicUsize::new(0)), Arc::new(AtomicUsize::new(0)));
+        let mut blockchain = Blockchain::new(Config { max_peer_requests: 2, ..test_config() });
+        // Slow peers that record how many of them are answering at the same time.
+        for _ in 0..8 {
+            let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+            blockchain.register_node(&format!("http://{}", listener.local_addr().unwrap()));
+            let (in_flight, most) = (in_flight.clone(), most.clone());
+            thread::spawn(move || for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut request = [0; 65536];
+                let _ = stream.read(&mut request);
+                let current = in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+                most.fetch_max(current, Ordering::SeqCst);
+                thread::sleep(Duration::from_millis(50));
+                in_flight.fetch_sub(1, Ordering::SeqCst);
+                let _ = stream.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\nConnection: close\r\n\r\n");
+            });
+        }
+        let blockchain = web::Data::new(Mutex::new(blockchain));
+        let (code, body) = get(&blockchain, "/nodes/latency");
+        assert_eq!(code, StatusCode::OK);
+        let latencies: Vec<PeerLatency> = serde_json::from_value(body).unwrap();
+        assert_eq!(latencies.len(), 8);
+        assert!(latencies.iter().all(|peer| peer.latency_ms.is_some()));
+        assert_eq!(most.load(Ordering::SeqCst), 2);
+        // Consensus and topology discovery go through the same limit.
+        most.store(0, Ordering::SeqCst);
+        let (code, body) = get(&blockchain, "/nodes/resolve");
+        assert_eq!(code, StatusCode::OK);
+        assert_eq!(body["replaced"], false);
+        assert_eq!(most.load(Ordering::SeqCst), 2);
+        most.store(0, Ordering::SeqCst);
+        let (code, body) = get(&blockchain, "/nodes/topology?depth=2");
+        assert_eq!(code, StatusCode::OK);
+        assert_eq!(body["nodes"].as_array().unwrap().len(), 9);
+        assert_eq!(most.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn a_panicking_peer_request_fails_the_whole_query() {
+        let peers: Vec<String> = vec!["a".to_string(), "bb".to_string(), "ccc".to_string()];
+        assert_eq!(query_peers(peers.clone(), 2, |node| node.len()), Ok(vec![1, 2, 3]));
+        let answered = query_peers(peers, 2, |node| if node == "bb" { panic!("no answer from {}", node) } else { node });
+        assert_eq!(answered, Err("a request to a peer panicked".to_string()));
+    }
+
+    #[test]
+    fn the_network_supply_flags_a_peer_on_a_diverged_chain() {
+        let (mut blockchain, _) = mine_at(test_config(), &[10, 20]);
+        let (first, _first) = serve(peer_of(&blockchain));
+        let (second, _second) = serve(peer_of(&blockchain));
+        let mut diverged = peer_of(&blockchain);
+        mine_block(&mut diverged);
+        let (third, _third) = serve(diverged);
+        for peer in &[&first, &second, &third] {
+            blockchain.register_node(&format!("http://{}", peer));
+        }
+        let blockchain = web::Data::new(Mutex::new(blockchain));
+        let (code, body) = get(&blockchain, "/network/supply");
+        assert_eq!(code, StatusCode::OK);
+        let supply: NetworkSupply = serde_json::from_value(body).unwrap();
+        assert_eq!((supply.local_supply, supply.agreed_supply), (2.0, 2.0));
+        assert_eq!((supply.reporting, supply.agreeing), (4, 3));
+        let agrees = |node: &str| supply.peers.iter().find(|peer| peer.node == node).map(|peer| (peer.total_supply, peer.agrees));
+        assert_eq!(agrees(&first), Some((Some(2.0), true)));
+        assert_eq!(agrees(&second), Some((Some(2.0), true)));
+        assert_eq!(agrees(&third), Some((Some(3.0), false)));
+    }
+
+    #[test]
+    fn amounts_serialize_the_same_way_on_every_endpoint() {
+        let mut blockchain = Blockchain::new(Config { node_identifier: "miner".to_string(), ..test_config() });
+        mine_block(&mut blockchain);
+        blockchain.new_transaction(Transaction::new("miner", "bob", 1.0, 0.0)).unwrap();
+        let blockchain = web::Data::new(Mutex::new(blockchain));
+        assert!(call_raw(&blockchain, test::TestRequest::get().uri("/chain")).1.contains(r#""amount":1.0"#));
+        assert!(call_raw(&blockchain, test::TestRequest::get().uri("/transactions/pending/export")).1.contains(r#""amount":1.0"#));
+        let balances = test::TestRequest::post().uri("/balances").set_json(&BalanceQuery { addresses: vec!["miner".to_string()] });
+        assert_eq!(call_raw(&blockchain, balances).1, r#"{"miner":1.0}"#);
+        assert_eq!(serde_json::to_string(&1e20f32).unwrap(), "1e20");
+        assert_eq!(serde_json::from_str::<Transaction>(r#"{"sender":"a","recipient":"b","amount":1}"#).unwrap().amount, 1.0);
+    }
+
+    #[test]
+    fn locators_find_the_fork_point_of_a_deep_shared_prefix() {
+        let mut ours = Blockchain::new(test_config());
+        for _ in 0..19 {
+            mine_block(&mut ours);
+        }
+        let mut theirs = peer_of(&ours);
+        let fork = ours.chain[19].clone();
+        for _ in 0..15 {
+            mine_block(&mut ours);
+        }
+        for _ in 0..3 {
+            mine_block(&mut theirs);
+        }
+        let locator = ours.locator();
+        assert_eq!(locator.hashes.len(), 14);
+        assert_eq!(locator.hashes.first(), Some(&ours.chain[34].hash()));
+        assert_eq!(locator.hashes.last(), Some(&ours.chain[0].hash()));
+        let theirs = web::Data::new(Mutex::new(theirs));
+        let (code, body) = call(&theirs, test::TestRequest::post().uri("/chain/locate").set_json(&locator));
+        assert_eq!(code, StatusCode::OK);
+        assert_eq!((body["index"].as_u64(), body["hash"].as_str()), (Some(20), Some(fork.hash().as_str())));
+        // Sync carries on from the fork point with just the blocks we lack.
+        let page = get(&theirs, &format!("/chain/blocks?cursor={}", body["cursor"].as_str().unwrap())).1;
+        let missing: Vec<Block> = serde_json::from_value(page["blocks"].clone()).unwrap();
+        assert_eq!(hashes(&missing), hashes(&theirs.lock().unwrap().chain[20..]));
+    }
+
+    #[test]
+    fn maintenance_turns_away_writes_but_keeps_serving_reads() {
+        let mut blockchain = Blockchain::new(Config { admin_key: Some("key".to_string()), ..test_config() });
+        mine_block(&mut blockchain);
+        let miner = blockchain.config.node_identifier.clone();
+        let blockchain = web::Data::new(Mutex::new(blockchain));
+        let maintenance = |enabled: bool| call(&blockchain, test::TestRequest::post().uri("/admin/maintenance")
+            .header(ADMIN_KEY_HEADER, "key")
+            .set_json(&serde_json::json!({ "enabled": enabled })));
+        let transaction = Transaction::new(&miner, "alice", 0.25, 0.0);
+        let submit = || call(&blockchain, test::TestRequest::post().uri("/transactions/new").set_json(&transaction));
+        assert_eq!(maintenance(true), (StatusCode::OK, serde_json::json!({ "enabled": true })));
+        assert_eq!(get(&blockchain, "/status").1["maintenance"], true);
+        assert_eq!(get(&blockchain, "/ready"), (StatusCode::SERVICE_UNAVAILABLE, serde_json::json!({ "message": "maintenance" })));
+        assert_eq!(get(&blockchain, "/mine").0, StatusCode::SERVICE_UNAVAILABLE);
+        let (code, body) = submit();
+        assert_eq!(code, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(body["message"], "The node is down for maintenance and only serves reads");
+        let (code, body) = get(&blockchain, "/chain");
+        assert_eq!((code, body["length"].as_u64()), (StatusCode::OK, Some(2)));
+        assert_eq!(maintenance(false).0, StatusCode::OK);
+        assert_eq!(get(&blockchain, "/ready").0, StatusCode::OK);
+        assert_eq!(submit().0, StatusCode::OK);
+        assert_eq!(get(&blockchain, "/mine").0, StatusCode::OK);
+        assert_eq!(blockchain.lock().unwrap().chain.len(), 3);
+    }
+
+    #[test]
+    fn received_blocks_before_the_median_time_past_are_rejected() {
+        let (blockchain, clock) = mine_at(test_config(), &[10, 20, 30]);
+        let mut peer = Blockchain::with_clock(Config { node_identifier: "peer".to_string(), ..test_config() }, clock.clone());
+        peer.chain = blockchain.chain.clone();
+        peer.hash_index = Blockchain::hash_index(&peer.chain);
+        clock.set(at(15));
+        let backdated = mine_block(&mut peer);
+        peer.rollback(1).unwrap();
+        clock.set(at(25));
+        let compliant = mine_block(&mut peer);
+        let blockchain = web::Data::new(Mutex::new(blockchain));
+        let deliver = |block: &Block| call(&blockchain, test::TestRequest::post().uri("/block/receive").set_json(block));
+        let (code, body) = deliver(&backdated);
+        assert_eq!(code, StatusCode::BAD_REQUEST);
+        assert_eq!(body["message"], BlockchainError::TimestampTooEarly {
+            index: 5,
+            timestamp: backdated.timestamp.clone(),
+            median: at(20).to_string()
+        }.to_string());
+        assert_eq!(deliver(&compliant).0, StatusCode::OK);
+        assert_eq!(blockchain.lock().unwrap().chain.last().map(Block::hash), Some(compliant.hash()));
+    }
+
+    #[cfg(feature = "sqlite")]
+    fn sqlite_snapshot(blockchain: &Blockchain) -> Snapshot {
+        Snapshot { chain: blockchain.chain.clone(), current_transactions: blockchain.current_transactions.clone(), reward_epochs: Vec::new() }
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[test]
+    fn sqlite_store_round_trips_the_chain_and_mempool() {
+        let store = SqliteStore::open(&temp_path("sqlite-store")).unwrap();
+        assert!(store.load().unwrap().is_none());
+        let mut blockchain = Blockchain::new(test_config());
+        mine_block(&mut blockchain);
+        blockchain.current_transactions.push(Transaction::new("alice", "bob", 1.0, 0.0));
+        store.save(&sqlite_snapshot(&blockchain)).unwrap();
+        let snapshot = store.load().unwrap().unwrap();
+        assert_eq!(hashes(&snapshot.chain), hashes(&blockchain.chain));
+        assert_eq!(snapshot.current_transactions, blockchain.current_transactions);
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[test]
+    fn a_node_restarted_on_sqlite_serves_its_blocks_by_index() {
+        let config = Config { storage_backend: StorageBackend::Sqlite, storage_path: Some(temp_path("sqlite-node")), ..test_config() };
+        let mut blockchain = Blockchain::new(config.clone());
+        blockchain.attach_storage().unwrap();
+        mine_block(&mut blockchain);
+        let block = mine_block(&mut blockchain);
+        let mut restarted = Blockchain::new(config);
+        restarted.attach_storage().unwrap();
+        assert_eq!(hashes(&restarted.chain), hashes(&blockchain.chain));
+        let (code, body) = get(&web::Data::new(Mutex::new(restarted)), "/block/3");
+        assert_eq!(code, StatusCode::OK);
+        assert_eq!(body["proof"], block.proof);
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[test]
+    fn sqlite_store_replaces_blocks_after_a_fork() {
+        let store = SqliteStore::open(&temp_path("sqlite-fork")).unwrap();
+        let mut blockchain = Blockchain::new(test_config());
+        let genesis = blockchain.chain.clone();
+        mine_block(&mut blockchain);
+        mine_block(&mut blockchain);
+        store.save(&sqlite_snapshot(&blockchain)).unwrap();
+        blockchain.chain = genesis;
+        mine_block(&mut blockchain);
+        store.save(&sqlite_snapshot(&blockchain)).unwrap();
+        assert_eq!(hashes(&store.load().unwrap().unwrap().chain), hashes(&blockchain.chain));
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[test]
+    fn sqlite_store_compacts_to_the_same_chain() {
+        let store = SqliteStore::open(&temp_path("sqlite-compact")).unwrap();
+        let mut blockchain = Blockchain::new(test_config());
+        mine_block(&mut blockchain);
+        store.save(&sqlite_snapshot(&blockchain)).unwrap();
+        store.compact(&sqlite_snapshot(&blockchain)).unwrap();
+        assert_eq!(hashes(&store.load().unwrap().unwrap().chain), hashes(&blockchain.chain));
+    }
+
+    #[test]
+    fn window_stats_only_cover_blocks_inside_the_window() {
+        let (mut blockchain, clock) = mine_at(test_config(), &[10]);
+        let miner = blockchain.config.node_identifier.clone();
+        for (time, amount) in &[(20, 0.25), (40, 0.5)] {
+            blockchain.new_transaction(Transaction::new(&miner, "bob", *amount, 0.0)).unwrap();
+            clock.set(at(*time));
+            mine_block(&mut blockchain);
+        }
+        clock.set(at(70));
+        mine_block(&mut blockchain);
+        let blockchain = web::Data::new(Mutex::new(blockchain));
+        let window = format!("from={}&to={}", at(15).to_rfc3339(), at(45).to_rfc3339()).replace('+', "%2B");
+        let (code, stats) = get(&blockchain, &format!("/stats/window?{}", window));
+        assert_eq!(code, StatusCode::OK);
+        assert_eq!(stats, serde_json::json!({
+            "blocks": 2,
+            "transactions": 4,
+            "total_transferred": 0.75,
+            "average_block_interval": 20.0
+        }));
+        assert_eq!(get(&blockchain, "/stats/window?from=yesterday&to=today").0, StatusCode::BAD_REQUEST);
+    }
+
+    #[test]
+    fn a_corrupt_chain_blocks_mining_until_a_valid_resync() {
+        let mut source = Blockchain::new(test_config());
+        mine_block(&mut source);
+        mine_block(&mut source);
+        let mut chain = source.chain.clone();
+        chain[1].proof += 1;
+        let path = temp_path("corrupt");
+        FileStore::new(&path).save(&Snapshot { chain, current_transactions: Vec::new(), reward_epochs: Vec::new() }).unwrap();
+        mine_block(&mut source);
+        let (address, _source) = serve(source);
+        let mut blockchain = Blockchain::new(Config { storage_path: Some(path), ..test_config() });
+        blockchain.attach_storage().unwrap();
+        assert!(blockchain.corruption.is_some());
+        blockchain.register_node(&format!("http://{}", address));
+        let blockchain = web::Data::new(Mutex::new(blockchain));
+        let (code, body) = get(&blockchain, "/mine");
+        assert_eq!(code, StatusCode::SERVICE_UNAVAILABLE);
+        assert!(body["message"].as_str().unwrap().starts_with("Mining is disabled because the chain is corrupt"));
+        assert_eq!(get(&blockchain, "/nodes/resolve").1["replaced"], true);
+        assert!(blockchain.lock().unwrap().corruption.is_none());
+        assert_eq!(get(&blockchain, "/mine").0, StatusCode::OK);
+    }
+
+    #[test]
+    fn a_high_fee_is_not_enough_when_the_rate_per_byte_is_below_the_floor() {
+        let mut blockchain = Blockchain::new(Config { min_fee_per_byte: 0.0025, ..test_config() });
+        mine_block(&mut blockchain);
+        let miner = blockchain.config.node_identifier.clone();
+        let mut large = Transaction::new(&miner, &"b".repeat(MAX_ADDRESS_LENGTH), 0.25, 0.5);
+        large.category = Some("x".repeat(MAX_CATEGORY_LENGTH));
+        let size = large.byte_size();
+        let err = blockchain.new_transaction(large).unwrap_err();
+        assert!(matches!(err, BlockchainError::FeeRateTooLow { size: rejected, .. } if rejected == size), "{:?}", err);
+        let small = Transaction::new(&miner, "bob", 0.25, 0.5);
+        assert!(small.fee_per_byte() >= 0.0025);
+        assert!(blockchain.new_transaction(small).is_ok());
+    }
+
+    #[test]
+    fn transactions_can_be_queried_by_category() {
+        let mut blockchain = Blockchain::new(test_config());
+        mine_block(&mut blockchain);
+        let miner = blockchain.config.node_identifier.clone();
+        let blockchain = web::Data::new(Mutex::new(blockchain));
+        let submit = |recipient: &str, category: Option<&str>| {
+            let mut transaction = Transaction::new(&miner, recipient, 0.1, 0.0);
+            transaction.category = category.map(str::to_string);
+            call(&blockchain, test::TestRequest::post().uri("/transactions/new").set_json(&transaction)).0
+        };
+        assert_eq!(submit("landlord", Some("rent")), StatusCode::OK);
+        assert_eq!(submit("grocer", Some("food")), StatusCode::OK);
+        assert_eq!(submit("garage", Some("rent")), StatusCode::OK);
+        assert_eq!(submit("friend", None), StatusCode::OK);
+        assert_eq!(submit("friend", Some("not a label")), StatusCode::BAD_REQUEST);
+        assert_eq!(get(&blockchain, "/mine").0, StatusCode::OK);
+        let recipients = |category: &str| -> Vec<String> {
+            let (_, body) = get(&blockchain, &format!("/transactions/by-category/{}", category));
+            body.as_array().unwrap().iter().map(|transaction| transaction["recipient"].as_str().unwrap().to_string()).collect()
+        };
+        assert_eq!(recipients("rent"), vec!["landlord", "garage"]);
+        assert_eq!(recipients("food"), vec!["grocer"]);
+        assert!(recipients("travel").is_empty());
+    }
+
+    #[test]
+    fn a_compact_block_is_rebuilt_fetching_only_the_missing_transactions() {
+        let mut miner = Blockchain::new(test_config());
+        mine_block(&mut miner);
+        let payer = miner.config.node_identifier.clone();
+        let mut receiver = peer_of(&miner);
+        let (known, unknown) = (Transaction::new(&payer, "bob", 0.25, 0.0), Transaction::new(&payer, "carol", 0.5, 0.0));
+        miner.new_transaction(known.clone()).unwrap();
+        miner.new_transaction(unknown).unwrap();
+        receiver.new_transaction(known).unwrap();
+        let (address, miner) = serve(miner);
+        let (block, mut compact) = {
+            let mut miner = miner.lock().unwrap();
+            let block = mine_block(&mut miner);
+            let compact = miner.compact_block(&block);
+            (block, compact)
+        };
+        assert_eq!((compact.prefilled.len(), compact.short_ids.len()), (1, 2));
+        compact.origin = address;
+        let receiver = web::Data::new(Mutex::new(receiver));
+        let (code, body) = call(&receiver, test::TestRequest::post().uri("/block/compact").set_json(&compact));
+        assert_eq!(code, StatusCode::OK);
+        assert_eq!(body["message"], "Block reconstructed after fetching 1 transactions");
+        let receiver = receiver.lock().unwrap();
+        assert_eq!(receiver.chain.last().unwrap().hash(), block.hash());
+        assert!(receiver.current_transactions.is_empty());
+    }
+
+    #[test]
+    fn logged_peer_failures_redact_the_peer_address() {
+        let message = "failed to announce block 2 to 10.0.0.7:5000: http://10.0.0.7:5000/block/compact: connection refused";
+        let logger = Blockchain::new(Config { redact_logs: true, ..test_config() }).logger();
+        let redacted = logger.peer_log_line("10.0.0.7:5000", message);
+        assert!(!redacted.contains("10.0.0.7"), "{}", redacted);
+        assert!(redacted.contains(REDACTED));
+        assert_eq!(Blockchain::new(test_config()).logger().peer_log_line("10.0.0.7:5000", message), message);
+    }
+
+    #[test]
+    fn topology_discovery_follows_each_peers_peers() {
+        let (c, _) = serve(Blockchain::new(test_config()));
+        let mut node_b = Blockchain::new(test_config());
+        node_b.register_node(&format!("http://{}", c));
+        let (b, _) = serve(node_b);
+        let mut node_a = Blockchain::new(Config { advertised_address: Some("a.example:5000".to_string()), ..test_config() });
+        node_a.register_node(&format!("http://{}", b));
+        let (code, body) = get(&web::Data::new(Mutex::new(node_a)), "/nodes/topology");
+        assert_eq!(code, StatusCode::OK);
+        let discovered: Topology = serde_json::from_value(body).unwrap();
+        assert_eq!(discovered.edges, vec![
+            Edge { from: "a.example:5000".to_string(), to: b.clone() },
+            Edge { from: b.clone(), to: c.clone() }
+        ]);
+        let mut expected = vec!["a.example:5000".to_string(), b, c];
+        expected.sort();
+        assert_eq!(discovered.nodes, expected);
+    }
+
+    #[test]
+    fn peers_sync_on_the_highest_schema_version_they_share() {
+        let mut peer = Blockchain::new(Config { schema_versions: vec![1, 2], ..test_config() });
+        mine_block(&mut peer);
+        let (address, peer) = serve(peer);
+        let mut blockchain = Blockchain::new(Config { schema_versions: vec![2, 3], consensus_interval: 0, ..test_config() });
+        blockchain.register_node(&format!("http://{}", address));
+        let client = reqwest::Client::new();
+        assert_eq!(negotiate_version(&client, &address, &blockchain.config.schema_versions), Some((2, address.clone())));
+        assert!(blockchain.resolve_conflicts().replaced);
+        assert_eq!(hashes(&blockchain.chain), hashes(&peer.lock().unwrap().chain));
+        let mut newer = Blockchain::new(Config { schema_versions: vec![3], ..test_config() });
+        newer.register_node(&format!("http://{}", address));
+        assert_eq!(negotiate_version(&client, &address, &newer.config.schema_versions), None);
+        assert!(!newer.resolve_conflicts().replaced);
+        assert_eq!(newer.chain.len(), 1);
+    }
+
+    #[test]
+    fn a_block_delivered_twice_is_processed_and_relayed_once() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        // Counts the requests reaching a peer that answers every one of them with an empty 200.
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let relay = listener.local_addr().unwrap().to_string();
+        let relayed = Arc::new(AtomicUsize::new(0));
+        let counter = relayed.clone();
+        thread::spawn(move || for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            let mut request = [0; 65536];
+            let _ = stream.read(&mut request);
+            counter.fetch_add(1, Ordering::SeqCst);
+            let _ = stream.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\nConnection: close\r\n\r\n");
+        });
+        let mut miner = Blockchain::new(test_config());
+        let mut blockchain = peer_of(&miner);
+        blockchain.register_node(&format!("http://{}", relay));
+        let block = mine_block(&mut miner);
+        let blockchain = web::Data::new(Mutex::new(blockchain));
+        let deliver = || call(&blockchain, test::TestRequest::post().uri("/block/receive").set_json(&block)).1["message"].clone();
+        assert_eq!(deliver(), "Block accepted");
+        assert_eq!(deliver(), "Block already seen");
+        thread::sleep(Duration::from_millis(500));
+        assert_eq!(relayed.load(Ordering::SeqCst), 1);
+        assert_eq!(blockchain.lock().unwrap().chain.len(), 2);
+    }
+
+    #[test]
+    fn the_hash_index_follows_the_chain() {
+        let mut blockchain = Blockchain::new(test_config());
+        let middle = mine_block(&mut blockchain);
+        let tip = mine_block(&mut blockchain);
+        assert_eq!(blockchain.position_of_hash(&middle.hash()), Some(1));
+        let blockchain = web::Data::new(Mutex::new(blockchain));
+        let (code, body) = get(&blockchain, &format!("/chain/at-hash/{}", middle.hash()));
+        assert_eq!(code, StatusCode::OK);
+        assert_eq!(body["length"], 2);
+        assert_eq!(body["chain"][1]["proof"], middle.proof);
+        let mut blockchain = blockchain.lock().unwrap();
+        blockchain.rollback(1).unwrap();
+        assert_eq!(blockchain.position_of_hash(&tip.hash()), None);
+        let replacement = mine_block(&mut blockchain);
+        assert_eq!(blockchain.position_of_hash(&replacement.hash()), Some(2));
+        assert_eq!(blockchain.hash_index, Blockchain::hash_index(&blockchain.chain));
+    }
+
+    #[test]
+    fn the_suggested_difficulty_matches_the_measured_hashrate() {
+        let blockchain = web::Data::new(Mutex::new(Blockchain::new(test_config())));
+        assert_eq!(get(&blockchain, "/mining/suggest-difficulty").0, StatusCode::SERVICE_UNAVAILABLE);
+        {
+            let mut local_blockchain = blockchain.lock().unwrap();
+            local_blockchain.record_hashrate(65536, Duration::from_secs(2));
+            local_blockchain.record_hashrate(65536, Duration::from_secs(0));
+        }
+        // 65536 hashes a second, or 16^4: one second's worth is difficulty 4, sixteen seconds' worth 5.
+        let (code, body) = get(&blockchain, "/mining/suggest-difficulty?target_block_time=16");
+        assert_eq!(code, StatusCode::OK);
+        assert_eq!(body["hashrate"], 65536.0);
+        assert_eq!(body["suggested_difficulty"], 5);
+        assert_eq!(get(&blockchain, "/mining/suggest-difficulty?target_block_time=1").1["suggested_difficulty"], 4);
+        assert_eq!(get(&blockchain, "/mining/suggest-difficulty?target_block_time=0").0, StatusCode::BAD_REQUEST);
+    }
+
+    #[test]
+    fn chain_info_describes_the_mined_chain() {
+        let (mut blockchain, _) = mine_at(Config { chain_id: "testnet".to_string(), ..test_config() }, &[10, 20]);
+        blockchain.config.difficulty = 2;
+        let expected = serde_json::json!({
+            "chain_id": "testnet",
+            "height": 3,
+            "tip_hash": blockchain.chain[2].hash(),
+            "tip_timestamp": at(20).to_string(),
+            "difficulty": 2,
+            "accumulated_work": Blockchain::accumulated_work(&blockchain.chain) as u64,
+            "genesis_hash": blockchain.chain[0].hash()
+        });
+        let (code, body) = get(&web::Data::new(Mutex::new(blockchain)), "/chain/info");
+        assert_eq!(code, StatusCode::OK);
+        assert_eq!(body, expected);
+    }
+
+    #[test]
+    fn a_waiter_returns_as_soon_as_its_transaction_is_mined() {
+        let mut blockchain = Blockchain::new(test_config());
+        mine_block(&mut blockchain);
+        let transaction = Transaction::new(&blockchain.config.node_identifier, "bob", 0.5, 0.0);
+        blockchain.new_transaction(transaction.clone()).unwrap();
+        let (address, blockchain) = serve(blockchain);
+        let url = format!("http://{}/transactions/{}/wait?timeout=20", address, transaction.id());
+        let started = Instant::now();
+        let waiter = thread::spawn(move || reqwest::get(&url).and_then(|mut res| res.json::<Confirmation>()));
+        thread::sleep(Duration::from_millis(200));
+        mine_block(&mut blockchain.lock().unwrap());
+        let confirmation = waiter.join().unwrap().unwrap();
+        assert_eq!((confirmation.id, confirmation.block_index), (transaction.id(), 3));
+        assert!(started.elapsed() < Duration::from_secs(5));
+        let unknown = format!("http://{}/transactions/unknown/wait?timeout=1", address);
+        assert_eq!(reqwest::get(&unknown).unwrap().status(), reqwest::StatusCode::REQUEST_TIMEOUT);
+    }
+
+    #[test]
+    fn a_block_with_an_overdraft_is_rejected_despite_a_valid_proof() {
+        let mut blockchain = Blockchain::new(test_config());
+        let mut forger = peer_of(&blockchain);
+        forger.current_transactions.push(Transaction::new("alice", "bob", 5.0, 0.0));
+        let block = mine_block(&mut forger);
+        assert!(verify_block(&block, &forger.chain[0], forger.config.required_bits()).is_ok());
+        let overdraft = BlockchainError::Overdraft { index: 2, sender: "alice".to_string() };
+        assert_eq!(Blockchain::check_chain(&forger.chain, &forger.config), Err(overdraft.clone()));
+        assert_eq!(blockchain.receive_block(block).err(), Some(overdraft));
+        assert_eq!(blockchain.chain.len(), 1);
+    }
+
+    #[test]
+    fn reordering_transactions_changes_the_merkle_root_and_block_hash() {
+        let mut blockchain = Blockchain::new(Config { merkle_root: true, ..test_config() });
+        mine_block(&mut blockchain);
+        let miner = blockchain.config.node_identifier.clone();
+        blockchain.new_transaction(Transaction::new(&miner, "bob", 0.25, 0.0)).unwrap();
+        blockchain.new_transaction(Transaction::new(&miner, "carol", 0.5, 0.0)).unwrap();
+        let block = mine_block(&mut blockchain);
+        let parent = &blockchain.chain[1];
+        let mut reordered = block.clone();
+        reordered.transactions.swap(1, 2);
+        assert!(matches!(verify_block(&reordered, parent, 0), Err(BlockchainError::MerkleRootMismatch { index: 3 })));
+        reordered.merkle_root = Some(reordered.compute_merkle_root());
+        assert_ne!(reordered.merkle_root, block.merkle_root);
+        assert_ne!(reordered.hash(), block.hash());
+    }
+
+    #[test]
+    fn compacting_the_file_store_reloads_into_the_same_chain() {
+        let path = temp_path("compact");
+        let config = Config { storage_path: Some(path.clone()), admin_key: Some("key".to_string()), ..test_config() };
+        let mut blockchain = Blockchain::new(config.clone());
+        blockchain.attach_storage().unwrap();
+        mine_block(&mut blockchain);
+        mine_block(&mut blockchain);
+        let expected = hashes(&blockchain.chain);
+        let blockchain = web::Data::new(Mutex::new(blockchain));
+        let compact = || test::TestRequest::post().uri("/admin/compact").header(ADMIN_KEY_HEADER, "key");
+        let (code, body) = call(&blockchain, compact());
+        assert_eq!(code, StatusCode::OK);
+        assert_eq!(body["size_after"], fs::metadata(&path).unwrap().len());
+        let mut reloaded = Blockchain::new(config);
+        reloaded.attach_storage().unwrap();
+        assert_eq!(hashes(&reloaded.chain), expected);
+        let unstored = web::Data::new(Mutex::new(Blockchain::new(Config { admin_key: Some("key".to_string()), ..test_config() })));
+        assert_eq!(call(&unstored, compact()).0, StatusCode::BAD_REQUEST);
+    }
+
+    #[test]
+    fn mempool_pressure_eases_difficulty_and_an_empty_mempool_tightens_it() {
+        let config = Config { mempool_difficulty: true, mempool_full: 2, max_difficulty_boost: 2, ..test_config() };
+        let mut blockchain = Blockchain::new(config);
+        let miner = blockchain.config.node_identifier.clone();
+        let mut difficulties = Vec::new();
+        let mut payments = 0;
+        for pending in &[0, 0, 0, 2, 2, 2, 0] {
+            for _ in 0..*pending {
+                payments += 1;
+                blockchain.new_transaction(Transaction::new(&miner, &format!("payee-{}", payments), 0.01, 0.0)).unwrap();
+            }
+            difficulties.push(mine_block(&mut blockchain).difficulty);
+        }
+        assert_eq!(difficulties, vec![2, 3, 3, 2, 1, 1, 2]);
+        assert_eq!(Blockchain::check_chain(&blockchain.chain, &blockchain.config), Ok(()));
+    }
+
+    #[test]
+    fn the_genesis_endpoint_returns_block_one_and_the_chain_id() {
+        let mut blockchain = Blockchain::new(Config { chain_id: "testnet".to_string(), ..test_config() });
+        mine_block(&mut blockchain);
+        let blockchain = web::Data::new(Mutex::new(blockchain));
+        let (code, genesis) = get(&blockchain, "/block/genesis");
+        assert_eq!(code, StatusCode::OK);
+        let (_, first) = get(&blockchain, "/block/1");
+        assert_eq!(genesis["block"], first);
+        assert_eq!(genesis["chain_id"], "testnet");
+        assert_eq!(genesis["hash"], blockchain.lock().unwrap().chain[0].hash());
+    }
+
+    #[test]
+    fn a_truncated_chain_file_is_recovered_from_the_backup() {
+        let path = temp_path("partial-write");
+        let config = Config { storage_path: Some(path.clone()), ..test_config() };
+        let mut blockchain = Blockchain::new(config.clone());
+        blockchain.attach_storage().unwrap();
+        mine_block(&mut blockchain);
+        let backed_up = hashes(&blockchain.chain);
+        mine_block(&mut blockchain);
+        let contents = fs::read_to_string(&path).unwrap();
+        fs::write(&path, &contents[..contents.len() / 2]).unwrap();
+        let mut restarted = Blockchain::new(config);
+        restarted.attach_storage().unwrap();
+        assert_eq!(hashes(&restarted.chain), backed_up);
+        assert!(restarted.corruption.is_none());
+        fs::remove_file(format!("{}.bak", path)).unwrap();
+        fs::write(&path, "{").unwrap();
+        assert!(FileStore::new(&path).load().is_err());
+    }
+
+    #[test]
+    fn consensus_contacts_a_subset_of_peers_favoring_reputable_ones() {
+        let mut blockchain = Blockchain::new(Config { consensus_peers: Some(2), ..test_config() });
+        for n in 0..10 {
+            blockchain.register_node(&format!("http://peer{}.example:5000", n));
+        }
+        blockchain.rate_peer("peer0.example:5000", 1000);
+        let mut picked: HashMap<String, usize> = HashMap::new();
+        for _ in 0..200 {
+            let chosen = blockchain.consensus_peers();
+            assert_eq!(chosen.len(), 2);
+            assert_ne!(chosen[0], chosen[1]);
+            for node in chosen {
+                *picked.entry(node).or_default() += 1;
+            }
+        }
+        assert!(picked["peer0.example:5000"] > 190, "{:?}", picked);
+        assert!(picked.iter().all(|(node, count)| node == "peer0.example:5000" || *count < 100), "{:?}", picked);
+        blockchain.config.consensus_peers = None;
+        assert_eq!(blockchain.consensus_peers().len(), 10);
+    }
+
+    #[test]
+    fn transactions_signed_by_a_key_other_than_the_senders_are_rejected() {
+        let signer = Signer::load_or_create(&temp_path("signer")).unwrap();
+        let mut transaction = Transaction::new("alice", "bob", 1.0, 0.0);
+        transaction.public_key = Some(signer.public_key.clone());
+        let signature = EcdsaSig::sign(&transaction.signing_hash(), &signer.key).unwrap();
+        transaction.signature = Some(to_hex(&signature.to_der().unwrap()));
+        let blockchain = web::Data::new(Mutex::new(Blockchain::new(test_config())));
+        let (code, body) = call(&blockchain, test::TestRequest::post().uri("/transactions/new").set_json(&transaction));
+        assert_eq!(code, StatusCode::BAD_REQUEST);
+        assert_eq!(body["message"], format!("transaction is signed by {} but claims to be sent by alice", signer.address()));
+        assert!(blockchain.lock().unwrap().current_transactions.is_empty());
+    }
+
+    #[test]
+    fn mined_blocks_are_streamed_as_server_sent_events() {
+        let (address, _blockchain) = serve(Blockchain::new(Config { events: true, ..test_config() }));
+        let mut stream = std::net::TcpStream::connect(&address).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+        write!(stream, "GET /events HTTP/1.1\r\nHost: {}\r\nAccept: text/event-stream\r\n\r\n", address).unwrap();
+        let mut received = String::new();
+        let mut buffer = [0; 4096];
+        let mut mined = false;
+        while !received.contains("event: block\n") {
+            if !mined && received.contains("\r\n\r\n") {
+                assert!(reqwest::get(&format!("http://{}/mine", address)).unwrap().status().is_success());
+                mined = true;
+            }
+            let read = stream.read(&mut buffer).unwrap();
+            assert!(read > 0, "{}", received);
+            received.push_str(&String::from_utf8_lossy(&buffer[..read]));
+        }
+        assert!(received.starts_with("HTTP/1.1 200 OK"));
+        assert!(received.contains("content-type: text/event-stream"), "{}", received);
+        let event = &received[received.find("id: ").unwrap()..];
+        let data = event.lines().find(|line| line.starts_with("data: ")).unwrap();
+        let block: Block = serde_json::from_str(&data["data: ".len()..]).unwrap();
+        assert_eq!(block.index, 2);
+    }
+
+    #[test]
+    fn a_transaction_mined_twice_is_a_replay() {
+        let mut blockchain = Blockchain::new(test_config());
+        mine_block(&mut blockchain);
+        let payment = Transaction::new(&blockchain.config.node_identifier, "bob", 0.25, 0.0);
+        blockchain.new_transaction(payment.clone()).unwrap();
+        mine_block(&mut blockchain);
+        assert!(matches!(blockchain.new_transaction(payment.clone()), Err(BlockchainError::DuplicateTransaction { .. })));
+        blockchain.current_transactions.push(payment.clone());
+        mine_block(&mut blockchain);
+        assert_eq!(Blockchain::check_chain(&blockchain.chain, &blockchain.config),
+            Err(BlockchainError::ReplayedTransaction { index: 4, id: payment.short_id() }));
+    }
+
+    #[test]
+    fn an_exported_mempool_imports_into_another_node() {
+        let mut source = Blockchain::new(test_config());
+        mine_block(&mut source);
+        let payer = source.config.node_identifier.clone();
+        let mut target = peer_of(&source);
+        let (held, new) = (Transaction::new(&payer, "bob", 0.25, 0.0), Transaction::new(&payer, "carol", 0.5, 0.0));
+        source.new_transaction(held.clone()).unwrap();
+        source.new_transaction(new.clone()).unwrap();
+        target.new_transaction(held.clone()).unwrap();
+        let source = web::Data::new(Mutex::new(source));
+        let (code, body) = call_raw(&source, test::TestRequest::get().uri("/transactions/pending/export"));
+        assert_eq!(code, StatusCode::OK);
+        let mut exported: Vec<Transaction> = serde_json::from_str(&body).unwrap();
+        assert_eq!(exported, vec![held.clone(), new.clone()]);
+        exported.push(Transaction::new("mallory", "bob", 5.0, 0.0));
+        let target = web::Data::new(Mutex::new(target));
+        let (code, result) = call(&target, test::TestRequest::post().uri("/transactions/pending/import").set_json(&exported));
+        assert_eq!(code, StatusCode::OK);
+        assert_eq!(result["imported"], 1);
+        let skipped: Vec<_> = result["skipped"].as_array().unwrap().iter().map(|skip| skip["position"].as_u64().unwrap()).collect();
+        assert_eq!(skipped, vec![0, 2]);
+        assert_eq!(target.lock().unwrap().current_transactions, vec![held, new]);
+    }
+
+    #[test]
+    fn the_orphan_pool_stays_within_its_limit() {
+        let mut blockchain = Blockchain::new(Config { orphan_limit: 4, orphan_max_ahead: 8, ..test_config() });
+        let genesis = blockchain.chain[0].clone();
+        let orphan = |index: usize, n: usize| Block { index, previous_hash: format!("unknown-{}", n), proof: n, ..genesis.clone() };
+        for n in 0..50 {
+            let index = 3 + n % 7;
+            assert!(matches!(blockchain.receive_block(orphan(index, n)), Ok(Received::Buffered)));
+            assert!(blockchain.orphans.len() <= 4);
+        }
+        assert!(blockchain.orphans.iter().all(|orphan| orphan.index == 3));
+        assert!(matches!(blockchain.receive_block(orphan(1000, 50)), Err(BlockchainError::TooFarAhead { index: 1000, tip: 1, max_ahead: 8 })));
+        assert_eq!(blockchain.orphans.len(), 4);
+        assert_eq!(blockchain.chain.len(), 1);
+    }
+
+    #[test]
+    fn fast_sync_trusts_blocks_up_to_the_checkpoint_and_requires_it() {
+        let mut peer = Blockchain::new(test_config());
+        for _ in 0..3 {
+            mine_block(&mut peer);
+        }
+        let honest = peer.chain.clone();
+        // Blocks up to the checkpoint are only checked for linkage, so a bad proof
+        // there goes unnoticed once the later blocks are relinked over it.
+        let mut trusted = honest.clone();
+        trusted[1].proof += 1;
+        for position in 2..trusted.len() {
+            trusted[position].previous_hash = trusted[position - 1].hash();
+        }
+        assert!(Blockchain::check_chain(&trusted, &peer.config).is_err());
+        let checkpoint = trusted[3].hash();
+        let blockchain = Blockchain::new(Config { assume_valid: Some(checkpoint.clone()), ..test_config() });
+        assert_eq!(blockchain.check_peer_chain(&trusted), Ok(()));
+        assert_eq!(blockchain.check_peer_chain(&honest), Err(BlockchainError::MissingCheckpoint { hash: checkpoint }));
+        let early = Blockchain::new(Config { assume_valid: Some(trusted[1].hash()), ..test_config() });
+        assert!(early.check_peer_chain(&trusted).is_err());
+    }
+
+    #[test]
+    fn simulating_a_transaction_projects_balances_without_changing_state() {
+        let mut blockchain = Blockchain::new(test_config());
+        mine_block(&mut blockchain);
+        let miner = blockchain.config.node_identifier.clone();
+        blockchain.new_transaction(Transaction::new(&miner, "bob", 0.25, 0.0)).unwrap();
+        let blockchain = web::Data::new(Mutex::new(blockchain));
+        let simulate = |transaction: Transaction| call(&blockchain, test::TestRequest::post().uri("/transactions/simulate").set_json(&transaction));
+        let (code, body) = simulate(Transaction::new(&miner, "bob", 0.5, 0.0));
+        assert_eq!(code, StatusCode::OK);
+        assert_eq!(body, serde_json::json!({
+            "sender": miner,
+            "sender_balance": 0.25,
+            "recipient": "bob",
+            "recipient_balance": 0.75
+        }));
+        let (code, body) = simulate(Transaction::new(&miner, "bob", 1.0, 0.0));
+        assert_eq!(code, StatusCode::BAD_REQUEST);
+        assert_eq!(body["message"], BlockchainError::Overdraft { index: 3, sender: miner }.to_string());
+        assert_eq!(blockchain.lock().unwrap().current_transactions.len(), 1);
+    }
+
+    #[test]
+    fn blocks_take_their_timestamps_from_the_clock() {
+        let (blockchain, _) = mine_at(test_config(), &[10, 20, 30]);
+        let times: Vec<_> = blockchain.chain.iter().map(|block| block.time().unwrap()).collect();
+        assert_eq!(times, vec![at(0), at(10), at(20), at(30)]);
+        let blockchain = web::Data::new(Mutex::new(blockchain));
+        let window = format!("from={}&to={}", at(5).to_rfc3339(), at(30).to_rfc3339()).replace('+', "%2B");
+        let (_, stats) = get(&blockchain, &format!("/stats/window?{}", window));
+        assert_eq!((stats["blocks"].as_u64(), stats["average_block_interval"].as_f64()), (Some(3), Some(10.0)));
+        assert_eq!(get(&blockchain, &format!("/blocks/by-time?{}", window)).1.as_array().unwrap().len(), 3);
+    }
+
+    #[test]
+    fn a_mock_clock_makes_retargeted_chains_reproducible() {
+        let config = || Config { node_identifier: "miner".to_string(), mempool_difficulty: true, max_difficulty_boost: 2, ..test_config() };
+        let (first, _) = mine_at(config(), &[10, 20, 30]);
+        let (second, _) = mine_at(config(), &[10, 20, 30]);
+        assert_eq!(hashes(&first.chain), hashes(&second.chain));
+        let difficulties: Vec<usize> = first.difficulty_history().iter().map(|point| point.difficulty).collect();
+        assert_eq!(difficulties, vec![1, 2, 3, 3]);
+        assert_eq!(Blockchain::check_chain(&first.chain, &first.config), Ok(()));
+    }
+
+    #[test]
+    fn blocks_before_the_median_time_past_are_rejected() {
+        let (mut blockchain, clock) = mine_at(test_config(), &[10, 20, 30]);
+        clock.set(at(15));
+        let backdated = mine_block(&mut blockchain);
+        assert_eq!(Blockchain::check_chain(&blockchain.chain, &blockchain.config), Err(BlockchainError::TimestampTooEarly {
+            index: 5,
+            timestamp: backdated.timestamp,
+            median: at(20).to_string()
+        }));
+        blockchain.rollback(1).unwrap();
+        clock.set(at(25));
+        mine_block(&mut blockchain);
+        assert_eq!(Blockchain::check_chain(&blockchain.chain, &blockchain.config), Ok(()));
+    }
+
+    #[test]
+    fn miner_earnings_add_up_rewards_and_fees() {
+        let mut blockchain = Blockchain::new(test_config());
+        blockchain.config.node_identifier = "alice".to_string();
+        mine_block(&mut blockchain);
+        mine_block(&mut blockchain);
+        blockchain.new_transaction(Transaction::new("alice", "carol", 0.5, 0.25)).unwrap();
+        blockchain.new_transaction(Transaction::new("alice", "dave", 0.25, 0.125)).unwrap();
+        blockchain.config.node_identifier = "bob".to_string();
+        mine_block(&mut blockchain);
+        mine_block(&mut blockchain);
+        let balance = Blockchain::balances(&blockchain.chain)["bob"];
+        let blockchain = web::Data::new(Mutex::new(blockchain));
+        let (code, body) = get(&blockchain, "/miner/bob/earnings");
+        assert_eq!(code, StatusCode::OK);
+        assert_eq!(body, serde_json::json!({ "address": "bob", "blocks": 2, "rewards": 2.0, "fees": 0.375, "total": 2.375 }));
+        assert_eq!(balance, 2.375);
+        assert_eq!(get(&blockchain, "/miner/alice/earnings").1["fees"], 0.0);
+    }
+
+    #[test]
+    fn difficulty_can_be_set_in_bits_between_hex_digits() {
+        let mine_with = |bits: usize| {
+            let mut blockchain = Blockchain::new(Config { difficulty_bits: Some(bits), ..test_config() });
+            let block = mine_block(&mut blockchain);
+            assert_eq!(Blockchain::check_chain(&blockchain.chain, &blockchain.config), Ok(()));
+            (blockchain.chain[0].clone(), block)
+        };
+        let (_, four) = mine_with(4);
+        let (genesis, five) = mine_with(5);
+        let (_, eight) = mine_with(8);
+        assert_eq!(five.bits(), 5);
+        assert!(four.work() < five.work() && five.work() < eight.work());
+        let (last_proof, last_hash) = (genesis.proof, genesis.hash());
+        assert!(verify_proof(last_proof, five.proof, &last_hash, 5));
+        // Some proof meets four bits but not five, and can't stand in for one.
+        let short = (0..).find(|proof| verify_proof(last_proof, *proof, &last_hash, 4) && !verify_proof(last_proof, *proof, &last_hash, 5)).unwrap();
+        let weak = Block { proof: short, ..five };
+        assert!(matches!(verify_block(&weak, &genesis, 5), Err(BlockchainError::InvalidProof { index: 2 })));
+    }
+
+    #[test]
+    fn debug_state_reports_every_section_with_the_admin_key_redacted() {
+        let mut blockchain = Blockchain::new(Config { admin_key: Some("secret".to_string()), ..test_config() });
+        blockchain.register_node("http://peer.example:5000");
+        blockchain.rate_peer("peer.example:5000", 2);
+        let blockchain = web::Data::new(Mutex::new(blockchain));
+        assert_eq!(get(&blockchain, "/mine").0, StatusCode::OK);
+        let miner = blockchain.lock().unwrap().config.node_identifier.clone();
+        call(&blockchain, test::TestRequest::post().uri("/transactions/new").set_json(&Transaction::new(&miner, "bob", 0.5, 0.0)));
+        assert_eq!(get(&blockchain, "/debug/state").0, StatusCode::UNAUTHORIZED);
+        let (code, body) = call(&blockchain, test::TestRequest::get().uri("/debug/state").header(ADMIN_KEY_HEADER, "secret"));
+        assert_eq!(code, StatusCode::OK);
+        for section in &["length", "tip_index", "tip_hash", "pending_transactions", "peers", "config", "safe_mode_reason",
+            "seconds_since_consensus", "orphan_blocks", "seen_blocks", "hashrate"] {
+            assert!(body.get(section).is_some(), "missing {}", section);
+        }
+        assert_eq!((body["length"].as_u64(), body["tip_index"].as_u64()), (Some(2), Some(2)));
+        assert_eq!(body["pending_transactions"].as_array().unwrap().len(), 1);
+        assert_eq!(body["peers"], serde_json::json!([{ "node": "peer.example:5000", "reputation": 2, "seconds_since_seen": 0 }]));
+        assert_eq!(body["config"]["admin_key"], REDACTED);
+        assert!(body["hashrate"].as_f64().unwrap() > 0.0);
+        assert!(!body.to_string().contains("secret"));
+    }
+
+    #[test]
+    fn a_coinbase_must_be_the_one_and_only_first_transaction() {
+        let mut blockchain = Blockchain::new(test_config());
+        mine_block(&mut blockchain);
+        let miner = blockchain.config.node_identifier.clone();
+        let coinbase = blockchain.coinbase_for(&miner).unwrap();
+        let transfer = Transaction::new(&miner, "bob", 0.5, 0.0);
+        let forge = |transactions: Vec<Transaction>| {
+            let mut forger = peer_of(&blockchain);
+            forger.current_transactions = transactions;
+            let last_block = forger.chain[forger.chain.len() - 1].clone();
+            let proof = forger.proof_of_work(&last_block, 0).unwrap();
+            forger.new_block(proof, &last_block.hash()).clone()
+        };
+        let misplaced = forge(vec![transfer.clone(), coinbase.clone()]);
+        let doubled = forge(vec![coinbase.clone(), coinbase.clone()]);
+        let proper = forge(vec![coinbase, transfer]);
+        assert_eq!(blockchain.receive_block(misplaced).err(), Some(BlockchainError::MisplacedCoinbase { index: 3 }));
+        assert_eq!(blockchain.receive_block(doubled).err(), Some(BlockchainError::MultipleCoinbases { index: 3, count: 2 }));
+        assert!(matches!(blockchain.receive_block(proper), Ok(Received::Accepted)));
+    }
+
+    #[test]
+    fn a_spent_output_is_gone_and_cannot_be_spent_again() {
+        let mut blockchain = Blockchain::new(Config { utxo: true, ..test_config() });
+        let coinbase = mine_block(&mut blockchain).coinbase().unwrap().clone();
+        let miner = blockchain.config.node_identifier.clone();
+        let spent = OutPoint { transaction: coinbase.id(), output: 0 };
+        let mut payment = Transaction::new(&miner, "bob", 0.25, 0.0);
+        payment.inputs = vec![spent.clone()];
+        blockchain.new_transaction(payment.clone()).unwrap();
+        blockchain.config.node_identifier = "someone-else".to_string();
+        mine_block(&mut blockchain);
+        let blockchain = web::Data::new(Mutex::new(blockchain));
+        let (code, body) = get(&blockchain, &format!("/utxos/{}", miner));
+        assert_eq!(code, StatusCode::OK);
+        assert_eq!(body["outputs"], serde_json::json!([{ "transaction": payment.id(), "output": 1, "amount": 0.75 }]));
+        assert_eq!(get(&blockchain, "/utxos/bob").1["total"], 0.25);
+        let mut again = Transaction::new(&miner, "carol", 0.25, 0.0);
+        again.inputs = vec![spent];
+        let (code, body) = call(&blockchain, test::TestRequest::post().uri("/transactions/new").set_json(&again));
+        assert_eq!(code, StatusCode::BAD_REQUEST);
+        assert!(body["message"].as_str().unwrap().ends_with("does not exist or was already spent"), "{}", body);
+    }
+
+    #[test]
+    fn the_ndjson_stream_has_one_block_per_line_in_order() {
+        let mut blockchain = Blockchain::new(test_config());
+        mine_block(&mut blockchain);
+        mine_block(&mut blockchain);
+        let expected = hashes(&blockchain.chain);
+        let blockchain = web::Data::new(Mutex::new(blockchain));
+        let (code, body) = call_raw(&blockchain, test::TestRequest::get().uri("/chain/ndjson"));
+        assert_eq!(code, StatusCode::OK);
+        let blocks: Vec<Block> = body.lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+        assert_eq!(hashes(&blocks), expected);
+        assert!(blocks.iter().enumerate().all(|(position, block)| block.index == position + 1));
+    }
+
+    #[test]
+    fn a_paused_node_refuses_to_mine_until_resumed() {
+        let blockchain = web::Data::new(Mutex::new(Blockchain::new(Config { admin_key: Some("key".to_string()), ..test_config() })));
+        let admin = |path: &str| test::TestRequest::post().uri(path).header(ADMIN_KEY_HEADER, "key");
+        assert_eq!(call(&blockchain, test::TestRequest::post().uri("/mine/pause")).0, StatusCode::UNAUTHORIZED);
+        assert_eq!(call(&blockchain, admin("/mine/pause")).0, StatusCode::OK);
+        assert_eq!(get(&blockchain, "/status").1["mining_paused"], true);
+        let (code, body) = get(&blockchain, "/mine");
+        assert_eq!(code, StatusCode::SERVICE_UNAVAILABLE);
+        assert!(body["message"].as_str().unwrap().starts_with("Mining is paused"), "{}", body);
+        assert_eq!(blockchain.lock().unwrap().chain.len(), 1);
+        assert_eq!(call(&blockchain, admin("/mine/resume")).0, StatusCode::OK);
+        assert_eq!(get(&blockchain, "/status").1["mining_paused"], false);
+        assert_eq!(get(&blockchain, "/mine").0, StatusCode::OK);
+        assert_eq!(blockchain.lock().unwrap().chain.len(), 2);
+    }
+
+    #[test]
+    fn transactions_over_the_output_cap_are_rejected() {
+        let mut blockchain = Blockchain::new(Config { utxo: true, max_outputs: 1, ..test_config() });
+        let coinbase = mine_block(&mut blockchain).coinbase().unwrap().clone();
+        let miner = blockchain.config.node_identifier.clone();
+        let blockchain = web::Data::new(Mutex::new(blockchain));
+        assert_eq!(get(&blockchain, "/status").1["max_outputs"], 1);
+        let submit = |amount: f32| {
+            let mut transaction = Transaction::new(&miner, "bob", amount, 0.0);
+            transaction.inputs = vec![OutPoint { transaction: coinbase.id(), output: 0 }];
+            call(&blockchain, test::TestRequest::post().uri("/transactions/new").set_json(&transaction))
+        };
+        let (code, body) = submit(0.25);
+        assert_eq!(code, StatusCode::BAD_REQUEST);
+        assert_eq!(body["message"], BlockchainError::TooManyOutputs { count: 2, max: 1 }.to_string());
+        assert_eq!(submit(1.0).0, StatusCode::OK);
+    }
+
+    #[test]
+    fn a_peer_chain_extending_ours_is_applied_as_new_blocks() {
+        let mut blockchain = Blockchain::new(Config { consensus_interval: 0, ..test_config() });
+        mine_block(&mut blockchain);
+        let miner = blockchain.config.node_identifier.clone();
+        blockchain.new_transaction(Transaction::new(&miner, "alice", 0.25, 0.0)).unwrap();
+        mine_block(&mut blockchain);
+        let confirmed = hashes(&blockchain.chain);
+        let mut peer = peer_of(&blockchain);
+        mine_block(&mut peer);
+        mine_block(&mut peer);
+        let expected = hashes(&peer.chain);
+        let (address, _peer) = serve(peer);
+        let pending = Transaction::new(&miner, "bob", 0.25, 0.0);
+        blockchain.new_transaction(pending.clone()).unwrap();
+        blockchain.register_node(&format!("http://{}", address));
+        let result = blockchain.resolve_conflicts();
+        assert!(result.extended && !result.replaced, "{}", result.message);
+        assert_eq!(result.length, confirmed.len() + 2);
+        assert_eq!(hashes(&blockchain.chain), expected);
+        assert_eq!(hashes(&blockchain.chain[..confirmed.len()]), confirmed);
+        assert_eq!(Blockchain::balances(&blockchain.chain)["alice"], 0.25);
+        assert_eq!(blockchain.current_transactions, vec![pending]);
+    }
+
+    #[test]
+    fn an_externally_mined_template_is_accepted_once() {
+        let blockchain = web::Data::new(Mutex::new(Blockchain::new(test_config())));
+        let solve = |template: &BlockTemplate| {
+            let target = proof_target(&template.previous_hash, template.merkle_root.as_deref());
+            (0..).find(|proof| verify_proof(template.last_proof, *proof, &target, template.bits)).unwrap()
+        };
+        let submit = |template: &BlockTemplate, proof| {
+            let submission = BlockSubmission { previous_hash: template.previous_hash.clone(), proof, bits: template.bits, transactions: template.transactions.clone() };
+            call(&blockchain, test::TestRequest::post().uri("/mining/submit").set_json(&submission))
+        };
+        let template: BlockTemplate = serde_json::from_value(get(&blockchain, "/mine/preview?miner=external").1).unwrap();
+        let proof = solve(&template);
+        let (code, body) = submit(&template, proof);
+        assert_eq!(code, StatusCode::OK, "{}", body);
+        assert_eq!(body["index"], 2);
+        assert_eq!(blockchain.lock().unwrap().chain[1].coinbase().unwrap().recipient, "external");
+        assert_eq!(submit(&template, proof).0, StatusCode::CONFLICT);
+        let mut greedy: BlockTemplate = serde_json::from_value(get(&blockchain, "/mine/preview?miner=external").1).unwrap();
+        greedy.transactions[0].amount = 100.0;
+        let (code, body) = submit(&greedy, solve(&greedy));
+        assert_eq!(code, StatusCode::BAD_REQUEST);
+        assert_eq!(body["message"], BlockchainError::ForgedCoinbase { index: 3 }.to_string());
+        assert_eq!(blockchain.lock().unwrap().chain.len(), 2);
+    }
+
+    #[test]
+    fn long_addresses_are_rejected_and_equivalent_ones_are_the_same() {
+        let mut blockchain = Blockchain::new(test_config());
+        mine_block(&mut blockchain);
+        let miner = blockchain.config.node_identifier.clone();
+        let blockchain = web::Data::new(Mutex::new(blockchain));
+        let submit = |recipient: &str| {
+            call(&blockchain, test::TestRequest::post().uri("/transactions/new").set_json(&Transaction::new(&miner, recipient, 0.25, 0.0)))
+        };
+        let (code, body) = submit(&"a".repeat(MAX_ADDRESS_LENGTH + 1));
+        assert_eq!(code, StatusCode::BAD_REQUEST);
+        assert_eq!(body["message"], BlockchainError::AddressTooLong { length: MAX_ADDRESS_LENGTH + 1 }.to_string());
+        // "é" as one code point, then as "e" followed by a combining acute accent.
+        let (composed, decomposed) = ("caf\u{e9}", "cafe\u{301}");
+        assert_eq!(submit(decomposed).0, StatusCode::OK);
+        assert_eq!(blockchain.lock().unwrap().current_transactions[0].recipient, composed);
+        assert_eq!(get(&blockchain, "/mine").0, StatusCode::OK);
+        let (code, body) = call(&blockchain, test::TestRequest::post().uri("/balances")
+            .set_json(&serde_json::json!({ "addresses": [composed, decomposed] })));
+        assert_eq!(code, StatusCode::OK);
+        assert_eq!(body[composed], 0.25);
+        assert_eq!(body[decomposed], 0.25);
+    }
+
+    #[test]
+    fn a_transaction_not_mined_by_its_height_is_dropped() {
+        let mut blockchain = Blockchain::new(test_config());
+        mine_block(&mut blockchain);
+        let miner = blockchain.config.node_identifier.clone();
+        let mut expiring = Transaction::new(&miner, "alice", 0.25, 0.0);
+        expiring.valid_until_height = Some(3);
+        blockchain.new_transaction(expiring.clone()).unwrap();
+        // Another node mines block 3 without it, so it can't be mined in time.
+        let mut peer = peer_of(&blockchain);
+        let block = mine_block(&mut peer);
+        blockchain.accept_block(block).unwrap();
+        assert!(blockchain.current_transactions.is_empty());
+        assert_eq!(
+            blockchain.new_transaction(expiring.clone()).unwrap_err().to_string(),
+            BlockchainError::ExpiredTransaction { index: 4, valid_until_height: 3 }.to_string()
+        );
+        mine_block(&mut blockchain);
+        assert!(blockchain.chain.iter().all(|block| !block.transactions.contains(&expiring)));
+    }
+
+    #[test]
+    fn batch_balances_return_every_address_in_one_call() {
+        let mut blockchain = Blockchain::new(test_config());
+        mine_block(&mut blockchain);
+        let miner = blockchain.config.node_identifier.clone();
+        blockchain.new_transaction(Transaction::new(&miner, "alice", 0.5, 0.0)).unwrap();
+        blockchain.new_transaction(Transaction::new(&miner, "bob", 0.25, 0.0)).unwrap();
+        mine_block(&mut blockchain);
+        let blockchain = web::Data::new(Mutex::new(blockchain));
+        let (code, body) = call(&blockchain, test::TestRequest::post().uri("/balances")
+            .set_json(&serde_json::json!({ "addresses": [miner, "alice", "bob", "nobody"] })));
+        assert_eq!(code, StatusCode::OK);
+        assert_eq!(body, serde_json::json!({ miner: 1.25, "alice": 0.5, "bob": 0.25, "nobody": 0.0 }));
+    }
+
+    #[test]
+    fn chains_below_the_minimum_height_are_ignored() {
+        let (peer, _) = mine_at(test_config(), &[10]);
+        let (address, _peer) = serve(peer);
+        let mut blockchain = Blockchain::new(Config { min_consensus_height: 10, consensus_interval: 0, ..test_config() });
+        blockchain.register_node(&format!("http://{}", address));
+        let result = blockchain.resolve_conflicts();
+        assert!(!result.replaced && result.network_error.is_none(), "{}", result.message);
+        assert_eq!(blockchain.chain.len(), 1);
+        blockchain.config.min_consensus_height = 2;
+        assert!(blockchain.resolve_conflicts().replaced);
+        assert_eq!(blockchain.chain.len(), 2);
+    }
+
+    #[test]
+    fn the_mempool_policy_reflects_the_configuration_and_admin_updates() {
+        let config = Config { min_fee: 0.5, dust_limit: 0.1, admin_key: Some("key".to_string()), ..test_config() };
+        let blockchain = web::Data::new(Mutex::new(Blockchain::new(config)));
+        let (code, policy) = get(&blockchain, "/mempool/policy");
+        assert_eq!(code, StatusCode::OK);
+        assert_eq!((policy["min_fee"].as_f64(), policy["dust_limit"].as_f64()), (Some(0.5), Some(0.1)));
+        assert!(policy["max_block_bytes"].is_null() && policy["transaction_ttl"].is_null());
+        let update = test::TestRequest::post().uri("/admin/policy")
+            .header(ADMIN_KEY_HEADER, "key")
+            .set_json(&serde_json::json!({ "min_fee": 2.0 }));
+        assert_eq!(call(&blockchain, update).0, StatusCode::OK);
+        assert_eq!(get(&blockchain, "/mempool/policy").1["min_fee"], 2.0);
+    }
+
+    #[test]
+    fn mutually_dependent_transactions_are_rejected() {
+        let mut blockchain = Blockchain::new(Config { utxo: true, ..test_config() });
+        let coinbase = mine_block(&mut blockchain).coinbase().unwrap().clone();
+        let miner = blockchain.config.node_identifier.clone();
+        let mut first = Transaction::new(&miner, &miner, 1.0, 0.0);
+        first.inputs = vec![OutPoint { transaction: coinbase.id(), output: 0 }];
+        let mut second = Transaction::new(&miner, &miner, 1.0, 0.0);
+        second.inputs = vec![OutPoint { transaction: first.id(), output: 0 }];
+        // Closes the loop: first now also spends the output of second.
+        first.inputs.push(OutPoint { transaction: second.id(), output: 0 });
+        let blockchain = web::Data::new(Mutex::new(blockchain));
+        for transaction in &[&first, &second] {
+            let (code, body) = call(&blockchain, test::TestRequest::post().uri("/transactions/new").set_json(transaction));
+            assert_eq!(code, StatusCode::BAD_REQUEST);
+            assert!(body["message"].as_str().unwrap().contains("does not exist or was already spent"), "{}", body);
+        }
+        assert!(blockchain.lock().unwrap().current_transactions.is_empty());
+        assert_eq!(get(&blockchain, "/mine").0, StatusCode::OK);
+    }
+    #[test]
+    fn the_api_is_mounted_under_the_base_path() {
+        let blockchain = web::Data::new(Mutex::new(Blockchain::new(test_config())));
+        let mut app = test::init_service(App::new()
+            .register_data(blockchain.clone())
+            .data(json_config(false))
+            .configure(|config| mount(config, "/api/v1")));
+        let chain = test::call_service(&mut app, test::TestRequest::get().uri("/api/v1/chain").to_request());
+        assert_eq!(chain.status(), StatusCode::OK);
+        let unprefixed = test::call_service(&mut app, test::TestRequest::get().uri("/chain").to_request());
+        assert_eq!(unprefixed.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[test]
+    fn consensus_reaches_peers_under_their_advertised_base_path() {
+        let mut peer = Blockchain::new(Config { base_path: "/api/v1".to_string(), ..test_config() });
+        mine_block(&mut peer);
+        mine_block(&mut peer);
+        let (address, peer) = serve(peer);
+        let mut blockchain = Blockchain::new(test_config());
+        assert!(blockchain.register_node(&format!("http://{}", address)));
+        let result = blockchain.resolve_conflicts();
+        assert!(result.replaced, "{}", result.message);
+        assert_eq!(hashes(&blockchain.chain), hashes(&peer.lock().unwrap().chain));
+    }
+
+    #[test]
+    fn mining_gives_up_after_the_iteration_cap() {
+        let config = Config { difficulty: 60, max_pow_iterations: Some(100), ..test_config() };
+        let blockchain = web::Data::new(Mutex::new(Blockchain::new(config)));
+        let (code, body) = get(&blockchain, "/mine?start=50");
+        assert_eq!(code, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(body["message"], BlockchainError::ProofNotFound { start: 50, iterations: 100 }.to_string());
+        let (code, body) = get(&blockchain, &format!("/mine?start={}", usize::MAX));
+        assert_eq!(code, StatusCode::BAD_REQUEST);
+        assert_eq!(body["message"], BlockchainError::ProofSpaceExhausted { start: usize::MAX }.to_string());
+        assert_eq!(blockchain.lock().unwrap().chain.len(), 1);
+    }
+
+    #[test]
+    fn the_consensus_rules_reflect_the_configuration() {
+        let config = Config {
+            chain_id: "testnet".to_string(),
+            difficulty: 2,
+            mining_reward: 5.0,
+            halving_interval: Some(10),
+            supply_cap: Some(100.0),
+            median_time_span: 7,
+            ..test_config()
+        };
+        let blockchain = web::Data::new(Mutex::new(Blockchain::new(config)));
+        let (code, body) = get(&blockchain, "/consensus/rules");
+        assert_eq!(code, StatusCode::OK);
+        assert_eq!(body["chain_id"], "testnet");
+        assert_eq!(body["hash_algorithm"], "sha256");
+        assert_eq!(body["min_difficulty_bits"], 8);
+        assert_eq!(body["median_time_span"], 7);
+        assert_eq!(body["max_address_length"], MAX_ADDRESS_LENGTH);
+        assert_eq!(body["max_coinbases_per_block"], 1);
+        let schedule = &body["reward_schedule"];
+        assert_eq!((schedule["base_reward"].as_f64(), schedule["halving_interval"].as_u64()), (Some(5.0), Some(10)));
+        assert_eq!((schedule["supply_cap"].as_f64(), schedule["next_reward"].as_f64()), (Some(100.0), Some(5.0)));
+    }
+
+    #[test]
+    fn oversized_chain_responses_are_cut_off_and_skipped() {
+        let (peer, _) = mine_at(test_config(), &[10, 20, 30]);
+        let (address, _peer) = serve(peer);
+        let mut blockchain = Blockchain::new(Config { max_chain_response: 256, consensus_interval: 0, ..test_config() });
+        let node = format!("http://{}", address);
+        blockchain.register_node(&node);
+        let result = blockchain.resolve_conflicts();
+        assert!(!result.replaced && result.network_error.is_none(), "{}", result.message);
+        assert_eq!(blockchain.chain.len(), 1);
+        assert_eq!(blockchain.peer_stats[&address].reputation, -1);
+        // A peer that never stops sending and never says how much it will send.
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let endless = listener.local_addr().unwrap().to_string();
+        thread::spawn(move || for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            let mut request = [0; 65536];
+            let _ = stream.read(&mut request);
+            let _ = stream.write_all(b"HTTP/1.1 200 OK\r\nConnection: close\r\n\r\n{\"chain\":[");
+            while stream.write_all(&[b' '; 4096]).is_ok() {}
+        });
+        let client = reqwest::Client::new();
+        match fetch_chain(&client, &format!("http://{}/chain", endless), 1 << 20) {
+            Err(err) => assert_eq!(err.to_string(), format!("response exceeds the limit of {} bytes", 1 << 20)),
+            Ok(chain) => panic!("expected the response to be cut off, got {} blocks", chain.length)
+        }
+    }
+
+    #[test]
+    fn interval_saves_persist_each_tick_and_reload() {
+        let path = temp_path("save-ticks");
+        let config = Config { storage_path: Some(path.clone()), save_interval: Some(1), ..test_config() };
+        let mut blockchain = Blockchain::new(config.clone());
+        blockchain.attach_storage().unwrap();
+        blockchain.save_if_unsaved();
+        let blockchain = web::Data::new(Mutex::new(blockchain));
+        spawn_saver(blockchain.clone(), Duration::from_millis(100));
+        {
+            let mut local_blockchain = blockchain.lock().unwrap();
+            mine_block(&mut local_blockchain);
+            let miner = local_blockchain.config.node_identifier.clone();
+            local_blockchain.new_transaction(Transaction::new(&miner, "alice", 0.25, 0.0)).unwrap();
+            assert_eq!(FileStore::new(&path).load().unwrap().unwrap().chain.len(), 1);
+        }
+        thread::sleep(Duration::from_millis(500));
+        let local_blockchain = blockchain.lock().unwrap();
+        assert!(!local_blockchain.unsaved);
+        let mut restored = Blockchain::new(config);
+        restored.attach_storage().unwrap();
+        assert_eq!(hashes(&restored.chain), hashes(&local_blockchain.chain));
+        assert_eq!(restored.current_transactions, local_blockchain.current_transactions);
+    }
+
+    #[test]
+    fn latency_is_measured_for_reachable_peers_only() {
+        let (reachable, _peer) = serve(Blockchain::new(test_config()));
+        let unreachable = {
+            let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+            listener.local_addr().unwrap().to_string()
+        };
+        let mut blockchain = Blockchain::new(test_config());
+        blockchain.register_node(&format!("http://{}", reachable));
+        blockchain.register_node(&format!("http://{}", unreachable));
+        let blockchain = web::Data::new(Mutex::new(blockchain));
+        let (code, body) = get(&blockchain, "/nodes/latency");
+        assert_eq!(code, StatusCode::OK);
+        let latencies: Vec<PeerLatency> = serde_json::from_value(body).unwrap();
+        let of = |node: &str| latencies.iter().find(|peer| peer.node == node).unwrap();
+        assert!(of(&reachable).latency_ms.unwrap() >= 0.0);
+        assert!(of(&reachable).error.is_none());
+        assert!(of(&unreachable).latency_ms.is_none());
+        assert!(of(&unreachable).error.is_some());
+    }
+    #[test]
+    fn signed_blocks_must_pay_the_miner_of_their_key() {
+        let signer = Arc::new(Signer::load_or_create(&temp_path("block-signer")).unwrap());
+        let mut honest = Blockchain::new(Config { node_identifier: signer.address(), ..test_config() });
+        honest.signer = Some(signer.clone());
+        let mut impostor = peer_of(&honest);
+        impostor.config.node_identifier = "mallory".to_string();
+        impostor.signer = Some(signer.clone());
+        let receiver = web::Data::new(Mutex::new(peer_of(&honest)));
+        let deliver = |block: &Block| call(&receiver, test::TestRequest::post().uri("/block/receive").set_json(block));
+        let forged = mine_block(&mut impostor);
+        assert!(!impostor.valid_chain(&impostor.chain));
+        let (code, body) = deliver(&forged);
+        assert_eq!(code, StatusCode::BAD_REQUEST);
+        assert_eq!(body["message"], BlockchainError::MinerMismatch { index: 2, address: signer.address() }.to_string());
+        let signed = mine_block(&mut honest);
+        assert_eq!(signed.miner_key.as_ref(), Some(&signer.public_key));
+        assert!(honest.valid_chain(&honest.chain));
+        assert_eq!(deliver(&signed).0, StatusCode::OK);
+        assert_eq!(hashes(&receiver.lock().unwrap().chain), hashes(&honest.chain));
+    }
+
+    #[test]
+    fn a_peer_claiming_more_blocks_than_it_sends_is_rejected() {
+        // A peer answering every request, /version included, with the same chain.
+        fn answer_with(body: String) -> String {
+            let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+            let address = listener.local_addr().unwrap().to_string();
+            thread::spawn(move || for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut request = [0; 65536];
+                let _ = stream.read(&mut request);
+                let head = format!("HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n", body.len());
+                let _ = stream.write_all(format!("{}{}", head, body).as_bytes());
+            });
+            address
+        }
+        let (peer, _) = mine_at(test_config(), &[10, 20]);
+        let inflated = FullChain { chain: peer.chain.clone(), length: 1000 };
+        let spoofer = answer_with(serde_json::to_string(&inflated).unwrap());
+        let mut blockchain = Blockchain::new(Config { consensus_interval: 0, ..test_config() });
+        blockchain.register_node(&format!("http://{}", spoofer));
+        let result = blockchain.resolve_conflicts();
+        assert!(!result.replaced, "{}", result.message);
+        assert_eq!(blockchain.chain.len(), 1);
+        assert_eq!(blockchain.peer_stats[&spoofer].reputation, -1);
+        let honest = answer_with(serde_json::to_string(&FullChain { length: 3, ..inflated }).unwrap());
+        let mut blockchain = Blockchain::new(Config { consensus_interval: 0, ..test_config() });
+        blockchain.register_node(&format!("http://{}", honest));
+        assert!(blockchain.resolve_conflicts().replaced);
+        assert_eq!(hashes(&blockchain.chain), hashes(&peer.chain));
+    }
+
+    #[test]
+    fn a_new_base_reward_applies_to_later_blocks_only() {
+        let mut blockchain = Blockchain::new(Config { admin_key: Some("key".to_string()), ..test_config() });
+        mine_block(&mut blockchain);
+        let blockchain = web::Data::new(Mutex::new(blockchain));
+        let update = serde_json::json!({ "base_reward": 3.0 });
+        let request = || test::TestRequest::post().uri("/reward/schedule").set_json(&update);
+        assert_eq!(call(&blockchain, request()).0, StatusCode::UNAUTHORIZED);
+        let (code, body) = call(&blockchain, request().header(ADMIN_KEY_HEADER, "key"));
+        assert_eq!(code, StatusCode::OK, "{}", body);
+        assert_eq!((body["base_reward"].as_f64(), body["next_reward"].as_f64()), (Some(3.0), Some(3.0)));
+        assert_eq!(get(&blockchain, "/consensus/rules").1["reward_schedule"]["base_reward"], 3.0);
+        assert_eq!(get(&blockchain, "/mine").0, StatusCode::OK);
+        let local_blockchain = blockchain.lock().unwrap();
+        let rewards: Vec<f32> = local_blockchain.chain[1..].iter().map(|block| block.coinbase().unwrap().amount).collect();
+        assert_eq!(rewards, vec![1.0, 3.0]);
+        assert!(local_blockchain.valid_chain(&local_blockchain.chain));
+    }
 }